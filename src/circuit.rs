@@ -0,0 +1,326 @@
+//! A circuit breaker for a single backend
+//!
+//! The circuit starts closed and requests flow normally. The circuit opens when too many requests
+//! fail during an interval or when the backend has too many requests in flight. While open, no
+//! requests are sent to the backend. Once the open time has passed, the circuit is half-open and a
+//! limited number of probe requests are let through. The circuit closes if the probes succeed and
+//! opens again if any probe fails. A probe holds its slot until the request is done, even if it
+//! never gets a result, such as when the client disconnects.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use config::CircuitBreaker;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CircuitState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CircuitState, String> {
+        match s {
+            "closed" => Ok(CircuitState::Closed),
+            "open" => Ok(CircuitState::Open),
+            "half-open" => Ok(CircuitState::HalfOpen),
+            _ => Err(format!("unknown circuit state: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Circuit {
+    state: CircuitState,
+    open_until: Option<Instant>,
+
+    /// Requests in the current failure rate interval
+    interval_start: Option<Instant>,
+    interval_success: u64,
+    interval_failure: u64,
+
+    /// Probe requests sent while half-open
+    probes_in_flight: u64,
+    probe_successes: u64,
+
+    /// Counts the times the circuit became half-open, so that a probe from an earlier time does
+    /// not release a slot of the current probes
+    probe_round: u64,
+}
+
+impl Circuit {
+    pub fn new() -> Circuit {
+        Circuit {
+            state: CircuitState::Closed,
+            open_until: None,
+            interval_start: None,
+            interval_success: 0,
+            interval_failure: 0,
+            probes_in_flight: 0,
+            probe_successes: 0,
+            probe_round: 0,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Returns true if a request may be sent to the backend
+    pub fn allows_request(&self, now: Instant, conf: &CircuitBreaker) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                match self.open_until {
+                    Some(until) => now >= until && conf.half_open_probes > 0,
+                    None => false,
+                }
+            }
+            CircuitState::HalfOpen => self.probes_in_flight < conf.half_open_probes,
+        }
+    }
+
+    /// Returns the round of the probe being sent, if the request is a probe
+    ///
+    /// The probe must be released with `release_probe` once the request is done.
+    pub fn probe(&self) -> Option<u64> {
+        match self.state {
+            CircuitState::HalfOpen => Some(self.probe_round),
+            _ => None,
+        }
+    }
+
+    /// Release the slot of a probe that is done
+    pub fn release_probe(&mut self, round: u64) {
+        if self.state == CircuitState::HalfOpen && round == self.probe_round &&
+            self.probes_in_flight > 0
+        {
+            self.probes_in_flight -= 1;
+        }
+    }
+
+    /// Record that a request was sent to the backend
+    ///
+    /// `in_flight` includes the request being sent. Returns the new state if it changed.
+    pub fn dispatch(
+        &mut self,
+        in_flight: usize,
+        now: Instant,
+        conf: &CircuitBreaker,
+    ) -> Option<CircuitState> {
+        match self.state {
+            CircuitState::Closed => {
+                if conf.max_concurrent > 0 && in_flight as u64 >= conf.max_concurrent {
+                    self.open(now, conf);
+                    return Some(CircuitState::Open);
+                }
+                None
+            }
+            CircuitState::Open => {
+                self.state = CircuitState::HalfOpen;
+                self.probes_in_flight = 1;
+                self.probe_successes = 0;
+                self.probe_round += 1;
+                Some(CircuitState::HalfOpen)
+            }
+            CircuitState::HalfOpen => {
+                self.probes_in_flight += 1;
+                None
+            }
+        }
+    }
+
+    /// Record the result of a request
+    ///
+    /// Returns the new state if it changed.
+    pub fn record(
+        &mut self,
+        success: bool,
+        now: Instant,
+        conf: &CircuitBreaker,
+    ) -> Option<CircuitState> {
+        match self.state {
+            CircuitState::Closed => {
+                let interval = Duration::from_secs(conf.interval);
+                let interval_over = match self.interval_start {
+                    Some(start) => now.duration_since(start) >= interval,
+                    None => true,
+                };
+                if interval_over {
+                    self.interval_start = Some(now);
+                    self.interval_success = 0;
+                    self.interval_failure = 0;
+                }
+
+                if success {
+                    self.interval_success += 1;
+                    return None;
+                }
+                self.interval_failure += 1;
+
+                let requests = self.interval_success + self.interval_failure;
+                if conf.failure_rate > 0 && requests >= conf.min_requests &&
+                    self.interval_failure * 100 >= conf.failure_rate * requests
+                {
+                    self.open(now, conf);
+                    return Some(CircuitState::Open);
+                }
+                None
+            }
+            // requests sent before the circuit opened are ignored
+            CircuitState::Open => None,
+            CircuitState::HalfOpen => {
+                if !success {
+                    self.open(now, conf);
+                    return Some(CircuitState::Open);
+                }
+
+                self.probe_successes += 1;
+                if self.probe_successes >= conf.half_open_probes {
+                    self.state = CircuitState::Closed;
+                    self.interval_start = None;
+                    return Some(CircuitState::Closed);
+                }
+                None
+            }
+        }
+    }
+
+    fn open(&mut self, now: Instant, conf: &CircuitBreaker) {
+        self.state = CircuitState::Open;
+        self.open_until = Some(now + Duration::from_secs(conf.open_time));
+        self.probes_in_flight = 0;
+        self.probe_successes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Circuit, CircuitState};
+    use config::CircuitBreaker;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_disabled_by_default() {
+        let conf = CircuitBreaker::default();
+        let mut circuit = Circuit::new();
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert_eq!(None, circuit.record(false, now, &conf));
+        }
+        assert_eq!(None, circuit.dispatch(100_000, now, &conf));
+        assert!(circuit.allows_request(now, &conf));
+    }
+
+    #[test]
+    fn test_trip_on_failure_rate() {
+        let mut conf = CircuitBreaker::default();
+        conf.failure_rate = 80;
+        let mut circuit = Circuit::new();
+        let now = Instant::now();
+
+        for _ in 0..conf.min_requests - 1 {
+            assert_eq!(None, circuit.record(false, now, &conf));
+        }
+        assert_eq!(Some(CircuitState::Open), circuit.record(false, now, &conf));
+        assert!(!circuit.allows_request(now, &conf));
+    }
+
+    #[test]
+    fn test_trip_on_max_concurrent() {
+        let mut conf = CircuitBreaker::default();
+        conf.max_concurrent = 1024;
+        let mut circuit = Circuit::new();
+        let now = Instant::now();
+
+        let max = conf.max_concurrent as usize;
+        assert_eq!(None, circuit.dispatch(max - 1, now, &conf));
+        assert_eq!(Some(CircuitState::Open), circuit.dispatch(max, now, &conf));
+    }
+
+    #[test]
+    fn test_half_open_probes() {
+        let conf = CircuitBreaker::default();
+        let mut circuit = Circuit::new();
+        let now = Instant::now();
+        circuit.open(now, &conf);
+
+        let now = now + Duration::from_secs(conf.open_time);
+        assert!(circuit.allows_request(now, &conf));
+        assert_eq!(Some(CircuitState::HalfOpen), circuit.dispatch(1, now, &conf));
+
+        // only a limited number of probes are let through
+        for _ in 1..conf.half_open_probes {
+            assert!(circuit.allows_request(now, &conf));
+            assert_eq!(None, circuit.dispatch(1, now, &conf));
+        }
+        assert!(!circuit.allows_request(now, &conf));
+
+        for _ in 1..conf.half_open_probes {
+            assert_eq!(None, circuit.record(true, now, &conf));
+        }
+        assert_eq!(Some(CircuitState::Closed), circuit.record(true, now, &conf));
+        assert!(circuit.allows_request(now, &conf));
+    }
+
+    #[test]
+    fn test_failed_probe_opens_circuit() {
+        let conf = CircuitBreaker::default();
+        let mut circuit = Circuit::new();
+        let now = Instant::now();
+        circuit.open(now, &conf);
+
+        let now = now + Duration::from_secs(conf.open_time);
+        circuit.dispatch(1, now, &conf);
+        assert_eq!(Some(CircuitState::Open), circuit.record(false, now, &conf));
+        assert!(!circuit.allows_request(now, &conf));
+    }
+
+    #[test]
+    fn test_release_probe() {
+        let mut conf = CircuitBreaker::default();
+        conf.half_open_probes = 1;
+        let mut circuit = Circuit::new();
+        let now = Instant::now();
+        circuit.open(now, &conf);
+
+        let now = now + Duration::from_secs(conf.open_time);
+        circuit.dispatch(1, now, &conf);
+        let round = circuit.probe().unwrap();
+        assert!(!circuit.allows_request(now, &conf));
+
+        // a probe that never got a result lets another one through
+        circuit.release_probe(round);
+        assert!(circuit.allows_request(now, &conf));
+
+        // a probe from before the circuit opened again does not release a current one
+        circuit.dispatch(1, now, &conf);
+        circuit.record(false, now, &conf);
+        let now = now + Duration::from_secs(conf.open_time);
+        circuit.dispatch(1, now, &conf);
+        circuit.release_probe(round);
+        assert!(!circuit.allows_request(now, &conf));
+        assert_eq!(None, Circuit::new().probe());
+    }
+}
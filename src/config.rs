@@ -1,6 +1,42 @@
+use hash::HashKey;
+use pool::Strategy;
+use upstream::Protocol;
+
 #[derive(Default)]
 pub struct Config {
     pub health_check: HealthCheck,
+
+    pub outlier_detection: OutlierDetection,
+
+    pub circuit_breaker: CircuitBreaker,
+
+    pub retry: Retry,
+
+    pub slow_start: SlowStart,
+
+    pub error_pages: ErrorPages,
+
+    /// The default timeouts of requests to a server. A server may override them.
+    pub timeouts: Timeouts,
+
+    pub connection_pool: ConnectionPool,
+
+    pub upgrade: Upgrade,
+
+    /// TLS termination of client connections on the HTTPS listener
+    pub tls: Tls,
+
+    /// HTTP/2 between clients and the proxy
+    pub http2: Http2,
+
+    /// The strategy used by the pool to select a backend for each request
+    pub strategy: Strategy,
+
+    /// The part of the request hashed when the strategy is consistent hashing
+    pub hash_key: HashKey,
+
+    /// The protocol used to send requests to the servers of the pool
+    pub protocol: Protocol,
 }
 
 pub struct HealthCheck {
@@ -28,9 +64,314 @@ impl Default for HealthCheck {
     }
 }
 
+/// Passive health checking using the responses to live traffic
+///
+/// Both checks are disabled by default, so servers are only ejected once a check is enabled.
+#[derive(Clone, Debug)]
+pub struct OutlierDetection {
+    /// The number of consecutive failed requests that eject a server. Zero disables this check.
+    pub consecutive_failures: u64,
+
+    /// The percentage of failed requests during an interval that ejects a server. Zero disables
+    /// this check.
+    pub failure_rate: u64,
+
+    /// The minimum number of requests during an interval before the failure rate is checked
+    pub failure_rate_min_requests: u64,
+
+    /// The time (in seconds) over which the failure rate is measured
+    pub interval: u64,
+
+    /// The time (in seconds) a server is ejected the first time. The time doubles for each
+    /// ejection that follows soon after the server returns.
+    pub base_ejection_time: u64,
+
+    /// The maximum time (in seconds) a server is ejected
+    pub max_ejection_time: u64,
+
+    /// The maximum percentage of servers in the pool that can be ejected at the same time
+    pub max_ejection_percent: u64,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> OutlierDetection {
+        OutlierDetection {
+            consecutive_failures: 0,
+            failure_rate: 0,
+            failure_rate_min_requests: 20,
+            interval: 10,
+            base_ejection_time: 30,
+            max_ejection_time: 300,
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+/// A per-server circuit breaker that stops sending requests to a struggling server
+///
+/// Both checks are disabled by default, so a circuit only opens once a check is enabled.
+#[derive(Clone, Debug)]
+pub struct CircuitBreaker {
+    /// The percentage of failed requests during an interval that opens the circuit. Zero disables
+    /// this check.
+    pub failure_rate: u64,
+
+    /// The minimum number of requests during an interval before the failure rate is checked
+    pub min_requests: u64,
+
+    /// The time (in seconds) over which the failure rate is measured
+    pub interval: u64,
+
+    /// The number of concurrent requests to a server that opens the circuit. Zero disables this
+    /// check.
+    pub max_concurrent: u64,
+
+    /// The time (in seconds) the circuit stays open before probe requests are let through
+    pub open_time: u64,
+
+    /// The number of probe requests let through while half-open. The circuit closes once all of
+    /// them succeed.
+    pub half_open_probes: u64,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_rate: 0,
+            min_requests: 20,
+            interval: 10,
+            max_concurrent: 0,
+            open_time: 5,
+            half_open_probes: 3,
+        }
+    }
+}
+
+/// Retrying failed requests on another server
+#[derive(Clone, Debug)]
+pub struct Retry {
+    /// The maximum number of times a request is sent, including the first attempt. One disables
+    /// retries.
+    pub max_attempts: u64,
+
+    /// The response status codes that are retried
+    pub statuses: Vec<u16>,
+
+    /// The time (in milliseconds) since the first attempt after which a request is no longer
+    /// retried
+    pub budget: u64,
+
+    /// The largest request body (in bytes) that is buffered so that the request can be retried.
+    /// This lets requests with a body, including non-idempotent ones such as `POST`, be retried
+    /// when the connection to the server fails. Zero disables buffering.
+    pub buffer_body: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            max_attempts: 3,
+            statuses: vec![502, 503, 504],
+            budget: 5000,
+            buffer_body: 0,
+        }
+    }
+}
+
+/// Ramping up the share of requests sent to a server that was just added or recovered
+#[derive(Clone, Debug)]
+pub struct SlowStart {
+    /// The time (in seconds) over which a server ramps up to its full share of requests. Zero
+    /// disables slow start, which is the default.
+    pub window: u64,
+}
+
+impl Default for SlowStart {
+    fn default() -> SlowStart {
+        SlowStart { window: 0 }
+    }
+}
+
+/// The responses sent to the client when a request cannot be proxied
+#[derive(Clone, Debug)]
+pub struct ErrorPages {
+    /// The `Content-Type` of the error responses
+    pub content_type: String,
+
+    /// The body of the `400 Bad Request` response sent when the request body cannot be read from
+    /// the client
+    pub bad_request: String,
+
+    /// The body of the `502 Bad Gateway` response sent when the server cannot be reached or sends
+    /// an invalid response
+    pub bad_gateway: String,
+
+    /// The body of the `503 Service Unavailable` response sent when no server is available
+    pub service_unavailable: String,
+
+    /// The body of the `504 Gateway Timeout` response sent when the server does not respond in
+    /// time
+    pub gateway_timeout: String,
+
+    /// The time (in seconds) sent in the `Retry-After` header of a `503 Service Unavailable`
+    /// response
+    pub retry_after: u64,
+}
+
+impl Default for ErrorPages {
+    fn default() -> ErrorPages {
+        ErrorPages {
+            content_type: "text/plain; charset=utf-8".to_string(),
+            bad_request: "Bad Request\n".to_string(),
+            bad_gateway: "Bad Gateway\n".to_string(),
+            service_unavailable: "Service Unavailable\n".to_string(),
+            gateway_timeout: "Gateway Timeout\n".to_string(),
+            retry_after: 5,
+        }
+    }
+}
+
+/// Timeouts for requests sent to a server. A timeout of zero is disabled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeouts {
+    /// The time (in milliseconds) allowed to connect to the server
+    pub connect: u64,
+
+    /// The time (in milliseconds) from sending the request until the response headers are
+    /// received
+    pub first_byte: u64,
+
+    /// The longest time (in milliseconds) the server may go without sending part of the response
+    /// body
+    pub idle: u64,
+
+    /// The time (in milliseconds) allowed for the whole request, including the response body
+    pub request: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: 5000,
+            first_byte: 30000,
+            idle: 30000,
+            request: 0,
+        }
+    }
+}
+
+/// Reusing connections to servers across requests
+#[derive(Clone, Debug)]
+pub struct ConnectionPool {
+    /// The maximum number of idle connections kept open to each server. Zero disables keep-alive.
+    pub max_idle: u64,
+
+    /// The time (in seconds) after which an idle connection is closed
+    pub idle_timeout: u64,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> ConnectionPool {
+        ConnectionPool {
+            max_idle: 32,
+            idle_timeout: 90,
+        }
+    }
+}
+
+/// Tunneling connections that upgrade to another protocol, such as WebSocket
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upgrade {
+    /// Whether requests to upgrade the connection are forwarded to the server. When disabled, the
+    /// upgrade headers are removed and the request is proxied as plain HTTP.
+    pub enabled: bool,
+
+    /// The time (in seconds) a tunnel stays open while neither side sends anything. Zero disables
+    /// the timeout.
+    pub idle_timeout: u64,
+}
+
+impl Default for Upgrade {
+    fn default() -> Upgrade {
+        Upgrade {
+            enabled: false,
+            idle_timeout: 300,
+        }
+    }
+}
+
+/// TLS termination of client connections
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tls {
+    /// The certificates offered to clients, selected by the server name the client sends. The
+    /// first certificate is offered when no other one matches.
+    pub certificates: Vec<Certificate>,
+
+    /// The oldest protocol version accepted: `TLSv1`, `TLSv1.1`, `TLSv1.2` or `TLSv1.3`
+    pub min_version: String,
+
+    /// The ciphers allowed for TLS 1.2 and older, as an OpenSSL cipher list. Empty uses the Mozilla
+    /// intermediate compatibility list.
+    pub ciphers: String,
+
+    /// The time (in seconds) between two checks for changes to the certificate files, which are
+    /// reloaded once they change. Zero disables the checks.
+    pub watch_interval: u64,
+}
+
+impl Default for Tls {
+    fn default() -> Tls {
+        Tls {
+            certificates: Vec::new(),
+            min_version: "TLSv1.2".to_string(),
+            ciphers: String::new(),
+            watch_interval: 10,
+        }
+    }
+}
+
+/// HTTP/2 between clients and the proxy
+#[derive(Clone, Debug, PartialEq)]
+pub struct Http2 {
+    /// Whether clients may speak HTTP/2, either by selecting it during the TLS handshake (ALPN) or
+    /// by starting a plaintext connection with the HTTP/2 connection preface (prior knowledge)
+    pub enabled: bool,
+
+    /// The number of requests a client may have in flight at once on a connection
+    pub max_concurrent_streams: u32,
+}
+
+impl Default for Http2 {
+    fn default() -> Http2 {
+        Http2 {
+            enabled: true,
+            max_concurrent_streams: 100,
+        }
+    }
+}
+
+/// A certificate and its private key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Certificate {
+    /// The name used to replace the certificate while the listener runs
+    pub name: String,
+
+    /// The path of the PEM file holding the certificate followed by its chain
+    pub cert: String,
+
+    /// The path of the PEM file holding the private key
+    pub key: String,
+}
+
 #[test]
 fn test_config() {
     let conf = Config::default();
     assert_eq!(10, conf.health_check.interval);
     assert_eq!("/", conf.health_check.uri_path);
+    assert_eq!(Strategy::RoundRobin, conf.strategy);
+    assert_eq!(0, conf.outlier_detection.consecutive_failures);
+    assert_eq!(0, conf.outlier_detection.failure_rate);
+    assert_eq!(0, conf.circuit_breaker.failure_rate);
+    assert_eq!(0, conf.circuit_breaker.max_concurrent);
+    assert_eq!(0, conf.slow_start.window);
 }
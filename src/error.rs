@@ -0,0 +1,171 @@
+//! Mapping proxy errors to responses sent to the client
+//!
+//! A request that cannot be proxied is answered by the proxy itself, so that a client can tell a
+//! failed backend apart from a failed proxy. A dropped connection would look the same for both.
+//!
+//! A gRPC client expects the status of a call in the `grpc-status` header instead of the HTTP
+//! status, so a failed gRPC request is answered with a gRPC error.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use hyper::{self, server, Body, Headers, StatusCode};
+use hyper::header::{ContentLength, ContentType, RetryAfter};
+
+use config::ErrorPages;
+use pool::InFlightBody;
+
+/// The error returned when no backend in the pool is available for a request
+#[derive(Debug)]
+pub struct Exhausted;
+
+impl fmt::Display for Exhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+
+impl error::Error for Exhausted {
+    fn description(&self) -> &str {
+        "Pool is exhausted of servers"
+    }
+}
+
+/// Create the error returned when no backend in the pool is available for a request
+pub fn exhausted() -> hyper::Error {
+    hyper::Error::Io(io::Error::new(io::ErrorKind::Other, Exhausted))
+}
+
+/// Returns the status of the response sent to the client for a request that failed with this error
+pub fn status(e: &hyper::Error) -> StatusCode {
+    match *e {
+        hyper::Error::Io(ref e) if e.get_ref().map_or(false, |e| e.is::<Exhausted>()) => {
+            StatusCode::ServiceUnavailable
+        }
+        hyper::Error::Io(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+            StatusCode::GatewayTimeout
+        }
+        hyper::Error::Timeout => StatusCode::GatewayTimeout,
+        _ => StatusCode::BadGateway,
+    }
+}
+
+/// Create the response sent to the client for a request that failed with this status
+pub fn response(conf: &ErrorPages, status: StatusCode) -> server::Response<InFlightBody> {
+    let body = match status {
+        StatusCode::BadRequest => conf.bad_request.clone(),
+        StatusCode::ServiceUnavailable => conf.service_unavailable.clone(),
+        StatusCode::GatewayTimeout => conf.gateway_timeout.clone(),
+        _ => conf.bad_gateway.clone(),
+    };
+
+    let mut r = server::Response::new().with_status(status);
+    r.headers_mut().set(ContentLength(body.len() as u64));
+    r.headers_mut().set_raw("Content-Type", conf.content_type.clone());
+    if status == StatusCode::ServiceUnavailable {
+        r.headers_mut().set(RetryAfter::Delay(Duration::from_secs(conf.retry_after)));
+    }
+
+    r.with_body(InFlightBody::from(Body::from(body)))
+}
+
+/// Returns true if the request is a gRPC call
+pub fn is_grpc(headers: &Headers) -> bool {
+    headers
+        .get::<ContentType>()
+        .map_or(false, |ct| ct.0.type_() == "application" && ct.0.subtype() == "grpc")
+}
+
+/// Create the response sent to a gRPC client for a call that failed with this status
+///
+/// gRPC calls always succeed at the HTTP level. The status of the call is sent with the headers
+/// of a response without a body (a trailers-only response), which also works over HTTP/1.x.
+pub fn grpc_response(status: StatusCode) -> server::Response<InFlightBody> {
+    // https://github.com/grpc/grpc/blob/master/doc/statuscodes.md
+    let (code, message) = match status {
+        StatusCode::BadRequest => (13, "Unable to read request"),
+        StatusCode::GatewayTimeout => (4, "Deadline exceeded waiting for backend"),
+        StatusCode::ServiceUnavailable => (14, "No backend available"),
+        _ => (14, "Unable to reach backend"),
+    };
+
+    let mut r = server::Response::new();
+    r.headers_mut().set_raw("Content-Type", "application/grpc");
+    r.headers_mut().set_raw("grpc-status", code.to_string());
+    r.headers_mut().set_raw("grpc-message", message);
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exhausted, grpc_response, is_grpc, response, status};
+    use config::ErrorPages;
+    use hyper::{self, Headers, StatusCode};
+    use hyper::header::{ContentLength, RetryAfter};
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    fn test_status() {
+        let refused = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        let timed_out = hyper::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+
+        assert_eq!(StatusCode::ServiceUnavailable, status(&exhausted()));
+        assert_eq!(StatusCode::BadGateway, status(&refused));
+        assert_eq!(StatusCode::BadGateway, status(&hyper::Error::Incomplete));
+        assert_eq!(StatusCode::GatewayTimeout, status(&timed_out));
+        assert_eq!(StatusCode::GatewayTimeout, status(&hyper::Error::Timeout));
+    }
+
+    #[test]
+    fn test_response() {
+        let mut conf = ErrorPages::default();
+        conf.service_unavailable = "try again".to_string();
+        conf.retry_after = 10;
+
+        let res = response(&conf, StatusCode::ServiceUnavailable);
+        assert_eq!(StatusCode::ServiceUnavailable, res.status());
+        assert_eq!(
+            Some(&RetryAfter::Delay(Duration::from_secs(10))),
+            res.headers().get::<RetryAfter>()
+        );
+        assert_eq!(Some(&ContentLength(9)), res.headers().get::<ContentLength>());
+
+        let res = response(&conf, StatusCode::BadGateway);
+        assert_eq!(StatusCode::BadGateway, res.status());
+        assert!(!res.headers().has::<RetryAfter>());
+
+        let res = response(&conf, StatusCode::BadRequest);
+        assert_eq!(StatusCode::BadRequest, res.status());
+        assert_eq!(Some(&ContentLength(12)), res.headers().get::<ContentLength>());
+    }
+
+    #[test]
+    fn test_is_grpc() {
+        let mut headers = Headers::new();
+        assert!(!is_grpc(&headers));
+
+        headers.set_raw("Content-Type", "application/grpc+proto");
+        assert!(is_grpc(&headers));
+
+        headers.set_raw("Content-Type", "application/json");
+        assert!(!is_grpc(&headers));
+    }
+
+    #[test]
+    fn test_grpc_response() {
+        let res = grpc_response(StatusCode::GatewayTimeout);
+        assert_eq!(StatusCode::Ok, res.status());
+        assert!(res.body_ref().is_none());
+        assert_eq!(Some(&b"4"[..]), res.headers().get_raw("grpc-status").and_then(|v| v.one()));
+
+        let res = grpc_response(StatusCode::BadGateway);
+        assert_eq!(Some(&b"14"[..]), res.headers().get_raw("grpc-status").and_then(|v| v.one()));
+
+        // the client is at fault, so the call is not retried as if the backend were unavailable
+        let res = grpc_response(StatusCode::BadRequest);
+        assert_eq!(Some(&b"13"[..]), res.headers().get_raw("grpc-status").and_then(|v| v.one()));
+    }
+}
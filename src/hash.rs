@@ -0,0 +1,277 @@
+//! Consistent hashing of requests to backends
+//!
+//! The ring is built the same way as ketama. Each backend is given a number of points on the ring
+//! in proportion to its weight. A request is hashed onto the ring and sent to the backend that
+//! owns the next point. Adding or removing a backend only moves the keys that land on the points
+//! of that backend.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use hyper::Headers;
+use hyper::server::Request;
+
+/// The number of points on the ring for each unit of backend weight
+const POINTS_PER_WEIGHT: u64 = 160;
+
+/// The most points a single backend has on the ring
+///
+/// When the heaviest backend would have more, the points of every backend are scaled down so that
+/// they keep the same share of the ring.
+const MAX_POINTS: u64 = 100 * POINTS_PER_WEIGHT;
+
+/// The part of a request that is hashed to select a backend
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum HashKey {
+    /// The value of the named request header
+    Header(String),
+
+    /// The value of the named cookie
+    Cookie(String),
+
+    /// The URI path of the request
+    Path,
+
+    /// The IP address of the client
+    ClientIp,
+}
+
+impl HashKey {
+    /// Hash the key of a request
+    ///
+    /// Returns `None` if the request does not contain the key, such as when the header or cookie
+    /// is missing.
+    pub fn hash(&self, req: &Request, addr: &SocketAddr) -> Option<u64> {
+        match *self {
+            HashKey::Header(ref name) => {
+                req.headers().get_raw(name).map(|raw| {
+                    let mut hasher = DefaultHasher::new();
+                    for line in raw.iter() {
+                        line.hash(&mut hasher);
+                    }
+                    hasher.finish()
+                })
+            }
+            HashKey::Cookie(ref name) => cookie(req.headers(), name).map(|value| hash(&value)),
+            HashKey::Path => Some(hash(&req.path())),
+            HashKey::ClientIp => Some(hash(&addr.ip())),
+        }
+    }
+}
+
+impl Default for HashKey {
+    fn default() -> HashKey {
+        HashKey::ClientIp
+    }
+}
+
+impl fmt::Display for HashKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashKey::Header(ref name) => write!(f, "header:{}", name),
+            HashKey::Cookie(ref name) => write!(f, "cookie:{}", name),
+            HashKey::Path => f.write_str("path"),
+            HashKey::ClientIp => f.write_str("client-ip"),
+        }
+    }
+}
+
+impl FromStr for HashKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashKey, String> {
+        match s {
+            "path" => Ok(HashKey::Path),
+            "client-ip" => Ok(HashKey::ClientIp),
+            _ if s.starts_with("header:") && s.len() > 7 => Ok(HashKey::Header(s[7..].to_string())),
+            _ if s.starts_with("cookie:") && s.len() > 7 => Ok(HashKey::Cookie(s[7..].to_string())),
+            _ => Err(format!("unknown hash key: {}", s)),
+        }
+    }
+}
+
+/// Find the value of a cookie sent by the client
+pub fn cookie(headers: &Headers, name: &str) -> Option<String> {
+    let raw = match headers.get_raw("Cookie") {
+        Some(raw) => raw,
+        None => return None,
+    };
+
+    for line in raw.iter() {
+        let line = match ::std::str::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+
+        for pair in line.split(';') {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().map(|k| k.trim());
+            let value = parts.next().map(|v| v.trim());
+            if let (Some(key), Some(value)) = (key, value) {
+                if key == name {
+                    return Some(value.to_string());
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Hash a value the same way in every worker
+///
+/// `DefaultHasher::new` always uses the same keys, so all workers running the same binary agree
+/// on where a key lands on the ring.
+pub fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A consistent hash ring of backends
+///
+/// Backends are referred to by their index in the list used to build the ring.
+#[derive(Debug, Default)]
+pub struct Ring {
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    /// Build a ring from the name and weight of each backend
+    pub fn new<'a, I>(nodes: I) -> Ring
+    where
+        I: Iterator<Item = (&'a str, u32)>,
+    {
+        let nodes: Vec<(&str, u32)> = nodes.collect();
+        let max_weight = nodes.iter().map(|&(_, weight)| weight).max().unwrap_or(0) as u64;
+        let max_points = max_weight * POINTS_PER_WEIGHT;
+
+        let mut points = Vec::new();
+        for (index, &(name, weight)) in nodes.iter().enumerate() {
+            let mut count = weight as u64 * POINTS_PER_WEIGHT;
+            if max_points > MAX_POINTS && count > 0 {
+                count = ::std::cmp::max(1, count * MAX_POINTS / max_points);
+            }
+            for point in 0..count {
+                points.push((hash(&(name, point)), index));
+            }
+        }
+        points.sort();
+
+        Ring { points: points }
+    }
+
+    /// Find the first eligible backend at or after the hash on the ring
+    pub fn get<F>(&self, hash: u64, eligible: F) -> Option<usize>
+    where
+        F: Fn(usize) -> bool,
+    {
+        let len = self.points.len();
+        let start = match self.points.binary_search(&(hash, 0)) {
+            Ok(i) | Err(i) => i,
+        };
+
+        (0..len)
+            .map(|i| self.points[(start + i) % len].1)
+            .find(|&index| eligible(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, cookie, HashKey, Ring};
+    use hyper::Headers;
+
+    fn ring(names: &[&str]) -> Ring {
+        Ring::new(names.iter().map(|name| (*name, 1)))
+    }
+
+    #[test]
+    fn test_hash_key_from_str() {
+        for key in vec![
+            HashKey::Header("X-User".to_string()),
+            HashKey::Cookie("session".to_string()),
+            HashKey::Path,
+            HashKey::ClientIp,
+        ]
+        {
+            assert_eq!(Ok(key.clone()), key.to_string().parse::<HashKey>());
+        }
+
+        assert!("header:".parse::<HashKey>().is_err());
+        assert!("unknown".parse::<HashKey>().is_err());
+    }
+
+    #[test]
+    fn test_cookie() {
+        let mut headers = Headers::new();
+        headers.set_raw("Cookie", "a=1; session=abc ;b=2");
+
+        assert_eq!(Some("abc".to_string()), cookie(&headers, "session"));
+        assert_eq!(Some("2".to_string()), cookie(&headers, "b"));
+        assert_eq!(None, cookie(&headers, "c"));
+    }
+
+    #[test]
+    fn test_empty_ring() {
+        let ring = ring(&[]);
+        assert_eq!(None, ring.get(hash("key"), |_| true));
+    }
+
+    #[test]
+    fn test_ring_caps_points() {
+        // the weights would overflow the number of points without the cap
+        let ring = Ring::new(vec![("a", 40_000_000), ("b", 20_000_000)].into_iter());
+        let a = ring.points.iter().filter(|&&(_, index)| index == 0).count();
+        assert_eq!(100 * 160, a);
+        assert_eq!(a / 2, ring.points.len() - a);
+    }
+
+    #[test]
+    fn test_ring_skips_ineligible() {
+        let ring = ring(&["a", "b"]);
+        for key in 0..100 {
+            assert_eq!(Some(1), ring.get(hash(&key), |index| index == 1));
+        }
+        assert_eq!(None, ring.get(hash("key"), |_| false));
+    }
+
+    #[test]
+    fn test_ring_remove_only_remaps_removed_keys() {
+        let before = ring(&["a", "b", "c"]);
+        // "b" is removed, so "c" is now at index 1
+        let after = ring(&["a", "c"]);
+
+        for key in 0..1000 {
+            let h = hash(&key);
+            match before.get(h, |_| true).unwrap() {
+                0 => assert_eq!(Some(0), after.get(h, |_| true)),
+                2 => assert_eq!(Some(1), after.get(h, |_| true)),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn test_ring_add_only_remaps_to_new_backend() {
+        let before = ring(&["a", "b", "c"]);
+        let after = ring(&["a", "b", "c", "d"]);
+
+        let mut moved = 0;
+        for key in 0..1000 {
+            let h = hash(&key);
+            let old = before.get(h, |_| true).unwrap();
+            let new = after.get(h, |_| true).unwrap();
+            if old != new {
+                assert_eq!(3, new);
+                moved += 1;
+            }
+        }
+
+        // roughly a quarter of the keys should move to the new backend
+        assert!(moved > 150 && moved < 350, "moved {} keys", moved);
+    }
+}
@@ -0,0 +1,858 @@
+//! HTTP/2 between clients and the proxy, and between the proxy and backends
+//!
+//! A client speaks HTTP/2 when it offers `h2` during the TLS handshake (ALPN), or when it starts a
+//! plaintext connection with the HTTP/2 connection preface because it already knows the proxy
+//! supports it (prior knowledge, also known as h2c). Each stream of the connection carries one
+//! request, which is given to the same service as a request on an HTTP/1.x connection. It is
+//! routed and proxied to a backend of a pool as usual, and its response is sent back on the
+//! stream, so that many requests share a single client connection.
+//!
+//! A pool may also send its requests to backends over HTTP/2, see `upstream::Protocol`. Every
+//! request to a backend then shares a single connection to it.
+//!
+//! Bodies are streamed in both directions within the flow control window of each stream, so that
+//! a slow reader on either side slows down the writer instead of filling up the proxy.
+//!
+//! Trailers, the header fields sent after a body, are forwarded along with it. gRPC sends the
+//! status of a call in the trailers of the response, for example. hyper does not carry trailers
+//! with HTTP/1.x messages, so they are only forwarded when both the client and the backend speak
+//! HTTP/2. The trailers of a response to an HTTP/1.x client are dropped.
+
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use bytes::Bytes;
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use futures::future::Either;
+use futures::sync::mpsc;
+use h2::{self, Reason, RecvStream, SendStream};
+use h2::client::SendRequest;
+use h2::server::{Builder, SendResponse};
+use http::{self, HeaderMap};
+use http::header::{HeaderName, HeaderValue};
+use hyper::{self, client, Body, Chunk, Headers, HttpVersion, Method, StatusCode, Uri};
+use hyper::server::{self, Service};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use config::Http2;
+use pool::InFlightBody;
+
+/// The bytes a client sends first on an HTTP/2 connection
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The protocols offered during the TLS handshake, in order of preference, in ALPN wire format
+pub const ALPN_PROTOCOLS: &'static [u8] = b"\x02h2\x08http/1.1";
+
+/// The protocol a TLS client selects to speak HTTP/2
+pub const ALPN_H2: &'static [u8] = b"h2";
+
+/// Headers that are specific to an HTTP/1.x connection and not allowed in HTTP/2
+const CONNECTION_HEADERS: &'static [&'static str] = &[
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Holds the trailers of a request and of its response while they are forwarded
+///
+/// hyper bodies cannot carry trailers, so they are passed alongside the body instead. The trailers
+/// of a message are stored by the task reading its body before the body ends, and are read by the
+/// task writing the body once it is done.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers {
+    inner: Rc<RefCell<TrailersInner>>,
+}
+
+#[derive(Debug, Default)]
+struct TrailersInner {
+    request: Option<HeaderMap>,
+    response: Option<HeaderMap>,
+
+    /// Set when the client cannot be sent the trailers of the response
+    drop_response: bool,
+}
+
+impl Trailers {
+    pub fn new() -> Trailers {
+        Trailers::default()
+    }
+
+    /// The trailers of a request from an HTTP/1.x client
+    ///
+    /// The request has no trailers, and the trailers of the response are dropped because they
+    /// cannot be sent to the client.
+    pub fn http1() -> Trailers {
+        let trailers = Trailers::default();
+        trailers.inner.borrow_mut().drop_response = true;
+        trailers
+    }
+
+    /// Returns the trailers of the request
+    ///
+    /// They are kept, because a request may be sent to more than one backend.
+    pub fn request(&self) -> Option<HeaderMap> {
+        self.inner.borrow().request.clone()
+    }
+
+    pub fn set_request(&self, trailers: HeaderMap) {
+        self.inner.borrow_mut().request = Some(trailers);
+    }
+
+    /// Take the trailers of the response
+    pub fn take_response(&self) -> Option<HeaderMap> {
+        self.inner.borrow_mut().response.take()
+    }
+
+    pub fn set_response(&self, trailers: HeaderMap) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.drop_response {
+            debug!("Dropping response trailers for HTTP/1.x client: {:?}", trailers);
+            return;
+        }
+        inner.response = Some(trailers);
+    }
+}
+
+/// Serve an HTTP/2 client connection
+///
+/// Each request is handled on a task of its own, so that a slow response does not hold up the
+/// other streams. The service of a request is created with the trailers of the request, which it
+/// forwards to the backend along with the trailers of the response. The future finishes once the
+/// client closes the connection.
+pub fn serve<I, F, S>(
+    io: I,
+    new_service: F,
+    conf: &Http2,
+    handle: &Handle,
+) -> Box<Future<Item = (), Error = h2::Error>>
+where
+    I: AsyncRead + AsyncWrite + 'static,
+    F: Fn(Trailers) -> S + 'static,
+    S: Service<
+        Request = server::Request,
+        Response = server::Response<InFlightBody>,
+        Error = hyper::Error,
+    >,
+    S::Future: 'static,
+{
+    let handle = handle.clone();
+    let work = Builder::new()
+        .max_concurrent_streams(conf.max_concurrent_streams)
+        .handshake::<_, Bytes>(io)
+        .and_then(move |conn| {
+            conn.for_each(move |(req, mut respond)| {
+                let trailers = Trailers::new();
+                let req = match request(req, &trailers, &handle) {
+                    Ok(req) => req,
+                    Err(e) => {
+                        debug!("Invalid HTTP/2 request: {}", e);
+                        respond.send_reset(Reason::PROTOCOL_ERROR);
+                        return Ok(());
+                    }
+                };
+
+                let work = new_service(trailers.clone())
+                    .call(req)
+                    .then(move |res| match res {
+                        Ok(res) => Ok(respond_with(res, respond, trailers)),
+                        Err(e) => {
+                            debug!("Unable to serve HTTP/2 request: {}", e);
+                            respond.send_reset(Reason::INTERNAL_ERROR);
+                            Err(())
+                        }
+                    })
+                    .and_then(|body| match body {
+                        Some(body) => Either::A(body),
+                        None => Either::B(future::ok(())),
+                    });
+                handle.spawn(work);
+
+                Ok(())
+            })
+        });
+
+    Box::new(work)
+}
+
+/// Convert a request received on a stream into the request given to the service
+///
+/// The request is given the origin form of its URI, and the authority is moved to the `Host`
+/// header unless the client sent one. The body, if any, is forwarded on a task of its own.
+fn request(
+    req: http::Request<RecvStream>,
+    trailers: &Trailers,
+    handle: &Handle,
+) -> Result<server::Request, hyper::Error> {
+    let (parts, recv) = req.into_parts();
+
+    let method = Method::from_str(parts.method.as_str())?;
+    let uri = match parts.uri.path_and_query() {
+        Some(path) => Uri::from_str(path.as_str())?,
+        None => Uri::from_str("/")?,
+    };
+
+    let mut r = server::Request::new(method, uri);
+    r.set_version(HttpVersion::H2);
+    *r.headers_mut() = from_header_map(&parts.headers);
+    if !r.headers().has::<hyper::header::Host>() {
+        if let Some(authority) = parts.uri.authority_part() {
+            r.headers_mut().set_raw("Host", authority.as_str().to_string());
+        }
+    }
+
+    // a body without a `Content-Length` has an unknown length, so it is not buffered for a retry
+    if !recv.is_end_stream() {
+        let (tx, body) = Body::pair();
+        r.set_body(body);
+        handle.spawn(RecvBody::new(recv, tx, trailers, Trailers::set_request));
+    }
+
+    Ok(r)
+}
+
+/// Send the head of a response on its stream
+///
+/// Returns the future that sends the body, unless the response has none or the stream is gone.
+/// A response without a body ends with its head, which is how gRPC sends a call that failed
+/// right away.
+fn respond_with(
+    res: server::Response<InFlightBody>,
+    mut respond: SendResponse<Bytes>,
+    trailers: Trailers,
+) -> Option<SendBody<InFlightBody>> {
+    let mut head = http::Response::new(());
+    *head.status_mut() = match http::StatusCode::from_u16(res.status().as_u16()) {
+        Ok(status) => status,
+        Err(_) => http::StatusCode::BAD_GATEWAY,
+    };
+    *head.headers_mut() = to_header_map(res.headers());
+
+    let body = res.body_ref().map(InFlightBody::take_body);
+
+    match respond.send_response(head, body.is_none()) {
+        Ok(send) => body.map(|body| SendBody::new(body, send, trailers, Trailers::take_response)),
+        Err(e) => {
+            debug!("Unable to send HTTP/2 response: {}", e);
+            None
+        }
+    }
+}
+
+/// A connection to a backend over HTTP/2, which carries every request to the backend at once
+#[derive(Clone)]
+pub struct Client {
+    sender: SendRequest<Bytes>,
+
+    /// Set once the connection is closed
+    closed: Rc<Cell<bool>>,
+}
+
+/// Start HTTP/2 on a connection to a backend
+///
+/// The connection is driven on a task of its own until either side closes it.
+pub fn handshake<T>(io: T, handle: &Handle) -> Box<Future<Item = Client, Error = io::Error>>
+where
+    T: AsyncRead + AsyncWrite + 'static,
+{
+    let handle = handle.clone();
+    let work = h2::client::handshake(io)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        .map(move |(sender, conn)| {
+            let closed = Rc::new(Cell::new(false));
+            let closed1 = closed.clone();
+            handle.spawn(conn.then(move |res| {
+                if let Err(e) = res {
+                    debug!("HTTP/2 connection to backend closed: {}", e);
+                }
+                closed1.set(true);
+                Ok(())
+            }));
+
+            Client {
+                sender: sender,
+                closed: closed,
+            }
+        });
+
+    Box::new(work)
+}
+
+impl Client {
+    /// Returns true once the connection is closed and can no longer be used
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+
+    /// Send a request to the backend
+    ///
+    /// The request is sent to the authority in its `Host` header, or else in its URI, with the
+    /// scheme of its URI. The trailers of the request are sent after its body, and the trailers of
+    /// the response are stored once its body has been read.
+    pub fn request(
+        &self,
+        req: client::Request,
+        trailers: &Trailers,
+        handle: &Handle,
+    ) -> Box<Future<Item = client::Response, Error = hyper::Error>> {
+        let (head, body) = match backend_request(req) {
+            Ok(req) => req,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let request_trailers = trailers.clone();
+        let request_handle = handle.clone();
+        let trailers = trailers.clone();
+        let handle = handle.clone();
+        let work = self.sender
+            .clone()
+            .ready()
+            .and_then(move |mut sender| {
+                let (response, send) = sender.send_request(head, body.is_none())?;
+                if let Some(body) = body {
+                    let body = SendBody::new(body, send, request_trailers, Trailers::request);
+                    request_handle.spawn(body);
+                }
+                Ok(response)
+            })
+            .flatten()
+            .map(move |res| backend_response(res, &trailers, &handle))
+            .map_err(error);
+
+        Box::new(work)
+    }
+}
+
+/// Convert a request to a backend into the head of an HTTP/2 request and its body
+fn backend_request(
+    req: client::Request,
+) -> Result<(http::Request<()>, Option<Body>), hyper::Error> {
+    let authority = {
+        let host = req.headers().get_raw("Host").and_then(|host| host.one());
+        match host.and_then(|host| ::std::str::from_utf8(host).ok()) {
+            Some(host) => host.to_string(),
+            None => req.uri().authority().unwrap_or("").to_string(),
+        }
+    };
+    let uri = match req.uri().query() {
+        Some(query) => format!(
+            "{}://{}{}?{}",
+            req.uri().scheme().unwrap_or("http"),
+            authority,
+            req.uri().path(),
+            query
+        ),
+        None => format!(
+            "{}://{}{}",
+            req.uri().scheme().unwrap_or("http"),
+            authority,
+            req.uri().path()
+        ),
+    };
+
+    let mut head = http::Request::new(());
+    *head.uri_mut() = http::Uri::from_str(&uri).map_err(|e| {
+        hyper::Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e))
+    })?;
+    *head.method_mut() = http::Method::from_bytes(req.method().as_ref().as_bytes())
+        .map_err(|_| hyper::Error::Method)?;
+    *head.headers_mut() = to_header_map(req.headers());
+    // the authority is sent as a pseudo header instead
+    head.headers_mut().remove(http::header::HOST);
+
+    let body = match req.body_ref() {
+        Some(_) => Some(req.body()),
+        None => None,
+    };
+
+    Ok((head, body))
+}
+
+/// Convert a response received from a backend into the response the proxy handles
+///
+/// The body, if any, is read on a task of its own.
+fn backend_response(
+    res: http::Response<RecvStream>,
+    trailers: &Trailers,
+    handle: &Handle,
+) -> client::Response {
+    let (parts, recv) = res.into_parts();
+
+    let status = StatusCode::try_from(parts.status.as_u16()).unwrap_or(StatusCode::BadGateway);
+    let mut r = client::Response::new().with_status(status);
+    *r.headers_mut() = from_header_map(&parts.headers);
+
+    if !recv.is_end_stream() {
+        let (tx, body) = Body::pair();
+        r.set_body(body);
+        handle.spawn(RecvBody::new(recv, tx, trailers, Trailers::set_response));
+    }
+
+    r
+}
+
+/// Convert an HTTP/2 error into the error of a request to a backend
+///
+/// A stream the backend refused was never processed, so it is reported like a refused connection
+/// and can be retried safely.
+fn error(e: h2::Error) -> hyper::Error {
+    let kind = match e.reason() {
+        Some(Reason::REFUSED_STREAM) => io::ErrorKind::ConnectionRefused,
+        _ => io::ErrorKind::Other,
+    };
+
+    hyper::Error::Io(io::Error::new(kind, e))
+}
+
+/// Convert HTTP/2 headers into HTTP/1.x headers
+///
+/// HTTP/2 clients may split the `Cookie` header into one field per cookie, which are joined back
+/// into a single header as HTTP/1.x expects (RFC 7540 Section 8.1.2.5).
+fn from_header_map(map: &HeaderMap) -> Headers {
+    let mut headers = Headers::new();
+    for (name, value) in map.iter() {
+        if name == http::header::COOKIE {
+            continue;
+        }
+        headers.append_raw(name.as_str().to_string(), value.as_bytes().to_vec());
+    }
+
+    let cookies: Vec<&str> = map.get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if !cookies.is_empty() {
+        headers.set_raw("Cookie", cookies.join("; "));
+    }
+
+    headers
+}
+
+/// Convert HTTP/1.x headers into HTTP/2 headers
+///
+/// Headers that are specific to an HTTP/1.x connection are removed, as are headers that cannot be
+/// represented in HTTP/2.
+fn to_header_map(headers: &Headers) -> HeaderMap {
+    let mut map = HeaderMap::new();
+    for header in headers.iter() {
+        if CONNECTION_HEADERS.iter().any(|name| header.name().eq_ignore_ascii_case(name)) {
+            continue;
+        }
+
+        let name = match HeaderName::from_bytes(header.name().as_bytes()) {
+            Ok(name) => name,
+            Err(_) => {
+                debug!("Dropping invalid HTTP/2 header {:?}", header.name());
+                continue;
+            }
+        };
+        for line in header.raw().iter() {
+            match HeaderValue::from_bytes(line) {
+                Ok(value) => {
+                    map.append(name.clone(), value);
+                }
+                Err(_) => debug!("Dropping invalid value of HTTP/2 header {}", name),
+            }
+        }
+    }
+
+    map
+}
+
+/// Reads a body from its stream and hands it over to the other side of the proxy
+///
+/// Received data is released back to the flow control window of the stream once there is room to
+/// hand it over, so that the peer does not send faster than the other side reads. The trailers are
+/// stored before the body ends.
+struct RecvBody {
+    recv: RecvStream,
+    tx: mpsc::Sender<Result<Chunk, hyper::Error>>,
+    trailers: Trailers,
+    store: fn(&Trailers, HeaderMap),
+
+    /// Set once the data is done and the trailers are next
+    eof: bool,
+}
+
+impl RecvBody {
+    fn new(
+        recv: RecvStream,
+        tx: mpsc::Sender<Result<Chunk, hyper::Error>>,
+        trailers: &Trailers,
+        store: fn(&Trailers, HeaderMap),
+    ) -> RecvBody {
+        RecvBody {
+            recv: recv,
+            tx: tx,
+            trailers: trailers.clone(),
+            store: store,
+            eof: false,
+        }
+    }
+}
+
+impl Future for RecvBody {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        while !self.eof {
+            match self.tx.poll_ready() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // the other side is no longer interested in the body
+                Err(_) => return Ok(Async::Ready(())),
+            }
+
+            let item = match self.recv.poll() {
+                Ok(Async::Ready(Some(data))) => {
+                    let _ = self.recv.release_capacity().release_capacity(data.len());
+                    Ok(Chunk::from(data))
+                }
+                Ok(Async::Ready(None)) => {
+                    self.eof = true;
+                    continue;
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => Err(hyper::Error::Io(io::Error::new(io::ErrorKind::Other, e))),
+            };
+
+            let failed = item.is_err();
+            match self.tx.start_send(item) {
+                Ok(AsyncSink::Ready) if !failed => {}
+                _ => return Ok(Async::Ready(())),
+            }
+        }
+
+        match self.recv.poll_trailers() {
+            Ok(Async::Ready(Some(trailers))) => (self.store)(&self.trailers, trailers),
+            Ok(Async::Ready(None)) => {}
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => {
+                let e = hyper::Error::Io(io::Error::new(io::ErrorKind::Other, e));
+                let _ = self.tx.start_send(Err(e));
+            }
+        }
+
+        // the body ends once the sender is dropped
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Sends a body from the other side of the proxy on its stream
+///
+/// Each chunk is sent as the peer makes room for it in the flow control window. The body ends with
+/// its trailers, if there are any. If the peer resets the stream, the body is dropped, which stops
+/// the other side from sending the rest of it.
+struct SendBody<B> {
+    body: B,
+    send: SendStream<Bytes>,
+    trailers: Trailers,
+    load: fn(&Trailers) -> Option<HeaderMap>,
+
+    /// The part of the last chunk that was not sent yet
+    chunk: Option<Bytes>,
+}
+
+impl<B> SendBody<B> {
+    fn new(
+        body: B,
+        send: SendStream<Bytes>,
+        trailers: Trailers,
+        load: fn(&Trailers) -> Option<HeaderMap>,
+    ) -> SendBody<B> {
+        SendBody {
+            body: body,
+            send: send,
+            trailers: trailers,
+            load: load,
+            chunk: None,
+        }
+    }
+}
+
+impl<B> Future for SendBody<B>
+where
+    B: Stream<Item = Chunk, Error = hyper::Error>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Some(mut chunk) = self.chunk.take() {
+                self.send.reserve_capacity(chunk.len());
+                match self.send.poll_capacity() {
+                    Ok(Async::Ready(Some(n))) => {
+                        let n = cmp::min(n, chunk.len());
+                        let data = chunk.split_to(n);
+                        if let Err(e) = self.send.send_data(data, false) {
+                            debug!("Unable to send HTTP/2 body: {}", e);
+                            return Ok(Async::Ready(()));
+                        }
+                        if !chunk.is_empty() {
+                            self.chunk = Some(chunk);
+                        }
+                    }
+                    Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                    Ok(Async::NotReady) => {
+                        self.chunk = Some(chunk);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        debug!("HTTP/2 stream closed while sending body: {}", e);
+                        return Ok(Async::Ready(()));
+                    }
+                }
+                continue;
+            }
+
+            match self.body.poll() {
+                Ok(Async::Ready(Some(chunk))) => {
+                    if !chunk.is_empty() {
+                        self.chunk = Some(Bytes::from(chunk));
+                    }
+                }
+                Ok(Async::Ready(None)) => {
+                    let sent = match (self.load)(&self.trailers) {
+                        Some(trailers) => self.send.send_trailers(trailers),
+                        None => self.send.send_data(Bytes::new(), true),
+                    };
+                    if let Err(e) = sent {
+                        debug!("Unable to end HTTP/2 body: {}", e);
+                    }
+                    return Ok(Async::Ready(()));
+                }
+                Ok(Async::NotReady) => {
+                    return match self.send.poll_reset() {
+                        Ok(Async::Ready(reason)) => {
+                            debug!("HTTP/2 peer reset stream: {:?}", reason);
+                            Ok(Async::Ready(()))
+                        }
+                        Ok(Async::NotReady) => Ok(Async::NotReady),
+                        Err(_) => Ok(Async::Ready(())),
+                    };
+                }
+                Err(e) => {
+                    debug!("Unable to read body for HTTP/2 stream: {}", e);
+                    self.send.send_reset(Reason::INTERNAL_ERROR);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+    }
+}
+
+/// Find out whether a plaintext connection starts with the HTTP/2 connection preface
+///
+/// Bytes are read only until they differ from the preface. They are kept by the returned
+/// connection, which reads them again, so that the connection can be served with either protocol.
+pub fn detect<S>(io: S) -> Detect<S> {
+    Detect {
+        io: Some(io),
+        buf: Vec::with_capacity(PREFACE.len()),
+    }
+}
+
+/// A future that resolves to the connection and whether it starts with the HTTP/2 preface
+pub struct Detect<S> {
+    io: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: Read> Future for Detect<S> {
+    type Item = (Rewind<S>, bool);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            if !PREFACE.starts_with(&self.buf) {
+                return Ok(Async::Ready((self.rewind(), false)));
+            }
+            if self.buf.len() == PREFACE.len() {
+                return Ok(Async::Ready((self.rewind(), true)));
+            }
+
+            let mut buf = [0; 24];
+            let want = PREFACE.len() - self.buf.len();
+            let io = self.io.as_mut().expect("cannot poll Detect twice");
+            match io.read(&mut buf[..want]) {
+                Ok(0) => return Ok(Async::Ready((self.rewind(), false))),
+                Ok(n) => self.buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<S> Detect<S> {
+    fn rewind(&mut self) -> Rewind<S> {
+        let io = self.io.take().expect("cannot poll Detect twice");
+        Rewind::new(io, ::std::mem::replace(&mut self.buf, Vec::new()))
+    }
+}
+
+/// A connection that reads bytes that were already read from it again before reading any more
+#[derive(Debug)]
+pub struct Rewind<S> {
+    io: S,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(io: S, buf: Vec<u8>) -> Rewind<S> {
+        Rewind {
+            io: io,
+            buf: buf,
+            pos: 0,
+        }
+    }
+}
+
+impl<S: Read> Read for Rewind<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.buf.len() {
+            let n = cmp::min(buf.len(), self.buf.len() - self.pos);
+            buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+
+        self.io.read(buf)
+    }
+}
+
+impl<S: Write> Write for Rewind<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Rewind<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Rewind<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect, from_header_map, to_header_map, Rewind, Trailers, PREFACE};
+    use futures::{Async, Future};
+    use http::HeaderMap;
+    use http::header::{HeaderValue, COOKIE, HOST};
+    use hyper::Headers;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_detect() {
+        let mut bytes = PREFACE.to_vec();
+        bytes.extend_from_slice(b"frames");
+        let (mut io, h2) = match detect(Cursor::new(bytes)).poll().unwrap() {
+            Async::Ready(detected) => detected,
+            Async::NotReady => panic!("detect not ready"),
+        };
+        assert!(h2);
+        let mut read = Vec::new();
+        io.read_to_end(&mut read).unwrap();
+        assert_eq!(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\nframes", &read[..]);
+
+        let bytes = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".to_vec();
+        let (mut io, h2) = match detect(Cursor::new(bytes)).poll().unwrap() {
+            Async::Ready(detected) => detected,
+            Async::NotReady => panic!("detect not ready"),
+        };
+        assert!(!h2);
+        let mut read = Vec::new();
+        io.read_to_end(&mut read).unwrap();
+        assert_eq!(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n", &read[..]);
+
+        let (_, h2) = match detect(Cursor::new(b"PRI".to_vec())).poll().unwrap() {
+            Async::Ready(detected) => detected,
+            Async::NotReady => panic!("detect not ready"),
+        };
+        assert!(!h2);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut io = Rewind::new(Cursor::new(b"world".to_vec()), b"hello ".to_vec());
+        let mut buf = [0; 4];
+        assert_eq!(4, io.read(&mut buf).unwrap());
+        assert_eq!(b"hell", &buf);
+
+        let mut read = Vec::new();
+        io.read_to_end(&mut read).unwrap();
+        assert_eq!(b"o world", &read[..]);
+    }
+
+    #[test]
+    fn test_from_header_map() {
+        let mut map = HeaderMap::new();
+        map.insert(HOST, HeaderValue::from_static("example.com"));
+        map.append(COOKIE, HeaderValue::from_static("a=1"));
+        map.append(COOKIE, HeaderValue::from_static("b=2"));
+
+        let headers = from_header_map(&map);
+        assert_eq!(Some(&b"example.com"[..]), headers.get_raw("Host").and_then(|h| h.one()));
+        assert_eq!(Some(&b"a=1; b=2"[..]), headers.get_raw("Cookie").and_then(|h| h.one()));
+    }
+
+    #[test]
+    fn test_to_header_map() {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", "text/plain");
+        headers.set_raw("Connection", "keep-alive");
+        headers.set_raw("Keep-Alive", "timeout=5");
+        headers.set_raw("Transfer-Encoding", "chunked");
+        headers.set_raw("X-Invalid", vec![b"a\nb".to_vec()]);
+
+        let map = to_header_map(&headers);
+        assert_eq!(1, map.len());
+        assert_eq!("text/plain", map["content-type"]);
+    }
+
+    #[test]
+    fn test_trailers() {
+        let mut grpc = HeaderMap::new();
+        grpc.insert("grpc-status", HeaderValue::from_static("0"));
+
+        let trailers = Trailers::new();
+        assert_eq!(None, trailers.request());
+        assert_eq!(None, trailers.take_response());
+
+        // the request trailers are kept for another attempt
+        trailers.clone().set_request(grpc.clone());
+        assert_eq!(Some(grpc.clone()), trailers.request());
+        assert_eq!(Some(grpc.clone()), trailers.request());
+
+        trailers.clone().set_response(grpc.clone());
+        assert_eq!(Some(grpc), trailers.take_response());
+        assert_eq!(None, trailers.take_response());
+    }
+
+    #[test]
+    fn test_trailers_http1() {
+        let mut grpc = HeaderMap::new();
+        grpc.insert("grpc-status", HeaderValue::from_static("0"));
+
+        // the trailers of a response from an HTTP/2 backend are not kept for an HTTP/1.x client
+        let trailers = Trailers::http1();
+        trailers.clone().set_response(grpc);
+        assert_eq!(None, trailers.take_response());
+    }
+}
@@ -19,13 +19,32 @@ extern crate capnp;
 #[macro_use]
 extern crate capnp_rpc;
 extern crate net2;
+extern crate rand;
+extern crate regex;
+extern crate openssl;
+extern crate tokio_openssl;
+extern crate h2;
+extern crate http;
+extern crate bytes;
 
 pub mod weldr_capnp {
     include!(concat!(env!("OUT_DIR"), "/weldr_capnp.rs"));
 }
 
 pub mod server;
+pub mod hash;
+pub mod sticky;
+pub mod outlier;
+pub mod circuit;
+pub mod retry;
+pub mod error;
+pub mod timeout;
+pub mod upstream;
+pub mod upgrade;
+pub mod tls;
+pub mod http2;
 pub mod pool;
+pub mod router;
 pub mod proxy;
 pub mod mgmt;
 pub mod stats;
@@ -1,15 +1,24 @@
+use std::collections::BTreeMap;
+
 use serde_json;
 
 use futures::{future, Future, Stream};
 
 use tokio_core::reactor::Handle;
 
-use hyper::{self, Delete, Get, Post, StatusCode, Uri};
+use hyper::{self, Delete, Get, Method, Post, Put, StatusCode, Uri};
 use hyper::server::{Service, Request, Response};
 use hyper::header::{ContentLength, ContentType};
 
+use config::{Timeouts, Upgrade};
 use server::Server;
-use pool::Pool;
+use hash::HashKey;
+use pool::{Pool, Strategy, DEFAULT_WEIGHT, MAX_WEIGHT};
+use router::{Host, Mirror, PathMatch, Predicate, Route, Router, Split, DEFAULT_MIRROR_MAX_BODY,
+             DEFAULT_MIRROR_MAX_IN_FLIGHT, DEFAULT_POOL};
+use sticky::{StickySession, DEFAULT_COOKIE};
+use tls::{self, Pem};
+use upstream::Protocol;
 use super::manager::Manager;
 
 // HATEOAS links: https://en.wikipedia.org/wiki/HATEOAS
@@ -29,8 +38,310 @@ struct PoolServers {
 #[derive(Debug, Serialize, Deserialize)]
 struct PoolServer {
     pub url: String,
+    pub weight: Option<u32>,
+    pub backup: Option<bool>,
+    pub in_flight: Option<usize>,
+    pub latency_ms: Option<f64>,
+    /// The circuit breaker state in each worker, keyed by worker id
+    pub circuit_breakers: Option<BTreeMap<String, String>>,
+    /// Timeouts (in milliseconds) that override the defaults of the pool
+    pub timeouts: Option<ServerTimeouts>,
+    /// The connections to the server in each worker, keyed by worker id
+    pub connections: Option<BTreeMap<String, ServerConnections>>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ServerConnections {
+    pub open: u64,
+    pub opened: u64,
+    pub requests: u64,
+    /// The requests sent on a connection that was already open
+    pub reused: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ServerTimeouts {
+    pub connect: Option<u64>,
+    pub first_byte: Option<u64>,
+    pub idle: Option<u64>,
+    pub request: Option<u64>,
+}
+
+impl ServerTimeouts {
+    /// Override the default timeouts with the timeouts that are set
+    fn apply(&self, defaults: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.unwrap_or(defaults.connect),
+            first_byte: self.first_byte.unwrap_or(defaults.first_byte),
+            idle: self.idle.unwrap_or(defaults.idle),
+            request: self.request.unwrap_or(defaults.request),
+        }
+    }
+}
+
+impl From<Timeouts> for ServerTimeouts {
+    fn from(timeouts: Timeouts) -> ServerTimeouts {
+        ServerTimeouts {
+            connect: Some(timeouts.connect),
+            first_byte: Some(timeouts.first_byte),
+            idle: Some(timeouts.idle),
+            request: Some(timeouts.request),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DrainingServers {
+    pub servers: Vec<DrainingServer>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DrainingServer {
+    pub url: String,
+    /// True once every worker has finished its requests to the server
+    pub drained: bool,
+    /// The requests still in flight in each worker, keyed by worker id
+    pub in_flight: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolSettings {
+    pub strategy: Option<String>,
+    pub hash_key: Option<String>,
+    pub sticky: Option<StickySettings>,
+    pub upgrade: Option<UpgradeSettings>,
+    /// The protocol used to send requests to the servers, either `http1` or `http2`
+    pub protocol: Option<String>,
     pub links: Option<Vec<Link>>,
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StickySettings {
+    pub enabled: bool,
+    pub cookie: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct UpgradeSettings {
+    /// Whether connections that upgrade to another protocol, such as WebSocket, are tunneled
+    pub enabled: bool,
+    /// The time (in seconds) a tunnel stays open while neither side sends anything
+    pub idle_timeout: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Pools {
+    pub pools: Vec<NamedPool>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NamedPool {
+    pub name: String,
+    /// The hosts routed to the pool, either exact or a wildcard such as `*.example.com`
+    pub hosts: Option<Vec<String>>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CertificateList {
+    pub certificates: Vec<NamedCertificate>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NamedCertificate {
+    pub name: String,
+    /// The server names the certificate is valid for
+    pub names: Option<Vec<String>>,
+    pub links: Option<Vec<Link>>,
+}
+
+/// A certificate chain and private key in PEM
+#[derive(Debug, Serialize, Deserialize)]
+struct CertificateUpload {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Routes {
+    /// The routes in the order they are checked
+    pub routes: Vec<RouteRule>,
+    pub links: Option<Vec<Link>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RouteRule {
+    pub pool: String,
+    /// Matches this path and any path below it
+    pub prefix: Option<String>,
+    /// Matches any path the regex matches, instead of a prefix
+    pub regex: Option<String>,
+    /// Replaces the part of the path that matched
+    pub rewrite: Option<String>,
+    pub methods: Option<Vec<String>>,
+    /// Regexes that the value of each header must match, keyed by header name
+    pub headers: Option<BTreeMap<String, String>>,
+    /// Regexes that the value of each query parameter must match, keyed by parameter name
+    pub query: Option<BTreeMap<String, String>>,
+    pub split: Option<RouteSplit>,
+    pub mirror: Option<RouteMirror>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RouteSplit {
+    pub pool: String,
+    /// The percentage of requests sent to the pool, from 0 to 100
+    pub percent: u8,
+    /// The hash key that keeps a client on one side of the split, such as `cookie:user`
+    pub key: Option<String>,
+}
+
+impl RouteSplit {
+    /// Validate the split sent by the client
+    fn parse(&self, router: &Router) -> Result<Split, String> {
+        if router.pool(&self.pool).is_none() {
+            return Err(format!("unknown pool: {}", self.pool));
+        }
+
+        if self.percent > 100 {
+            return Err("percent must be between 0 and 100".to_string());
+        }
+
+        let key = match self.key {
+            Some(ref key) => Some(key.parse::<HashKey>()?),
+            None => None,
+        };
+
+        Ok(Split {
+            pool: self.pool.clone(),
+            percent: self.percent,
+            key: key,
+        })
+    }
+}
+
+impl<'a> From<&'a Split> for RouteSplit {
+    fn from(split: &'a Split) -> RouteSplit {
+        RouteSplit {
+            pool: split.pool.clone(),
+            percent: split.percent,
+            key: split.key.as_ref().map(|key| key.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RouteMirror {
+    pub pool: String,
+    /// The percentage of requests copied to the pool, from 0 to 100
+    pub percent: u8,
+    /// The most copies in flight at once in each worker
+    pub max_in_flight: Option<u64>,
+    /// The largest request body (in bytes) that is copied
+    pub max_body: Option<u64>,
+}
+
+impl RouteMirror {
+    /// Validate the mirror sent by the client
+    fn parse(&self, router: &Router) -> Result<Mirror, String> {
+        if router.pool(&self.pool).is_none() {
+            return Err(format!("unknown pool: {}", self.pool));
+        }
+
+        if self.percent > 100 {
+            return Err("percent must be between 0 and 100".to_string());
+        }
+
+        Ok(Mirror::new(
+            &self.pool,
+            self.percent,
+            self.max_in_flight.unwrap_or(DEFAULT_MIRROR_MAX_IN_FLIGHT),
+            self.max_body.unwrap_or(DEFAULT_MIRROR_MAX_BODY),
+        ))
+    }
+}
+
+impl<'a> From<&'a Mirror> for RouteMirror {
+    fn from(mirror: &'a Mirror) -> RouteMirror {
+        RouteMirror {
+            pool: mirror.pool.clone(),
+            percent: mirror.percent,
+            max_in_flight: Some(mirror.max_in_flight),
+            max_body: Some(mirror.max_body),
+        }
+    }
+}
+
+impl RouteRule {
+    /// Validate the route sent by the client
+    fn parse(&self, router: &Router) -> Result<Route, String> {
+        if router.pool(&self.pool).is_none() {
+            return Err(format!("unknown pool: {}", self.pool));
+        }
+
+        let mut route = Route::new(&self.pool);
+        route.path = match (&self.prefix, &self.regex) {
+            (&Some(_), &Some(_)) => return Err("set either prefix or regex".to_string()),
+            (&Some(ref prefix), &None) if !prefix.starts_with('/') => {
+                return Err(format!("prefix must start with /: {}", prefix))
+            }
+            (&Some(ref prefix), &None) => PathMatch::Prefix(prefix.clone()),
+            (&None, &Some(ref regex)) => PathMatch::regex(regex)?,
+            (&None, &None) => route.path,
+        };
+        route.rewrite = self.rewrite.clone();
+
+        for method in self.methods.as_ref().map(|m| &m[..]).unwrap_or(&[]) {
+            route.methods.push(method.parse::<Method>().map_err(|e| e.to_string())?);
+        }
+        for (name, value) in self.headers.as_ref().into_iter().flat_map(|h| h) {
+            route.headers.push(Predicate::new(name, value)?);
+        }
+        for (name, value) in self.query.as_ref().into_iter().flat_map(|q| q) {
+            route.query.push(Predicate::new(name, value)?);
+        }
+        if let Some(ref split) = self.split {
+            route.split = Some(split.parse(router)?);
+        }
+        if let Some(ref mirror) = self.mirror {
+            route.mirror = Some(mirror.parse(router)?);
+        }
+
+        Ok(route)
+    }
+}
+
+impl<'a> From<&'a Route> for RouteRule {
+    fn from(route: &'a Route) -> RouteRule {
+        let predicates = |predicates: &[Predicate]| {
+            predicates
+                .iter()
+                .map(|p| (p.name.clone(), p.value.as_str().to_string()))
+                .collect()
+        };
+
+        let (prefix, regex) = match route.path {
+            PathMatch::Prefix(ref prefix) => (Some(prefix.clone()), None),
+            PathMatch::Regex(ref regex) => (None, Some(regex.as_str().to_string())),
+        };
+
+        RouteRule {
+            pool: route.pool.clone(),
+            prefix: prefix,
+            regex: regex,
+            rewrite: route.rewrite.clone(),
+            methods: Some(route.methods.iter().map(|m| m.to_string()).collect()),
+            headers: Some(predicates(&route.headers)),
+            query: Some(predicates(&route.query)),
+            split: route.split.as_ref().map(RouteSplit::from),
+            mirror: route.mirror.as_ref().map(RouteMirror::from),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Index {
     pub about: String,
@@ -46,6 +357,31 @@ fn index() -> Response {
                 href: "/servers".to_string(),
                 method: None,
             },
+            Link {
+                rel: "pool".to_string(),
+                href: "/pool".to_string(),
+                method: None,
+            },
+            Link {
+                rel: "draining".to_string(),
+                href: "/servers/draining".to_string(),
+                method: None,
+            },
+            Link {
+                rel: "pools".to_string(),
+                href: "/pools".to_string(),
+                method: None,
+            },
+            Link {
+                rel: "routes".to_string(),
+                href: "/routes".to_string(),
+                method: None,
+            },
+            Link {
+                rel: "certificates".to_string(),
+                href: "/certificates".to_string(),
+                method: None,
+            },
         ],
     };
 
@@ -57,15 +393,178 @@ fn index() -> Response {
         .with_body(body)
 }
 
-fn all_servers_reponse(pool: &Pool) -> Response {
+/// Returns the path of the servers of a pool
+///
+/// The servers of the default pool are also found at `/servers`.
+fn servers_path(name: &str) -> String {
+    if name == DEFAULT_POOL {
+        "/servers".to_string()
+    } else {
+        format!("/pools/{}/servers", name)
+    }
+}
+
+/// Returns the path of the settings of a pool
+fn settings_path(name: &str) -> String {
+    if name == DEFAULT_POOL {
+        "/pool".to_string()
+    } else {
+        format!("/pools/{}", name)
+    }
+}
+
+/// Returns the index of the route in a path of the form `/routes/:index/split`
+fn split_path(path: &str) -> Option<usize> {
+    let len = "/routes/".len() + "/split".len();
+    if path.len() <= len || !path.starts_with("/routes/") || !path.ends_with("/split") {
+        return None;
+    }
+
+    path["/routes/".len()..path.len() - "/split".len()]
+        .parse::<usize>()
+        .ok()
+}
+
+/// Returns the name of the certificate in a path of the form `/certificates/:name`
+fn certificate_path(path: &str) -> Option<&str> {
+    if !path.starts_with("/certificates/") {
+        return None;
+    }
+
+    let name = &path["/certificates/".len()..];
+    if name.is_empty() || name.contains('/') {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Split a path below `/pools/` into the name of the pool and the rest of the path
+fn pool_path(path: &str) -> Option<(&str, &str)> {
+    if !path.starts_with("/pools/") {
+        return None;
+    }
+
+    let path = &path["/pools/".len()..];
+    let (name, rest) = match path.find('/') {
+        Some(i) => (&path[..i], &path[i + 1..]),
+        None => (path, ""),
+    };
+
+    if name.is_empty() {
+        None
+    } else {
+        Some((name, rest.trim_right_matches('/')))
+    }
+}
+
+fn all_pools_response(router: &Router) -> Response {
+    let pools: Vec<NamedPool> = router
+        .pools()
+        .keys()
+        .map(|name| {
+            let hosts = router.hosts(name).iter().map(|h| h.to_string()).collect();
+            let mut links = vec![
+                Link {
+                    rel: "servers".to_string(),
+                    href: servers_path(name),
+                    method: None,
+                },
+                Link {
+                    rel: "pool".to_string(),
+                    href: settings_path(name),
+                    method: None,
+                },
+            ];
+            if name != DEFAULT_POOL {
+                links.push(Link {
+                    rel: "delete".to_string(),
+                    href: format!("/pools/{}", name),
+                    method: Some("DELETE".to_string()),
+                });
+            }
+
+            NamedPool {
+                name: name.clone(),
+                hosts: Some(hosts),
+                links: Some(links),
+            }
+        })
+        .collect();
+
+    let pools = Pools {
+        pools: pools,
+        links: Some(vec![
+            Link {
+                rel: "add".to_string(),
+                href: "/pools".to_string(),
+                method: Some("POST".to_string()),
+            },
+        ]),
+    };
+
+    let body = serde_json::to_string_pretty(&pools).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+fn routes_response(router: &Router) -> Response {
+    let routes = Routes {
+        routes: router.routes().iter().map(RouteRule::from).collect(),
+        links: Some(vec![
+            Link {
+                rel: "update".to_string(),
+                href: "/routes".to_string(),
+                method: Some("PUT".to_string()),
+            },
+        ]),
+    };
+
+    let body = serde_json::to_string_pretty(&routes).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+fn all_servers_reponse(name: &str, pool: &Pool, manager: &Manager) -> Response {
     let backends = pool.all();
-    let all_servers: Vec<Server> = backends.iter().map(|backend| backend.server()).collect();
-    let servers: Vec<PoolServer> = all_servers
+    let servers: Vec<PoolServer> = backends
         .into_iter()
-        .map(|server| {
-            let delete_href = format!("/servers/{}", server.url());
+        .map(|backend| {
+            let server = backend.server();
+            let delete_href = format!("{}/{}", servers_path(name), server.url());
+            let circuit_breakers = manager
+                .circuit_breakers(name, &server.url())
+                .into_iter()
+                .map(|(worker, state)| (worker.to_string(), state.to_string()))
+                .collect();
+            let connections = manager
+                .connections(name, &server.url())
+                .into_iter()
+                .map(|(worker, c)| {
+                    let connections = ServerConnections {
+                        open: c.open,
+                        opened: c.opened,
+                        requests: c.requests,
+                        reused: c.reused(),
+                    };
+                    (worker.to_string(), connections)
+                })
+                .collect();
             PoolServer {
                 url: server.url().as_ref().to_string(),
+                weight: Some(backend.weight()),
+                backup: Some(backend.is_backup()),
+                in_flight: Some(backend.in_flight()),
+                latency_ms: backend.latency(),
+                circuit_breakers: Some(circuit_breakers),
+                timeouts: Some(backend.timeouts().unwrap_or_else(|| pool.timeouts()).into()),
+                connections: Some(connections),
                 links: Some(vec![
                     Link {
                         rel: "delete".to_string(),
@@ -82,7 +581,7 @@ fn all_servers_reponse(pool: &Pool) -> Response {
         links: Some(vec![
             Link {
                 rel: "add".to_string(),
-                href: "/servers".to_string(),
+                href: servers_path(name),
                 method: Some("POST".to_string()),
             },
         ]),
@@ -96,12 +595,215 @@ fn all_servers_reponse(pool: &Pool) -> Response {
         .with_body(body)
 }
 
-fn get_servers(pool: &Pool) -> Response {
-    all_servers_reponse(pool)
+fn get_servers(name: &str, pool: &Pool, manager: &Manager) -> Response {
+    all_servers_reponse(name, pool, manager)
+}
+
+fn draining_servers_response(name: &str, pool: &Pool, manager: &Manager) -> Response {
+    let servers: Vec<DrainingServer> = pool.all()
+        .into_iter()
+        .filter(|backend| backend.is_draining())
+        .map(|backend| {
+            let url = backend.server().url();
+            let in_flight = manager
+                .drain_progress(name, &url)
+                .into_iter()
+                .map(|(worker, in_flight)| (worker.to_string(), in_flight))
+                .collect();
+            DrainingServer {
+                url: url.as_ref().to_string(),
+                drained: manager.is_drained(name, &url),
+                in_flight: in_flight,
+            }
+        })
+        .collect();
+
+    let draining_servers = DrainingServers {
+        servers: servers,
+        links: Some(vec![
+            Link {
+                rel: "drain".to_string(),
+                href: servers_path(name),
+                method: Some("DELETE".to_string()),
+            },
+        ]),
+    };
+
+    let body =
+        serde_json::to_string_pretty(&draining_servers).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+fn pool_settings_response(name: &str, pool: &Pool) -> Response {
+    let settings = PoolSettings {
+        strategy: Some(pool.strategy().to_string()),
+        hash_key: Some(pool.hash_key().to_string()),
+        sticky: Some(StickySettings {
+            enabled: pool.sticky().is_some(),
+            cookie: pool.sticky().map(|sticky| sticky.cookie().to_string()),
+        }),
+        upgrade: Some(UpgradeSettings {
+            enabled: pool.upgrade().enabled,
+            idle_timeout: Some(pool.upgrade().idle_timeout),
+        }),
+        protocol: Some(pool.protocol().to_string()),
+        links: Some(vec![
+            Link {
+                rel: "update".to_string(),
+                href: settings_path(name),
+                method: Some("PUT".to_string()),
+            },
+        ]),
+    };
+
+    let body = serde_json::to_string_pretty(&settings).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+fn certificates_response(certificates: &tls::Certificates) -> Response {
+    let certificates = certificates
+        .names()
+        .into_iter()
+        .map(|(name, names)| {
+            NamedCertificate {
+                links: Some(vec![
+                    Link {
+                        rel: "replace".to_string(),
+                        href: format!("/certificates/{}", name),
+                        method: Some("PUT".to_string()),
+                    },
+                ]),
+                name: name,
+                names: Some(names),
+            }
+        })
+        .collect();
+
+    let certificates = CertificateList { certificates: certificates };
+    let body = serde_json::to_string_pretty(&certificates).expect("Failed to encode into json");
+
+    Response::new()
+        .with_header(ContentLength(body.len() as u64))
+        .with_header(ContentType::json())
+        .with_body(body)
+}
+
+fn bad_request(body: String) -> Response {
+    Response::new()
+        .with_status(StatusCode::BadRequest)
+        .with_header(ContentLength(body.len() as u64))
+        .with_body(body)
+}
+
+/// Validate the settings sent by the client
+///
+/// Settings that were not sent keep their current value.
+fn parse_pool_settings(
+    pool: &Pool,
+    settings: PoolSettings,
+) -> Result<(Strategy, HashKey, Option<StickySession>, Upgrade, Protocol), String> {
+    let strategy = match settings.strategy {
+        Some(ref strategy) => strategy.parse::<Strategy>()?,
+        None => pool.strategy(),
+    };
+
+    let hash_key = match settings.hash_key {
+        Some(ref hash_key) => hash_key.parse::<HashKey>()?,
+        None => pool.hash_key(),
+    };
+
+    let sticky = match settings.sticky {
+        Some(StickySettings { enabled: false, .. }) => None,
+        Some(StickySettings { enabled: true, cookie }) => {
+            // keep the current secret so existing cookies stay valid
+            let current = pool.sticky();
+            let cookie = cookie
+                .or_else(|| current.as_ref().map(|s| s.cookie().to_string()))
+                .unwrap_or_else(|| DEFAULT_COOKIE.to_string());
+
+            match current {
+                Some(current) => Some(StickySession::new(cookie, current.secret().to_string())),
+                None => Some(StickySession::with_random_secret(cookie)),
+            }
+        }
+        None => pool.sticky(),
+    };
+
+    let upgrade = match settings.upgrade {
+        Some(UpgradeSettings { enabled, idle_timeout }) => Upgrade {
+            enabled: enabled,
+            idle_timeout: idle_timeout.unwrap_or(pool.upgrade().idle_timeout),
+        },
+        None => pool.upgrade(),
+    };
+
+    let protocol = match settings.protocol {
+        Some(ref protocol) => protocol.parse::<Protocol>()?,
+        None => pool.protocol(),
+    };
+
+    Ok((strategy, hash_key, sticky, upgrade, protocol))
+}
+
+fn update_pool(
+    request: Request,
+    name: String,
+    pool: Pool,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+
+    let work = request
+        .body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend(&chunk[..]);
+            future::ok::<_, hyper::Error>(v)
+        })
+        .and_then(move |chunks| {
+            let body = String::from_utf8(chunks).unwrap();
+
+            let response = match serde_json::from_str::<PoolSettings>(&body) {
+                Ok(settings) => {
+                    debug!("body = {:?}", settings);
+
+                    match parse_pool_settings(&pool, settings) {
+                        Ok((strategy, hash_key, sticky, upgrade, protocol)) => {
+                            pool.set_strategy(strategy);
+                            pool.set_hash_key(hash_key.clone());
+                            pool.set_sticky(sticky.clone());
+                            pool.set_upgrade(upgrade.clone());
+                            pool.set_protocol(protocol);
+                            info!("Changed pool strategy to {} using {}", strategy, hash_key);
+                            manager.publish_strategy(&name, strategy, &hash_key, handle.clone());
+                            manager.publish_sticky_session(&name, sticky.as_ref(), handle.clone());
+                            manager.publish_upgrade(&name, &upgrade, handle.clone());
+                            manager.publish_protocol(&name, protocol, handle);
+
+                            pool_settings_response(&name, &pool)
+                        }
+                        Err(e) => bad_request(e),
+                    }
+                }
+                Err(e) => bad_request(format!("invalid JSON: {}", e)),
+            };
+
+            ::futures::finished(response)
+        });
+
+    Box::new(work)
 }
 
 fn add_server(
     request: Request,
+    name: String,
     pool: Pool,
     manager: Manager,
     handle: Handle,
@@ -117,32 +819,95 @@ fn add_server(
             let body = String::from_utf8(chunks).unwrap();
 
             let response = match serde_json::from_str::<PoolServer>(&body) {
+                Ok(ref server) if server.weight == Some(0) => {
+                    bad_request("weight must be greater than 0".to_string())
+                }
+                Ok(ref server) if server.weight > Some(MAX_WEIGHT) => {
+                    bad_request(format!("weight must be at most {}", MAX_WEIGHT))
+                }
                 Ok(server) => {
                     debug!("body = {:?}", server);
 
+                    let weight = server.weight.unwrap_or(DEFAULT_WEIGHT);
+                    let backup = server.backup.unwrap_or(false);
+                    let timeouts = server.timeouts.as_ref().map(
+                        |t| t.apply(pool.timeouts()),
+                    );
                     let backend = server
                         .url
                         .parse::<Uri>()
                         .expect("Failed to parse server url");
                     let backend = Server::new(backend, true);
-                    pool.add(backend);
+                    if backup {
+                        pool.add_backup(backend.clone(), weight);
+                    } else {
+                        pool.add_with_weight(backend.clone(), weight);
+                    }
+                    if let Some(ref timeouts) = timeouts {
+                        pool.set_server_timeouts(&backend, timeouts.clone());
+                    }
                     debug!("Added new server to pool");
 
                     let backend = server
                         .url
                         .parse::<Uri>()
                         .expect("Failed to parse server url");
-                    manager.publish_new_server(backend, handle);
+                    manager.publish_new_server(&name, backend, weight, backup, timeouts, handle);
 
-                    all_servers_reponse(&pool)
+                    all_servers_reponse(&name, &pool, &manager)
                 }
-                Err(e) => {
-                    let body = format!("invalid JSON: {}", e);
-                    Response::new()
-                        .with_status(StatusCode::BadRequest)
-                        .with_header(ContentLength(body.len() as u64))
-                        .with_body(body)
+                Err(e) => bad_request(format!("invalid JSON: {}", e)),
+            };
+
+            ::futures::finished(response)
+        });
+
+    Box::new(work)
+}
+
+/// Gracefully remove a server from the pool
+///
+/// The server gets no new requests and is removed by each worker once its requests in flight
+/// finish. The manager removes the server once every worker has drained it.
+fn drain_server(
+    request: Request,
+    name: String,
+    pool: Pool,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+
+    let work = request
+        .body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend(&chunk[..]);
+            future::ok::<_, hyper::Error>(v)
+        })
+        .and_then(move |chunks| {
+            let body = String::from_utf8(chunks).unwrap();
+
+            let response = match serde_json::from_str::<PoolServer>(&body) {
+                Ok(server) => {
+                    debug!("body = {:?}", server);
+
+                    match server.url.parse::<Uri>() {
+                        Ok(url) => {
+                            match pool.find(&Server::new(url.clone(), true)) {
+                                Some(backend) => {
+                                    info!("Draining {:?}", backend);
+                                    backend.mark_draining();
+                                    manager.publish_drain_server(&name, &url, handle);
+
+                                    draining_servers_response(&name, &pool, &manager)
+                                        .with_status(StatusCode::Accepted)
+                                }
+                                None => Response::new().with_status(StatusCode::NotFound),
+                            }
+                        }
+                        Err(e) => bad_request(format!("invalid url: {}", e)),
+                    }
                 }
+                Err(e) => bad_request(format!("invalid JSON: {}", e)),
             };
 
             ::futures::finished(response)
@@ -151,6 +916,224 @@ fn add_server(
     Box::new(work)
 }
 
+/// Add a named pool, or change the hosts routed to a pool that already exists
+fn add_pool(
+    request: Request,
+    router: Router,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+
+    let work = request
+        .body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend(&chunk[..]);
+            future::ok::<_, hyper::Error>(v)
+        })
+        .and_then(move |chunks| {
+            let body = String::from_utf8(chunks).unwrap();
+
+            let response = match serde_json::from_str::<NamedPool>(&body) {
+                Ok(ref pool) if pool.name.is_empty() || pool.name.contains('/') => {
+                    bad_request(format!("invalid pool name: {:?}", pool.name))
+                }
+                Ok(ref pool) if pool.name == DEFAULT_POOL => {
+                    bad_request("the hosts of the default pool cannot be set".to_string())
+                }
+                Ok(pool) => {
+                    debug!("body = {:?}", pool);
+
+                    let hosts = pool.hosts
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|h| h.parse::<Host>())
+                        .collect::<Result<Vec<Host>, String>>();
+
+                    match hosts {
+                        Ok(hosts) => {
+                            info!("Routing {:?} to pool {:?}", hosts, pool.name);
+                            router.add_pool(&pool.name, hosts.clone());
+                            manager.publish_add_pool(&pool.name, &hosts, handle);
+
+                            all_pools_response(&router)
+                        }
+                        Err(e) => bad_request(e),
+                    }
+                }
+                Err(e) => bad_request(format!("invalid JSON: {}", e)),
+            };
+
+            ::futures::finished(response)
+        });
+
+    Box::new(work)
+}
+
+/// Replace all routes
+///
+/// The routes are replaced together so that the order in which they are checked is clear. None of
+/// the routes are changed if any route is invalid.
+fn update_routes(
+    request: Request,
+    router: Router,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+
+    let work = request
+        .body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend(&chunk[..]);
+            future::ok::<_, hyper::Error>(v)
+        })
+        .and_then(move |chunks| {
+            let body = String::from_utf8(chunks).unwrap();
+
+            let response = match serde_json::from_str::<Routes>(&body) {
+                Ok(routes) => {
+                    debug!("body = {:?}", routes);
+
+                    let parsed = routes
+                        .routes
+                        .iter()
+                        .map(|r| r.parse(&router))
+                        .collect::<Result<Vec<Route>, String>>();
+
+                    match parsed {
+                        Ok(routes) => {
+                            info!("Changed routes to {:?}", routes);
+                            manager.publish_routes(&routes, handle);
+                            router.set_routes(routes);
+
+                            routes_response(&router)
+                        }
+                        Err(e) => bad_request(e),
+                    }
+                }
+                Err(e) => bad_request(format!("invalid JSON: {}", e)),
+            };
+
+            ::futures::finished(response)
+        });
+
+    Box::new(work)
+}
+
+/// Change or remove the split of one route
+///
+/// This is how the percentage sent to a canary is adjusted without replacing all routes. A `DELETE`
+/// removes the split, sending every request back to the pool of the route.
+fn update_split(
+    request: Request,
+    index: usize,
+    router: Router,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+
+    let remove = *request.method() == Delete;
+    let work = request
+        .body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend(&chunk[..]);
+            future::ok::<_, hyper::Error>(v)
+        })
+        .and_then(move |chunks| {
+            let body = String::from_utf8(chunks).unwrap();
+
+            let split = if remove {
+                Ok(None)
+            } else {
+                serde_json::from_str::<RouteSplit>(&body)
+                    .map_err(|e| format!("invalid JSON: {}", e))
+                    .and_then(|split| split.parse(&router))
+                    .map(Some)
+            };
+
+            let response = match split {
+                Ok(split) => {
+                    debug!("split = {:?}", split);
+
+                    if router.set_split(index, split) {
+                        info!("Changed split of route {}", index);
+                        manager.publish_routes(&router.routes(), handle);
+                        routes_response(&router)
+                    } else {
+                        Response::new().with_status(StatusCode::NotFound)
+                    }
+                }
+                Err(e) => bad_request(e),
+            };
+
+            ::futures::finished(response)
+        });
+
+    Box::new(work)
+}
+
+/// Add a certificate to the TLS listener, or replace the certificate with the same name
+///
+/// The certificate is checked before it is sent to the workers, so that an invalid certificate
+/// never replaces a valid one.
+fn update_certificate(
+    request: Request,
+    name: String,
+    certificates: tls::Certificates,
+    manager: Manager,
+    handle: Handle,
+) -> Box<Future<Item = Response, Error = hyper::Error>> {
+
+    let work = request
+        .body()
+        .fold(Vec::new(), |mut v, chunk| {
+            v.extend(&chunk[..]);
+            future::ok::<_, hyper::Error>(v)
+        })
+        .and_then(move |chunks| {
+            let body = String::from_utf8(chunks).unwrap();
+
+            let response = match serde_json::from_str::<CertificateUpload>(&body) {
+                Ok(upload) => {
+                    let pem = Pem {
+                        cert: upload.cert.into_bytes(),
+                        key: upload.key.into_bytes(),
+                    };
+
+                    match certificates.set(&name, pem.clone()) {
+                        Ok(()) => {
+                            info!("Changed certificate {:?}", name);
+                            manager.publish_certificate(&name, &pem, handle);
+                            certificates_response(&certificates)
+                        }
+                        Err(e) => bad_request(e.to_string()),
+                    }
+                }
+                Err(e) => bad_request(format!("invalid JSON: {}", e)),
+            };
+
+            ::futures::finished(response)
+        });
+
+    Box::new(work)
+}
+
+/// Remove a named pool
+///
+/// Requests in flight to the servers of the pool are allowed to finish.
+fn remove_pool(name: &str, router: &Router, manager: &Manager, handle: Handle) -> Response {
+    if name == DEFAULT_POOL {
+        return bad_request("the default pool cannot be removed".to_string());
+    }
+
+    if !router.remove_pool(name) {
+        return Response::new().with_status(StatusCode::NotFound);
+    }
+
+    info!("Removed pool {:?}", name);
+    manager.publish_remove_pool(name, handle);
+    all_pools_response(router)
+}
+
 // TODO figure out how to parse out query k/v pairs or parse the path
 //fn remove_server(context: Context, response: Response) {
 //
@@ -168,17 +1151,112 @@ fn add_server(
 
 #[derive(Debug)]
 pub struct Mgmt {
-    pool: Pool,
+    router: Router,
     handle: Handle,
     manager: Manager,
+
+    /// The certificates of the TLS listener, if it is enabled
+    certificates: Option<tls::Certificates>,
 }
 
 impl Mgmt {
-    pub fn new(pool: Pool, handle: Handle, manager: Manager) -> Mgmt {
+    pub fn new(
+        router: Router,
+        handle: Handle,
+        manager: Manager,
+        certificates: Option<tls::Certificates>,
+    ) -> Mgmt {
         Mgmt {
-            pool: pool,
+            router: router,
             handle: handle,
             manager: manager,
+            certificates: certificates,
+        }
+    }
+
+    /// Handle a request to `/certificates` or below it
+    fn call_certificates(&self, req: Request, name: Option<String>) -> <Self as Service>::Future {
+        let certificates = match self.certificates {
+            Some(ref certificates) => certificates.clone(),
+            None => {
+                return Box::new(::futures::finished(
+                    bad_request("TLS is not enabled".to_string()),
+                ))
+            }
+        };
+
+        match (req.method().clone(), name) {
+            (Get, None) => Box::new(::futures::finished(certificates_response(&certificates))),
+            (Put, Some(name)) => {
+                update_certificate(
+                    req,
+                    name,
+                    certificates,
+                    self.manager.clone(),
+                    self.handle.clone(),
+                )
+            }
+            _ => {
+                Box::new(::futures::finished(
+                    Response::new().with_status(StatusCode::NotFound),
+                ))
+            }
+        }
+    }
+
+    /// Handle a request to `/pools/:name` or below it
+    fn call_pool(&self, req: Request, name: String, rest: &str) -> <Self as Service>::Future {
+        if rest.is_empty() && *req.method() == Delete {
+            return Box::new(::futures::finished(remove_pool(
+                &name,
+                &self.router,
+                &self.manager,
+                self.handle.clone(),
+            )));
+        }
+
+        let pool = match self.router.pool(&name) {
+            Some(pool) => pool,
+            None => {
+                return Box::new(::futures::finished(
+                    Response::new().with_status(StatusCode::NotFound),
+                ))
+            }
+        };
+
+        self.call_with(req, name, pool, rest)
+    }
+
+    /// Handle a request for the servers or settings of a pool
+    fn call_with(
+        &self,
+        req: Request,
+        name: String,
+        pool: Pool,
+        path: &str,
+    ) -> <Self as Service>::Future {
+        match (req.method(), path) {
+            (&Get, "servers") => {
+                Box::new(::futures::finished(get_servers(&name, &pool, &self.manager)))
+            }
+            (&Post, "servers") => {
+                add_server(req, name, pool, self.manager.clone(), self.handle.clone())
+            }
+            (&Delete, "servers") => {
+                drain_server(req, name, pool, self.manager.clone(), self.handle.clone())
+            }
+            (&Get, "servers/draining") => {
+                Box::new(::futures::finished(
+                    draining_servers_response(&name, &pool, &self.manager),
+                ))
+            }
+            (&Get, "") => Box::new(::futures::finished(pool_settings_response(&name, &pool))),
+            (&Put, "") => update_pool(req, name, pool, self.manager.clone(), self.handle.clone()),
+            _ => {
+                Box::new(::futures::finished(
+                    Response::new().with_status(StatusCode::NotFound),
+                ))
+            }
         }
     }
 }
@@ -190,25 +1268,65 @@ impl Service for Mgmt {
     type Future = Box<Future<Item = Response, Error = hyper::Error>>;
 
     fn call(&self, req: Request) -> Self::Future {
-        match (req.method(), req.path()) {
-            (&Get, "/") => Box::new(::futures::finished(index())),
-            (&Get, "/servers") => Box::new(::futures::finished(get_servers(&self.pool))),
-            (&Post, "/servers") => {
-                add_server(
+        let named = pool_path(req.path()).map(
+            |(name, rest)| (name.to_string(), rest.to_string()),
+        );
+        if let Some((name, rest)) = named {
+            return self.call_pool(req, name, &rest);
+        }
+
+        let certificate = certificate_path(req.path()).map(|name| name.to_string());
+        if let Some(name) = certificate {
+            return self.call_certificates(req, Some(name));
+        }
+
+        if let Some(index) = split_path(req.path()) {
+            return match *req.method() {
+                Put | Delete => {
+                    update_split(
+                        req,
+                        index,
+                        self.router.clone(),
+                        self.manager.clone(),
+                        self.handle.clone(),
+                    )
+                }
+                _ => {
+                    Box::new(::futures::finished(
+                        Response::new().with_status(StatusCode::NotFound),
+                    ))
+                }
+            };
+        }
+
+        // the paths outside of `/pools` are for the default pool
+        let name = DEFAULT_POOL.to_string();
+        let pool = self.router.default_pool();
+        let path = req.path().to_string();
+        match (req.method().clone(), path.as_str()) {
+            (Get, "/") => Box::new(::futures::finished(index())),
+            (Get, "/pools") => Box::new(::futures::finished(all_pools_response(&self.router))),
+            (Get, "/routes") => Box::new(::futures::finished(routes_response(&self.router))),
+            (_, "/certificates") => self.call_certificates(req, None),
+            (Put, "/routes") => {
+                update_routes(
                     req,
-                    self.pool.clone(),
+                    self.router.clone(),
                     self.manager.clone(),
                     self.handle.clone(),
                 )
             }
-            (&Delete, "/servers") => {
-                let body = "Remove server";
-                Box::new(::futures::finished(
-                    Response::new()
-                        .with_header(ContentLength(body.len() as u64))
-                        .with_body(body),
-                ))
+            (Post, "/pools") => {
+                add_pool(
+                    req,
+                    self.router.clone(),
+                    self.manager.clone(),
+                    self.handle.clone(),
+                )
             }
+            (_, "/servers") |
+            (_, "/servers/draining") => self.call_with(req, name, pool, &path[1..]),
+            (_, "/pool") => self.call_with(req, name, pool, ""),
             _ => {
                 Box::new(::futures::finished(
                     Response::new().with_status(StatusCode::NotFound),
@@ -217,3 +1335,131 @@ impl Service for Mgmt {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{certificate_path, parse_pool_settings, pool_path, servers_path, split_path,
+                PoolSettings, RouteRule};
+    use hyper::{server, Method};
+    use pool::Pool;
+    use router::{Router, DEFAULT_MIRROR_MAX_BODY, DEFAULT_MIRROR_MAX_IN_FLIGHT};
+    use serde_json;
+    use upstream::Protocol;
+
+    #[test]
+    fn test_pool_path() {
+        assert_eq!(Some(("api", "")), pool_path("/pools/api"));
+        assert_eq!(Some(("api", "servers")), pool_path("/pools/api/servers"));
+        assert_eq!(Some(("api", "servers")), pool_path("/pools/api/servers/"));
+        assert_eq!(Some(("api", "servers/draining")), pool_path("/pools/api/servers/draining"));
+        assert_eq!(None, pool_path("/pools/"));
+        assert_eq!(None, pool_path("/pools"));
+        assert_eq!(None, pool_path("/servers"));
+    }
+
+    #[test]
+    fn test_certificate_path() {
+        assert_eq!(Some("example.com"), certificate_path("/certificates/example.com"));
+        assert_eq!(None, certificate_path("/certificates/"));
+        assert_eq!(None, certificate_path("/certificates/a/b"));
+        assert_eq!(None, certificate_path("/certificates"));
+    }
+
+    #[test]
+    fn test_parse_route_rule() {
+        let router = Router::new(Pool::default());
+        router.add_pool("mobile", Vec::new());
+
+        let rule = r#"{"pool": "mobile", "prefix": "/api", "methods": ["GET"],
+                       "headers": {"X-Api-Version": "^2"}}"#;
+        let rule: RouteRule = serde_json::from_str(rule).unwrap();
+        let route = rule.parse(&router).unwrap();
+
+        let mut req = server::Request::new(Method::Get, "/api/users".parse().unwrap());
+        req.headers_mut().set_raw("X-Api-Version", "2");
+        assert!(route.matches_request(&req).is_some());
+
+        let rule = r#"{"pool": "missing"}"#;
+        let rule: RouteRule = serde_json::from_str(rule).unwrap();
+        assert!(rule.parse(&router).is_err());
+
+        let rule = r#"{"pool": "mobile", "prefix": "/api", "regex": "^/api"}"#;
+        let rule: RouteRule = serde_json::from_str(rule).unwrap();
+        assert!(rule.parse(&router).is_err());
+    }
+
+    #[test]
+    fn test_parse_split() {
+        let router = Router::new(Pool::default());
+        router.add_pool("stable", Vec::new());
+        router.add_pool("canary", Vec::new());
+
+        let rule = r#"{"pool": "stable",
+                       "split": {"pool": "canary", "percent": 10, "key": "cookie:user"}}"#;
+        let rule: RouteRule = serde_json::from_str(rule).unwrap();
+        let split = rule.parse(&router).unwrap().split.unwrap();
+        assert_eq!(10, split.percent);
+        assert_eq!("cookie:user", split.key.unwrap().to_string());
+
+        let rule = r#"{"pool": "stable", "split": {"pool": "canary", "percent": 101}}"#;
+        let rule: RouteRule = serde_json::from_str(rule).unwrap();
+        assert!(rule.parse(&router).is_err());
+
+        assert_eq!(Some(2), split_path("/routes/2/split"));
+        assert_eq!(None, split_path("/routes/x/split"));
+        assert_eq!(None, split_path("/routes"));
+        assert_eq!(None, split_path("/routes/split"));
+    }
+
+    #[test]
+    fn test_parse_upgrade_settings() {
+        let pool = Pool::default();
+        let settings = r#"{"upgrade": {"enabled": true}}"#;
+        let settings: PoolSettings = serde_json::from_str(settings).unwrap();
+        let (_, _, _, upgrade, _) = parse_pool_settings(&pool, settings).unwrap();
+        assert!(upgrade.enabled);
+        assert_eq!(pool.upgrade().idle_timeout, upgrade.idle_timeout);
+
+        let settings: PoolSettings = serde_json::from_str("{}").unwrap();
+        let (_, _, _, upgrade, _) = parse_pool_settings(&pool, settings).unwrap();
+        assert!(!upgrade.enabled);
+    }
+
+    #[test]
+    fn test_parse_protocol_settings() {
+        let pool = Pool::default();
+        let settings: PoolSettings = serde_json::from_str(r#"{"protocol": "http2"}"#).unwrap();
+        let (_, _, _, _, protocol) = parse_pool_settings(&pool, settings).unwrap();
+        assert_eq!(Protocol::Http2, protocol);
+
+        let settings: PoolSettings = serde_json::from_str("{}").unwrap();
+        let (_, _, _, _, protocol) = parse_pool_settings(&pool, settings).unwrap();
+        assert_eq!(Protocol::Http1, protocol);
+
+        let settings: PoolSettings = serde_json::from_str(r#"{"protocol": "spdy"}"#).unwrap();
+        assert!(parse_pool_settings(&pool, settings).is_err());
+    }
+
+    #[test]
+    fn test_parse_mirror() {
+        let router = Router::new(Pool::default());
+        router.add_pool("shadow", Vec::new());
+
+        let rule = r#"{"pool": "default", "mirror": {"pool": "shadow", "percent": 5}}"#;
+        let rule: RouteRule = serde_json::from_str(rule).unwrap();
+        let mirror = rule.parse(&router).unwrap().mirror.unwrap();
+        assert_eq!(5, mirror.percent);
+        assert_eq!(DEFAULT_MIRROR_MAX_IN_FLIGHT, mirror.max_in_flight);
+        assert_eq!(DEFAULT_MIRROR_MAX_BODY, mirror.max_body);
+
+        let rule = r#"{"pool": "default", "mirror": {"pool": "missing", "percent": 5}}"#;
+        let rule: RouteRule = serde_json::from_str(rule).unwrap();
+        assert!(rule.parse(&router).is_err());
+    }
+
+    #[test]
+    fn test_servers_path() {
+        assert_eq!("/servers", servers_path("default"));
+        assert_eq!("/pools/api/servers", servers_path("api"));
+    }
+}
@@ -5,12 +5,15 @@ use std::collections::HashMap;
 
 use futures::Future;
 use tokio_core::reactor::Handle;
-use hyper::{Client, Uri};
+use hyper::{self, client, Client, Method, Uri};
+use hyper::client::HttpConnector;
 use hyper_tls::HttpsConnector;
 
 use pool::{Pool, Backend};
 use config::Config;
+use http2::Trailers;
 use mgmt::Manager;
+use upstream::{self, Protocol};
 
 #[derive(Debug, Clone, Copy)]
 enum HealthState {
@@ -33,6 +36,11 @@ impl BackendHealth {
         BackendHealth { inner: Rc::new(RefCell::new(Inner { health_state: HashMap::new() })) }
     }
 
+    /// Forget the health of a backend that was removed from the pool
+    pub fn remove(&self, backend: &Backend) {
+        self.inner.borrow_mut().health_state.remove(backend);
+    }
+
     pub fn should_mark_active(&self, backend: Backend, required_passes: u64) -> bool {
         let ref mut health_state = self.inner.borrow_mut().health_state;
         let state = health_state
@@ -106,15 +114,36 @@ impl BackendHealth {
     }
 }
 
-pub fn run(pool: Pool, handle: &Handle, conf: &Config, manager: Manager, health: BackendHealth) {
+/// Check the health of the servers of a pool
+pub fn run(
+    name: &str,
+    pool: Pool,
+    handle: &Handle,
+    conf: &Config,
+    manager: Manager,
+    health: BackendHealth,
+) {
     let client = Client::configure()
         .connector(HttpsConnector::new(4, &handle).unwrap())
         .build(&handle);
 
+    // a pool that speaks HTTP/2 to its servers is checked over HTTP/2, since they may not speak
+    // anything else
+    let protocol = pool.protocol();
+    let mut h2_connector = HttpConnector::new(1, &handle);
+    h2_connector.enforce_http(false);
+    let tls = upstream::h2_tls_connector();
+
     let backends = pool.all();
     let handle1 = handle.clone();
     for backend in backends {
+        // a draining server is on its way out of the pool
+        if backend.is_draining() {
+            continue;
+        }
+
         let manager = manager.clone();
+        let name = name.to_string();
         let handle1 = handle1.clone();
         let server = backend.server();
         let health = health.clone();
@@ -127,7 +156,7 @@ pub fn run(pool: Pool, handle: &Handle, conf: &Config, manager: Manager, health:
                     info!("Disabling {:?} in pool", backend);
                     backend.mark_down();
                     let uri = backend.server().url();
-                    manager.publish_server_state_down(&uri, handle1.clone());
+                    manager.publish_server_state_down(&name, &uri, handle1.clone());
                 }
                 continue;
             }
@@ -136,7 +165,18 @@ pub fn run(pool: Pool, handle: &Handle, conf: &Config, manager: Manager, health:
         let allowed_failures = conf.health_check.failures;
         let allowed_successes = conf.health_check.passes;
         debug!("Health check {:?}", url);
-        let req = client.get(url).then(move |res| match res {
+        let req: Box<Future<Item = client::Response, Error = hyper::Error>> = match protocol {
+            Protocol::Http1 => Box::new(client.get(url)),
+            Protocol::Http2 => {
+                let handle = handle.clone();
+                let req = client::Request::new(Method::Get, url.clone());
+                let connecting = upstream::connect_h2(&h2_connector, &tls, url, &handle);
+                Box::new(connecting.map_err(hyper::Error::from).and_then(move |h2| {
+                    h2.request(req, &Trailers::new(), &handle)
+                }))
+            }
+        };
+        let req = req.then(move |res| match res {
             Ok(res) => {
                 debug!("Response: {}", res.status());
                 debug!("Headers: \n{}", res.headers());
@@ -146,14 +186,14 @@ pub fn run(pool: Pool, handle: &Handle, conf: &Config, manager: Manager, health:
                         info!("Enabling {:?} in pool", backend);
                         backend.mark_active();
                         let uri = backend.server().url();
-                        manager.publish_server_state_active(&uri, handle1.clone());
+                        manager.publish_server_state_active(&name, &uri, handle1.clone());
                     }
                 } else {
                     if health.should_mark_down(backend.clone(), allowed_failures) {
                         info!("Disabling {:?} in pool", backend);
                         backend.mark_down();
                         let uri = backend.server().url();
-                        manager.publish_server_state_down(&uri, handle1.clone());
+                        manager.publish_server_state_down(&name, &uri, handle1.clone());
                     }
                 }
                 ::futures::finished(())
@@ -164,7 +204,7 @@ pub fn run(pool: Pool, handle: &Handle, conf: &Config, manager: Manager, health:
                     info!("Disabling {:?} in pool", backend);
                     backend.mark_down();
                     let uri = backend.server().url();
-                    manager.publish_server_state_down(&uri, handle1.clone());
+                    manager.publish_server_state_down(&name, &uri, handle1.clone());
                 }
                 ::futures::finished(())
             }
@@ -9,6 +9,7 @@ use std::io;
 use std::process::Command;
 use std::os::unix::process::CommandExt;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 
 use libc::pid_t;
@@ -16,6 +17,35 @@ use nix::unistd::{fork, ForkResult};
 use tokio_core::reactor::Handle;
 use hyper::Uri;
 
+use circuit::CircuitState;
+use config::{Timeouts, Upgrade};
+use hash::HashKey;
+use pool::Strategy;
+use router::{Host, Route, Router};
+use sticky::StickySession;
+use stats::Connections;
+use tls::{Certificates, Pem};
+use upstream::Protocol;
+
+/// A server in a pool, keyed by pool name and then server url
+///
+/// A server may be in more than one pool, with separate state in each.
+pub type ServerKey = (String, String);
+
+/// The circuit breaker state reported by each worker, keyed by server and then worker id
+pub type CircuitBreakers = HashMap<ServerKey, BTreeMap<u64, CircuitState>>;
+
+/// The requests in flight reported by each worker for a draining server, keyed by server and then
+/// worker id
+pub type Drains = HashMap<ServerKey, BTreeMap<u64, u64>>;
+
+/// The connections to each server reported by each worker, keyed by server and then worker id
+pub type ConnectionCounts = HashMap<ServerKey, BTreeMap<u64, Connections>>;
+
+fn server_key(pool: &str, url: &Uri) -> ServerKey {
+    (pool.to_string(), url.to_string())
+}
+
 #[derive(Debug)]
 pub struct Worker {
     id: u64,
@@ -31,6 +61,9 @@ pub struct Manager {
 pub struct Inner {
     workers: Vec<Worker>,
     subscribers: Rc<RefCell<capnp::SubscriberMap>>,
+    circuit_breakers: Rc<RefCell<CircuitBreakers>>,
+    drains: Rc<RefCell<Drains>>,
+    connections: Rc<RefCell<ConnectionCounts>>,
 }
 
 impl Manager {
@@ -39,6 +72,9 @@ impl Manager {
             inner: Rc::new(RefCell::new(Inner {
                 workers: Vec::new(),
                 subscribers: Rc::new(RefCell::new(capnp::SubscriberMap::new())),
+                circuit_breakers: Rc::new(RefCell::new(CircuitBreakers::new())),
+                drains: Rc::new(RefCell::new(Drains::new())),
+                connections: Rc::new(RefCell::new(ConnectionCounts::new())),
             })),
         }
     }
@@ -57,25 +93,182 @@ impl Manager {
     ///
     /// This works using a handle instead of running on the main core. This was done to allow the
     /// manager to perform other essential functions using the main core.
-    pub fn listen(&self, addr: SocketAddr, handle: Handle) {
+    ///
+    /// A worker that subscribes is first sent the current pools, routes and certificates, so that
+    /// a worker that restarts does not miss the changes made while the workers ran.
+    pub fn listen(
+        &self,
+        addr: SocketAddr,
+        router: Router,
+        certificates: Option<Certificates>,
+        handle: Handle,
+    ) {
 
         // TODO should the publisher should check against the worker list?
-        capnp::listen(addr, handle, self.inner.borrow().subscribers.clone())
+        let inner = self.inner.borrow();
+        capnp::listen(
+            addr,
+            handle,
+            inner.subscribers.clone(),
+            inner.circuit_breakers.clone(),
+            inner.drains.clone(),
+            inner.connections.clone(),
+            router,
+            certificates,
+        )
+    }
+
+    /// Returns the circuit breaker state of a server in a pool as last reported by each worker
+    ///
+    /// Workers only report changes, so a worker that has not reported a state has a closed circuit.
+    pub fn circuit_breakers(&self, pool: &str, url: &Uri) -> BTreeMap<u64, CircuitState> {
+        let inner = self.inner.borrow();
+        let circuit_breakers = inner.circuit_breakers.borrow();
+        circuit_breakers
+            .get(&server_key(pool, url))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the requests in flight to a draining server in a pool as last reported by each
+    /// worker
+    pub fn drain_progress(&self, pool: &str, url: &Uri) -> BTreeMap<u64, u64> {
+        let inner = self.inner.borrow();
+        let drains = inner.drains.borrow();
+        drains.get(&server_key(pool, url)).cloned().unwrap_or_default()
+    }
+
+    /// Returns the connections to a server in a pool as last reported by each worker
+    pub fn connections(&self, pool: &str, url: &Uri) -> BTreeMap<u64, Connections> {
+        let inner = self.inner.borrow();
+        let connections = inner.connections.borrow();
+        connections
+            .get(&server_key(pool, url))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns true once every worker has reported that a draining server in a pool is idle
+    pub fn is_drained(&self, pool: &str, url: &Uri) -> bool {
+        let progress = self.drain_progress(pool, url);
+        self.inner.borrow().workers.iter().all(
+            |worker| progress.get(&worker.id) == Some(&0),
+        )
+    }
+
+    /// Forget the drain progress of a server in a pool once it has been removed
+    pub fn forget_drain(&self, pool: &str, url: &Uri) {
+        let inner = self.inner.borrow();
+        inner.drains.borrow_mut().remove(&server_key(pool, url));
+    }
+
+    /// Ask all workers to add a new server to a pool
+    pub fn publish_new_server(
+        &self,
+        pool: &str,
+        url: Uri,
+        weight: u32,
+        backup: bool,
+        timeouts: Option<Timeouts>,
+        handle: Handle,
+    ) {
+        capnp::publish_new_server(
+            pool,
+            url,
+            weight,
+            backup,
+            timeouts,
+            handle,
+            self.inner.borrow().subscribers.clone(),
+        )
+    }
+
+    /// Ask all workers to mark a server down in a pool
+    pub fn publish_server_state_down(&self, pool: &str, url: &Uri, handle: Handle) {
+        capnp::publish_server_state_down(
+            pool,
+            url,
+            handle,
+            self.inner.borrow().subscribers.clone(),
+        )
+    }
+
+    /// Ask all workers to mark a server active in a pool
+    pub fn publish_server_state_active(&self, pool: &str, url: &Uri, handle: Handle) {
+        capnp::publish_server_state_active(
+            pool,
+            url,
+            handle,
+            self.inner.borrow().subscribers.clone(),
+        )
+    }
+
+    /// Ask all workers to change the strategy used to select a backend from a pool
+    pub fn publish_strategy(
+        &self,
+        pool: &str,
+        strategy: Strategy,
+        hash_key: &HashKey,
+        handle: Handle,
+    ) {
+        capnp::publish_strategy(
+            pool,
+            strategy,
+            hash_key,
+            handle,
+            self.inner.borrow().subscribers.clone(),
+        )
+    }
+
+    /// Ask all workers to enable or disable sticky sessions in a pool
+    pub fn publish_sticky_session(
+        &self,
+        pool: &str,
+        sticky: Option<&StickySession>,
+        handle: Handle,
+    ) {
+        capnp::publish_sticky_session(
+            pool,
+            sticky,
+            handle,
+            self.inner.borrow().subscribers.clone(),
+        )
+    }
+
+    /// Ask all workers to enable or disable tunneling upgraded connections in a pool
+    pub fn publish_upgrade(&self, pool: &str, upgrade: &Upgrade, handle: Handle) {
+        capnp::publish_upgrade(pool, upgrade, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to change the protocol used to send requests to the servers of a pool
+    pub fn publish_protocol(&self, pool: &str, protocol: Protocol, handle: Handle) {
+        capnp::publish_protocol(pool, protocol, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to drain a server and remove it from a pool once it is idle
+    pub fn publish_drain_server(&self, pool: &str, url: &Uri, handle: Handle) {
+        capnp::publish_drain_server(pool, url, handle, self.inner.borrow().subscribers.clone())
+    }
+
+    /// Ask all workers to add a named pool, or change the hosts routed to it
+    pub fn publish_add_pool(&self, name: &str, hosts: &[Host], handle: Handle) {
+        capnp::publish_add_pool(name, hosts, handle, self.inner.borrow().subscribers.clone())
     }
 
-    /// Ask all workers to add a new server to their pool
-    pub fn publish_new_server(&self, url: Uri, handle: Handle) {
-        capnp::publish_new_server(url, handle, self.inner.borrow().subscribers.clone())
+    /// Ask all workers to remove a named pool
+    pub fn publish_remove_pool(&self, name: &str, handle: Handle) {
+        capnp::publish_remove_pool(name, handle, self.inner.borrow().subscribers.clone())
     }
 
-    /// Ask all workers to mark a server down in their pool
-    pub fn publish_server_state_down(&self, url: &Uri, handle: Handle) {
-        capnp::publish_server_state_down(url, handle, self.inner.borrow().subscribers.clone())
+    /// Ask all workers to replace their routes
+    pub fn publish_routes(&self, routes: &[Route], handle: Handle) {
+        capnp::publish_routes(routes, handle, self.inner.borrow().subscribers.clone())
     }
 
-    /// Ask all workers to mark a server active in their pool
-    pub fn publish_server_state_active(&self, url: &Uri, handle: Handle) {
-        capnp::publish_server_state_active(url, handle, self.inner.borrow().subscribers.clone())
+    /// Ask all workers to add a certificate to the TLS listener, or replace the certificate with
+    /// the same name
+    pub fn publish_certificate(&self, name: &str, pem: &Pem, handle: Handle) {
+        capnp::publish_certificate(name, pem, handle, self.inner.borrow().subscribers.clone())
     }
 }
 
@@ -91,7 +284,9 @@ fn start_worker(id: u64) -> io::Result<Worker> {
         ForkResult::Child => {
             trace!("I am a new child");
 
+            // the worker is started with the same options as the manager
             Command::new(path.to_str().unwrap())
+                .args(::std::env::args().skip(1))
                 .arg("worker")
                 .arg("--id")
                 .arg(id.to_string())
@@ -108,6 +303,8 @@ mod capnp {
     use std::net::SocketAddr;
     use std::rc::Rc;
     use std::fmt;
+    use std::str::FromStr;
+    use std::time::Duration;
 
     use weldr_capnp::{publisher, subscriber, subscription};
 
@@ -118,10 +315,38 @@ mod capnp {
     use capnp::Error;
 
     use tokio_io::AsyncRead;
-    use tokio_core::reactor::Handle;
+    use tokio_core::reactor::{Handle, Timeout};
 
     use hyper::Uri;
 
+    use circuit::CircuitState;
+    use config::{Timeouts, Upgrade};
+    use hash::HashKey;
+    use pool::Strategy;
+    use router::{Host, PathMatch, Route, Router, DEFAULT_POOL};
+    use sticky::StickySession;
+    use stats::Connections;
+    use tls::{Certificates, Pem};
+    use upstream::Protocol;
+    use super::{CircuitBreakers, ConnectionCounts, Drains};
+
+    /// The time (in milliseconds) to wait before sending a message to a busy worker again
+    const RETRY_DELAY: u64 = 100;
+
+    type Client = subscriber::Client<::capnp::data::Owned>;
+
+    /// Builds the request of a message to a worker and sends it
+    ///
+    /// The same message may be sent more than once, such as to a worker that was busy.
+    type Message = Rc<Fn(&Client) -> Box<Future<Item = (), Error = Error>>>;
+
+    fn message<F>(f: F) -> Message
+    where
+        F: Fn(&Client) -> Box<Future<Item = (), Error = Error>> + 'static,
+    {
+        Rc::new(f)
+    }
+
     struct SubscriberHandle {
         client: subscriber::Client<::capnp::data::Owned>,
         requests_in_flight: i32,
@@ -173,13 +398,33 @@ mod capnp {
     pub struct PublisherImpl {
         next_id: u64,
         subscribers: Rc<RefCell<SubscriberMap>>,
+        circuit_breakers: Rc<RefCell<CircuitBreakers>>,
+        drains: Rc<RefCell<Drains>>,
+        connections: Rc<RefCell<ConnectionCounts>>,
+        router: Router,
+        certificates: Option<Certificates>,
+        handle: Handle,
     }
 
     impl PublisherImpl {
-        pub fn new(subscribers: Rc<RefCell<SubscriberMap>>) -> PublisherImpl {
+        pub fn new(
+            subscribers: Rc<RefCell<SubscriberMap>>,
+            circuit_breakers: Rc<RefCell<CircuitBreakers>>,
+            drains: Rc<RefCell<Drains>>,
+            connections: Rc<RefCell<ConnectionCounts>>,
+            router: Router,
+            certificates: Option<Certificates>,
+            handle: Handle,
+        ) -> PublisherImpl {
             PublisherImpl {
                 next_id: 0,
                 subscribers: subscribers,
+                circuit_breakers: circuit_breakers,
+                drains: drains,
+                connections: connections,
+                router: router,
+                certificates: certificates,
+                handle: handle,
             }
         }
     }
@@ -191,13 +436,22 @@ mod capnp {
             mut results: publisher::SubscribeResults<::capnp::data::Owned>,
         ) -> Promise<(), ::capnp::Error> {
             info!("subscribe");
-            self.subscribers.borrow_mut().subscribers.insert(
-                self.next_id,
-                SubscriberHandle {
-                    client: pry!(pry!(params.get()).get_subscriber()),
-                    requests_in_flight: 0,
-                },
-            );
+            let mut subscriber = SubscriberHandle {
+                client: pry!(pry!(params.get()).get_subscriber()),
+                requests_in_flight: 0,
+            };
+
+            // the changes are sent in order, regardless of how many are in flight
+            for message in sync_messages(&self.router, &self.certificates) {
+                send(
+                    self.next_id,
+                    &mut subscriber,
+                    &message,
+                    &self.handle,
+                    self.subscribers.clone(),
+                );
+            }
+            self.subscribers.borrow_mut().subscribers.insert(self.next_id, subscriber);
 
             results.get().set_subscription(
                 subscription::ToClient::new(SubscriptionImpl::new(
@@ -209,12 +463,127 @@ mod capnp {
             self.next_id += 1;
             Promise::ok(())
         }
+
+        fn circuit_breaker_changed(
+            &mut self,
+            params: publisher::CircuitBreakerChangedParams<::capnp::data::Owned>,
+            _results: publisher::CircuitBreakerChangedResults<::capnp::data::Owned>,
+        ) -> Promise<(), ::capnp::Error> {
+            trace!("circuit_breaker_changed");
+
+            let params = pry!(params.get());
+            let worker = params.get_worker();
+            let url = pry!(params.get_url());
+            let state = pry!(params.get_state());
+            let pool = pry!(params.get_pool());
+            info!(
+                "circuit breaker of {:?} in pool {:?} is {:?} in worker {}",
+                url,
+                pool,
+                state,
+                worker
+            );
+
+            match CircuitState::from_str(state) {
+                Ok(state) => {
+                    self.circuit_breakers
+                        .borrow_mut()
+                        .entry((pool.to_string(), url.to_string()))
+                        .or_insert_with(Default::default)
+                        .insert(worker, state);
+                }
+                Err(e) => {
+                    error!("Unable to record circuit breaker state: {}", e);
+                }
+            }
+
+            Promise::ok(())
+        }
+
+        fn server_draining(
+            &mut self,
+            params: publisher::ServerDrainingParams<::capnp::data::Owned>,
+            _results: publisher::ServerDrainingResults<::capnp::data::Owned>,
+        ) -> Promise<(), ::capnp::Error> {
+            trace!("server_draining");
+
+            let params = pry!(params.get());
+            let worker = params.get_worker();
+            let url = pry!(params.get_url());
+            let in_flight = params.get_in_flight();
+            let pool = pry!(params.get_pool());
+            info!(
+                "draining {:?} in pool {:?} has {} requests in flight in worker {}",
+                url,
+                pool,
+                in_flight,
+                worker
+            );
+
+            self.drains
+                .borrow_mut()
+                .entry((pool.to_string(), url.to_string()))
+                .or_insert_with(Default::default)
+                .insert(worker, in_flight);
+
+            Promise::ok(())
+        }
+
+        fn connections_report(
+            &mut self,
+            params: publisher::ConnectionsReportParams<::capnp::data::Owned>,
+            _results: publisher::ConnectionsReportResults<::capnp::data::Owned>,
+        ) -> Promise<(), ::capnp::Error> {
+            trace!("connections_report");
+
+            let params = pry!(params.get());
+            let worker = params.get_worker();
+            let url = pry!(params.get_url());
+            let connections = Connections {
+                open: params.get_open(),
+                opened: params.get_opened(),
+                requests: params.get_requests(),
+            };
+            let pool = pry!(params.get_pool());
+            debug!(
+                "{:?} in pool {:?} has connections {:?} in worker {}",
+                url,
+                pool,
+                connections,
+                worker
+            );
+
+            self.connections
+                .borrow_mut()
+                .entry((pool.to_string(), url.to_string()))
+                .or_insert_with(Default::default)
+                .insert(worker, connections);
+
+            Promise::ok(())
+        }
     }
 
-    pub fn listen(addr: SocketAddr, handle: Handle, subscribers: Rc<RefCell<SubscriberMap>>) {
+    pub fn listen(
+        addr: SocketAddr,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+        circuit_breakers: Rc<RefCell<CircuitBreakers>>,
+        drains: Rc<RefCell<Drains>>,
+        connections: Rc<RefCell<ConnectionCounts>>,
+        router: Router,
+        certificates: Option<Certificates>,
+    ) {
         let socket = ::tokio_core::net::TcpListener::bind(&addr, &handle).unwrap();
 
-        let publisher_impl = PublisherImpl::new(subscribers);
+        let publisher_impl = PublisherImpl::new(
+            subscribers,
+            circuit_breakers,
+            drains,
+            connections,
+            router,
+            certificates,
+            handle.clone(),
+        );
 
         let publisher = publisher::ToClient::new(publisher_impl)
             .from_server::<::capnp_rpc::Server>();
@@ -244,131 +613,452 @@ mod capnp {
         handle.spawn(done);
     }
 
-    pub fn publish_new_server(url: Uri, handle: Handle, subscribers: Rc<RefCell<SubscriberMap>>) {
+    pub fn publish_new_server(
+        pool: &str,
+        url: Uri,
+        weight: u32,
+        backup: bool,
+        timeouts: Option<Timeouts>,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
         trace!("publish_new_server");
 
-        let subscribers1 = subscribers.clone();
-        let subs = &mut subscribers.borrow_mut().subscribers;
-        for (&idx, mut subscriber) in subs.iter_mut() {
-            if subscriber.requests_in_flight < 5 {
-                subscriber.requests_in_flight += 1;
-
-                let mut request = subscriber.client.add_server_request();
-
-                request.get().set_url(&format!("{}", &url));
-
-                let subscribers2 = subscribers1.clone();
-                handle.spawn(
-                    request
-                        .send()
-                        .promise
-                        .then(move |r| {
-                            match r {
-                                Ok(_) => {
-                                    subscribers2
-                                        .borrow_mut()
-                                        .subscribers
-                                        .get_mut(&idx)
-                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
-                                }
-                                Err(e) => {
-                                    error!("Got error: {:?}. Dropping subscriber.", e);
-                                    subscribers2.borrow_mut().subscribers.remove(&idx);
-                                }
-                            }
-                            Ok::<(), Error>(())
-                        })
-                        .map_err(|_| unreachable!()),
-                );
+        let pool = pool.to_string();
+        let message = message(move |client| {
+            let mut request = client.add_server_request();
+
+            request.get().set_url(&format!("{}", &url));
+            request.get().set_weight(weight);
+            request.get().set_backup(backup);
+            request.get().set_pool(&pool);
+            if let Some(ref timeouts) = timeouts {
+                let mut t = request.get().init_timeouts();
+                t.set_connect(timeouts.connect);
+                t.set_first_byte(timeouts.first_byte);
+                t.set_idle(timeouts.idle);
+                t.set_request(timeouts.request);
             }
-        }
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
     }
 
     pub fn publish_server_state_down(
+        pool: &str,
         url: &Uri,
         handle: Handle,
         subscribers: Rc<RefCell<SubscriberMap>>,
     ) {
         trace!("publish_server_state_down");
 
-        let subscribers1 = subscribers.clone();
-        let subs = &mut subscribers.borrow_mut().subscribers;
-        for (&idx, mut subscriber) in subs.iter_mut() {
-            if subscriber.requests_in_flight < 5 {
-                subscriber.requests_in_flight += 1;
-
-                let mut request = subscriber.client.mark_server_down_request();
-
-                request.get().set_url(&format!("{}", &url));
-
-                let subscribers2 = subscribers1.clone();
-                handle.spawn(
-                    request
-                        .send()
-                        .promise
-                        .then(move |r| {
-                            match r {
-                                Ok(_) => {
-                                    subscribers2
-                                        .borrow_mut()
-                                        .subscribers
-                                        .get_mut(&idx)
-                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
-                                }
-                                Err(e) => {
-                                    error!("Got error: {:?}. Dropping subscriber.", e);
-                                    subscribers2.borrow_mut().subscribers.remove(&idx);
-                                }
-                            }
-                            Ok::<(), Error>(())
-                        })
-                        .map_err(|_| unreachable!()),
-                );
-            }
-        }
+        let pool = pool.to_string();
+        let url = url.to_string();
+        let message = message(move |client| {
+            let mut request = client.mark_server_down_request();
+
+            request.get().set_url(&url);
+            request.get().set_pool(&pool);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
     }
 
     pub fn publish_server_state_active(
+        pool: &str,
         url: &Uri,
         handle: Handle,
         subscribers: Rc<RefCell<SubscriberMap>>,
     ) {
         trace!("publish_server_state_active");
 
-        let subscribers1 = subscribers.clone();
-        let subs = &mut subscribers.borrow_mut().subscribers;
-        for (&idx, mut subscriber) in subs.iter_mut() {
-            if subscriber.requests_in_flight < 5 {
-                subscriber.requests_in_flight += 1;
-
-                let mut request = subscriber.client.mark_server_active_request();
-
-                request.get().set_url(&format!("{}", &url));
-
-                let subscribers2 = subscribers1.clone();
-                handle.spawn(
-                    request
-                        .send()
-                        .promise
-                        .then(move |r| {
-                            match r {
-                                Ok(_) => {
-                                    subscribers2
-                                        .borrow_mut()
-                                        .subscribers
-                                        .get_mut(&idx)
-                                        .map(|ref mut s| { s.requests_in_flight -= 1; });
-                                }
-                                Err(e) => {
-                                    error!("Got error: {:?}. Dropping subscriber.", e);
-                                    subscribers2.borrow_mut().subscribers.remove(&idx);
-                                }
-                            }
-                            Ok::<(), Error>(())
-                        })
-                        .map_err(|_| unreachable!()),
-                );
+        let pool = pool.to_string();
+        let url = url.to_string();
+        let message = message(move |client| {
+            let mut request = client.mark_server_active_request();
+
+            request.get().set_url(&url);
+            request.get().set_pool(&pool);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
+    }
+
+    pub fn publish_strategy(
+        pool: &str,
+        strategy: Strategy,
+        hash_key: &HashKey,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_strategy");
+
+        let pool = pool.to_string();
+        let hash_key = hash_key.to_string();
+        let message = message(move |client| {
+            let mut request = client.set_strategy_request();
+
+            request.get().set_strategy(strategy.as_str());
+            request.get().set_hash_key(&hash_key);
+            request.get().set_pool(&pool);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
+    }
+
+    pub fn publish_sticky_session(
+        pool: &str,
+        sticky: Option<&StickySession>,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_sticky_session");
+
+        let pool = pool.to_string();
+        let sticky = sticky.cloned();
+        let message = message(move |client| {
+            let mut request = client.set_sticky_session_request();
+
+            match sticky {
+                Some(ref sticky) => {
+                    request.get().set_enabled(true);
+                    request.get().set_cookie(sticky.cookie());
+                    request.get().set_secret(sticky.secret());
+                }
+                None => {
+                    request.get().set_enabled(false);
+                }
+            }
+            request.get().set_pool(&pool);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
+    }
+
+    pub fn publish_upgrade(
+        pool: &str,
+        upgrade: &Upgrade,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_upgrade");
+
+        let pool = pool.to_string();
+        let upgrade = upgrade.clone();
+        let message = message(move |client| {
+            let mut request = client.set_upgrade_request();
+
+            request.get().set_enabled(upgrade.enabled);
+            request.get().set_idle_timeout(upgrade.idle_timeout);
+            request.get().set_pool(&pool);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
+    }
+
+    pub fn publish_protocol(
+        pool: &str,
+        protocol: Protocol,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_protocol");
+
+        let pool = pool.to_string();
+        let message = message(move |client| {
+            let mut request = client.set_protocol_request();
+
+            request.get().set_protocol(protocol.as_str());
+            request.get().set_pool(&pool);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
+    }
+
+    pub fn publish_drain_server(
+        pool: &str,
+        url: &Uri,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_drain_server");
+
+        let pool = pool.to_string();
+        let url = url.to_string();
+        let message = message(move |client| {
+            let mut request = client.drain_server_request();
+
+            request.get().set_url(&url);
+            request.get().set_pool(&pool);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
+    }
+
+    /// Send a message to every subscriber
+    fn publish_all(message: Message, handle: Handle, subscribers: Rc<RefCell<SubscriberMap>>) {
+        let idxs = subscribers.borrow().subscribers.keys().cloned().collect();
+        publish(idxs, message, handle, subscribers);
+    }
+
+    /// Send a message to the subscribers with these ids
+    ///
+    /// A message is not dropped for a subscriber that is busy, so that no worker misses a change.
+    /// The busy subscribers are sent the message again after a short delay instead.
+    fn publish(
+        idxs: Vec<u64>,
+        message: Message,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        let mut busy = Vec::new();
+        {
+            let subscribers1 = subscribers.clone();
+            let subs = &mut subscribers.borrow_mut().subscribers;
+            for idx in idxs {
+                // a subscriber that has since been dropped is not sent the message again
+                let subscriber = match subs.get_mut(&idx) {
+                    Some(subscriber) => subscriber,
+                    None => continue,
+                };
+
+                if subscriber.requests_in_flight >= 5 {
+                    busy.push(idx);
+                    continue;
+                }
+                send(idx, subscriber, &message, &handle, subscribers1.clone());
             }
         }
+
+        if busy.is_empty() {
+            return;
+        }
+
+        let delay = match Timeout::new(Duration::from_millis(RETRY_DELAY), &handle) {
+            Ok(delay) => delay,
+            Err(e) => {
+                error!("Unable to send a message to busy workers again: {:?}", e);
+                return;
+            }
+        };
+
+        let handle1 = handle.clone();
+        handle.spawn(delay.then(move |_| {
+            publish(busy, message, handle1, subscribers);
+            Ok(())
+        }));
+    }
+
+    /// Send a message to a subscriber, which is dropped if the message fails
+    fn send(
+        idx: u64,
+        subscriber: &mut SubscriberHandle,
+        message: &Message,
+        handle: &Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        subscriber.requests_in_flight += 1;
+
+        handle.spawn(
+            message(&subscriber.client)
+                .then(move |r| {
+                    match r {
+                        Ok(_) => {
+                            subscribers
+                                .borrow_mut()
+                                .subscribers
+                                .get_mut(&idx)
+                                .map(|ref mut s| { s.requests_in_flight -= 1; });
+                        }
+                        Err(e) => {
+                            error!("Got error: {:?}. Dropping subscriber.", e);
+                            subscribers.borrow_mut().subscribers.remove(&idx);
+                        }
+                    }
+                    Ok::<(), Error>(())
+                })
+                .map_err(|_| unreachable!()),
+        );
+    }
+
+    /// The messages that bring a new subscriber up to date with the pools, routes and
+    /// certificates added since the workers started
+    ///
+    /// A worker that restarts, or subscribes after a change, would otherwise route with only its
+    /// configuration until the next change of each.
+    fn sync_messages(router: &Router, certificates: &Option<Certificates>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for name in router.pools().keys() {
+            if name != DEFAULT_POOL {
+                messages.push(add_pool_message(name, &router.hosts(name)));
+            }
+        }
+        messages.push(routes_message(&router.routes()));
+        if let Some(ref certificates) = *certificates {
+            for (name, pem) in certificates.pems() {
+                messages.push(certificate_message(&name, &pem));
+            }
+        }
+        messages
+    }
+
+    pub fn publish_add_pool(
+        name: &str,
+        hosts: &[Host],
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_add_pool");
+
+        publish_all(add_pool_message(name, hosts), handle, subscribers);
+    }
+
+    fn add_pool_message(name: &str, hosts: &[Host]) -> Message {
+        let name = name.to_string();
+        let hosts: Vec<String> = hosts.iter().map(|host| host.to_string()).collect();
+        message(move |client| {
+            let mut request = client.add_pool_request();
+
+            request.get().set_name(&name);
+            {
+                let mut list = request.get().init_hosts(hosts.len() as u32);
+                for (i, host) in hosts.iter().enumerate() {
+                    list.set(i as u32, host);
+                }
+            }
+
+            Box::new(request.send().promise.map(|_| ()))
+        })
+    }
+
+    pub fn publish_remove_pool(
+        name: &str,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_remove_pool");
+
+        let name = name.to_string();
+        let message = message(move |client| {
+            let mut request = client.remove_pool_request();
+
+            request.get().set_name(&name);
+
+            Box::new(request.send().promise.map(|_| ()))
+        });
+
+        publish_all(message, handle, subscribers);
+    }
+
+    pub fn publish_routes(
+        routes: &[Route],
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_routes");
+
+        publish_all(routes_message(routes), handle, subscribers);
+    }
+
+    fn routes_message(routes: &[Route]) -> Message {
+        let routes = routes.to_vec();
+        message(move |client| {
+            let mut request = client.set_routes_request();
+
+            {
+                let mut list = request.get().init_routes(routes.len() as u32);
+                for (i, route) in routes.iter().enumerate() {
+                    let mut r = list.borrow().get(i as u32);
+                    r.set_pool(&route.pool);
+                    match route.path {
+                        PathMatch::Prefix(ref prefix) => r.set_prefix(prefix),
+                        PathMatch::Regex(ref re) => r.set_regex(re.as_str()),
+                    }
+                    if let Some(ref rewrite) = route.rewrite {
+                        r.set_rewrite(rewrite);
+                    }
+                    {
+                        let mut methods = r.borrow().init_methods(route.methods.len() as u32);
+                        for (j, method) in route.methods.iter().enumerate() {
+                            methods.set(j as u32, method.as_ref());
+                        }
+                    }
+                    {
+                        let mut headers = r.borrow().init_headers(route.headers.len() as u32);
+                        for (j, p) in route.headers.iter().enumerate() {
+                            let mut h = headers.borrow().get(j as u32);
+                            h.set_name(&p.name);
+                            h.set_value(p.value.as_str());
+                        }
+                    }
+                    {
+                        let mut query = r.borrow().init_query(route.query.len() as u32);
+                        for (j, p) in route.query.iter().enumerate() {
+                            let mut q = query.borrow().get(j as u32);
+                            q.set_name(&p.name);
+                            q.set_value(p.value.as_str());
+                        }
+                    }
+                    if let Some(ref split) = route.split {
+                        let mut s = r.borrow().init_split();
+                        s.set_pool(&split.pool);
+                        s.set_percent(split.percent);
+                        if let Some(ref key) = split.key {
+                            s.set_key(&key.to_string());
+                        }
+                    }
+                    if let Some(ref mirror) = route.mirror {
+                        let mut m = r.init_mirror();
+                        m.set_pool(&mirror.pool);
+                        m.set_percent(mirror.percent);
+                        m.set_max_in_flight(mirror.max_in_flight);
+                        m.set_max_body(mirror.max_body);
+                    }
+                }
+            }
+
+            Box::new(request.send().promise.map(|_| ()))
+        })
+    }
+
+    pub fn publish_certificate(
+        name: &str,
+        pem: &Pem,
+        handle: Handle,
+        subscribers: Rc<RefCell<SubscriberMap>>,
+    ) {
+        trace!("publish_certificate");
+
+        publish_all(certificate_message(name, pem), handle, subscribers);
+    }
+
+    fn certificate_message(name: &str, pem: &Pem) -> Message {
+        let name = name.to_string();
+        let pem = pem.clone();
+        message(move |client| {
+            let mut request = client.set_certificate_request();
+
+            request.get().set_name(&name);
+            request.get().set_cert(&pem.cert);
+            request.get().set_key(&pem.key);
+
+            Box::new(request.send().promise.map(|_| ()))
+        })
     }
 }
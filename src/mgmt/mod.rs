@@ -10,24 +10,28 @@ use tokio_timer::Timer;
 use hyper::server::Http;
 
 use pool::Pool;
+use router::Router;
 use self::api::Mgmt;
 use self::manager::Manager;
 use self::health::BackendHealth;
 use config::Config;
+use tls::Certificates;
 
 pub mod api;
 pub mod health;
 pub mod manager;
+pub mod watch;
 pub mod worker;
 
 /// Run manager server and start health check timer
 pub fn run(
     sock: SocketAddr,
-    pool: Pool,
+    router: Router,
     mut core: Core,
     manager: Manager,
     conf: &Config,
     health: BackendHealth,
+    certificates: Option<Certificates>,
 ) -> io::Result<()> {
     let handle = core.handle();
     let listener = TcpListener::bind(&sock, &handle)?;
@@ -36,6 +40,10 @@ pub fn run(
         .interval(Duration::from_secs(conf.health_check.interval))
         .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
 
+    if let Some(ref certificates) = certificates {
+        watch::run(&conf.tls, certificates.clone(), manager.clone(), &timer, &handle);
+    }
+
     let admin_addr = listener.local_addr()?;
     let listener = listener.incoming().merge(health_timer);
     let srv = listener.for_each(move |stream| {
@@ -44,15 +52,15 @@ pub fn run(
         // second stream is health interval
         match stream {
             MergedItem::First((socket, addr)) => {
-                mgmt(socket, addr, pool.clone(), &handle, manager.clone());
+                mgmt(socket, addr, router.clone(), &handle, manager.clone(), certificates.clone());
             }
             MergedItem::Second(()) => {
-                health::run(pool.clone(), &handle, &conf, manager.clone(), health.clone());
+                check_pools(&router, &handle, &conf, &manager, &health);
             }
             MergedItem::Both((socket, addr), ()) => {
-                mgmt(socket, addr, pool.clone(), &handle, manager.clone());
+                mgmt(socket, addr, router.clone(), &handle, manager.clone(), certificates.clone());
                 info!("health check");
-                health::run(pool.clone(), &handle, &conf, manager.clone(), health.clone());
+                check_pools(&router, &handle, &conf, &manager, &health);
             }
         }
 
@@ -63,8 +71,42 @@ pub fn run(
     core.run(srv)
 }
 
-fn mgmt(socket: TcpStream, addr: SocketAddr, pool: Pool, handle: &Handle, manager: Manager) {
-    let service = Mgmt::new(pool, handle.clone(), manager);
+/// Check the health of the servers in every pool and remove the servers that are drained
+fn check_pools(
+    router: &Router,
+    handle: &Handle,
+    conf: &Config,
+    manager: &Manager,
+    health: &BackendHealth,
+) {
+    for (name, pool) in router.pools() {
+        health::run(&name, pool.clone(), handle, conf, manager.clone(), health.clone());
+        remove_drained(&name, &pool, manager, health);
+    }
+}
+
+/// Remove the draining servers that every worker has finished draining
+fn remove_drained(name: &str, pool: &Pool, manager: &Manager, health: &BackendHealth) {
+    for backend in pool.all() {
+        let url = backend.server().url();
+        if backend.is_draining() && manager.is_drained(name, &url) {
+            info!("Removing drained {:?} from pool", backend);
+            pool.remove(&backend.server());
+            health.remove(&backend);
+            manager.forget_drain(name, &url);
+        }
+    }
+}
+
+fn mgmt(
+    socket: TcpStream,
+    addr: SocketAddr,
+    router: Router,
+    handle: &Handle,
+    manager: Manager,
+    certificates: Option<Certificates>,
+) {
+    let service = Mgmt::new(router, handle.clone(), manager, certificates);
     let http = Http::new();
     http.bind_connection(&handle, socket, addr, service);
 }
@@ -0,0 +1,121 @@
+//! Reloads the certificates of the TLS listener when their files change on disk
+//!
+//! The files are checked on an interval. A certificate is reloaded once its certificate or key file
+//! changes, and is then sent to the workers. A certificate that fails to load is left as it was
+//! until its files change again, so that a certificate written before its key is picked up once
+//! the key is written too.
+
+use std::cmp;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use futures::Stream;
+use tokio_core::reactor::Handle;
+use tokio_timer::Timer;
+
+use config::{Certificate, Tls};
+use mgmt::Manager;
+use tls::{Certificates, Pem};
+
+/// The files of a certificate and when they last changed
+struct Watched {
+    certificate: Certificate,
+    modified: Option<SystemTime>,
+}
+
+/// Start watching the certificate files of the configuration
+pub fn run(
+    conf: &Tls,
+    certificates: Certificates,
+    manager: Manager,
+    timer: &Timer,
+    handle: &Handle,
+) {
+    if conf.watch_interval == 0 {
+        return;
+    }
+
+    let mut watched: Vec<Watched> = conf.certificates
+        .iter()
+        .map(|certificate| {
+            Watched {
+                certificate: certificate.clone(),
+                modified: modified(certificate),
+            }
+        })
+        .collect();
+
+    let handle1 = handle.clone();
+    let work = timer
+        .interval(Duration::from_secs(conf.watch_interval))
+        .map_err(|e| {
+            error!("Certificate watch timer error {:?}", e);
+        })
+        .for_each(move |()| {
+            for w in watched.iter_mut() {
+                let modified = modified(&w.certificate);
+                if modified != w.modified {
+                    w.modified = modified;
+                    reload(&w.certificate, &certificates, &manager, &handle1);
+                }
+            }
+
+            Ok(())
+        });
+
+    handle.spawn(work);
+}
+
+/// Returns when the certificate or key file last changed
+fn modified(certificate: &Certificate) -> Option<SystemTime> {
+    let cert = fs::metadata(&certificate.cert).and_then(|m| m.modified()).ok();
+    let key = fs::metadata(&certificate.key).and_then(|m| m.modified()).ok();
+    cmp::max(cert, key)
+}
+
+/// Load the files of a certificate and send it to the workers
+fn reload(
+    certificate: &Certificate,
+    certificates: &Certificates,
+    manager: &Manager,
+    handle: &Handle,
+) {
+    let name = &certificate.name;
+    let loaded = Pem::load(certificate).and_then(|pem| {
+        certificates.set(name, pem.clone()).map(|()| pem)
+    });
+
+    match loaded {
+        Ok(pem) => {
+            info!("Reloaded certificate {:?} from {}", name, certificate.cert);
+            manager.publish_certificate(name, &pem, handle.clone());
+        }
+        Err(e) => warn!("Unable to reload certificate {:?}: {}", name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::modified;
+    use config::Certificate;
+    use std::env;
+    use std::fs::{self, File};
+
+    #[test]
+    fn test_modified() {
+        let dir = env::temp_dir().join(format!("weldr-watch-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let certificate = Certificate {
+            name: "example".to_string(),
+            cert: dir.join("example.pem").to_str().unwrap().to_string(),
+            key: dir.join("example.key").to_str().unwrap().to_string(),
+        };
+
+        assert_eq!(None, modified(&certificate));
+
+        File::create(&certificate.key).unwrap();
+        assert!(modified(&certificate).is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,30 +2,59 @@ use std::net::SocketAddr;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::str::FromStr;
+use std::time::Duration;
 
-use weldr_capnp::{publisher, subscriber};
+use weldr_capnp::{predicate, publisher, route, subscriber};
 
-use futures::Future;
+use futures::{future, Future, Stream};
 
 use capnp_rpc::{RpcSystem, twoparty, rpc_twoparty_capnp};
 use capnp::capability::{Response, Promise};
+use capnp::struct_list;
 
-use hyper::Uri;
+use hyper::{Method, Uri};
 
 use tokio_io::AsyncRead;
 use tokio_core::reactor::Handle;
 use tokio_core::net::TcpStream;
 
+use config::{Timeouts, Upgrade};
 use server::Server;
-use pool::Pool;
+use hash::HashKey;
+use pool::{Event, Pool, Strategy};
+use router::{Host, Mirror, PathMatch, Predicate, Route, Router, Split, DEFAULT_POOL};
+use sticky::StickySession;
+use timeout;
+use tls::{Certificates, Pem};
+use upstream::Protocol;
+
+/// The time between two reports of the connections to each backend
+const CONNECTIONS_REPORT_INTERVAL: u64 = 10;
 
 struct SubscriberImpl {
-    pool: Pool,
+    router: Router,
+
+    /// The certificates of the TLS listener, if it is enabled
+    certificates: Option<Certificates>,
 }
 
 impl SubscriberImpl {
-    pub fn new(pool: Pool) -> SubscriberImpl {
-        SubscriberImpl { pool: pool }
+    pub fn new(router: Router, certificates: Option<Certificates>) -> SubscriberImpl {
+        SubscriberImpl {
+            router: router,
+            certificates: certificates,
+        }
+    }
+
+    /// Find a pool by the name sent by the publisher, where an empty name is the default pool
+    fn pool(&self, name: &str) -> Option<Pool> {
+        let name = if name.is_empty() { DEFAULT_POOL } else { name };
+        let pool = self.router.pool(name);
+        if pool.is_none() {
+            error!("Unable to find pool {:?}", name);
+        }
+
+        pool
     }
 }
 
@@ -37,12 +66,41 @@ impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
     ) -> Promise<(), ::capnp::Error> {
         trace!("add_server");
 
-        let url_str = pry!(pry!(params.get()).get_url());
-        info!("url from publisher: {:?}", url_str);
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
+        let weight = params.get_weight();
+        let backup = params.get_backup();
+        info!(
+            "url from publisher: {:?} with weight {} backup {}",
+            url_str,
+            weight,
+            backup
+        );
+
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
 
         let url = Uri::from_str(url_str).expect("Failed to parse server uri");
         let server = Server::new(url, true);
-        self.pool.add(server);
+        if backup {
+            pool.add_backup(server.clone(), weight);
+        } else {
+            pool.add_with_weight(server.clone(), weight);
+        }
+
+        if params.has_timeouts() {
+            let t = pry!(params.get_timeouts());
+            let timeouts = Timeouts {
+                connect: t.get_connect(),
+                first_byte: t.get_first_byte(),
+                idle: t.get_idle(),
+                request: t.get_request(),
+            };
+            debug!("timeouts from publisher: {:?}", timeouts);
+            pool.set_server_timeouts(&server, timeouts);
+        }
 
         Promise::ok(())
     }
@@ -54,13 +112,19 @@ impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
     ) -> Promise<(), ::capnp::Error> {
         trace!("mark_server_down");
 
-        let url_str = pry!(pry!(params.get()).get_url());
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
         info!("url from publisher: {:?}", url_str);
 
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
+
         let url = Uri::from_str(url_str).expect("Failed to parse server uri");
 
         let server = Server::new(url, true);
-        match self.pool.find(&server) {
+        match pool.find(&server) {
             Some(backend) => {
                 backend.mark_down();
             }
@@ -79,13 +143,19 @@ impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
     ) -> Promise<(), ::capnp::Error> {
         trace!("mark_server_active");
 
-        let url_str = pry!(pry!(params.get()).get_url());
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
         info!("url from publisher: {:?}", url_str);
 
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
+
         let url = Uri::from_str(url_str).expect("Failed to parse server uri");
 
         let server = Server::new(url, true);
-        match self.pool.find(&server) {
+        match pool.find(&server) {
             Some(backend) => {
                 backend.mark_active();
             }
@@ -96,14 +166,333 @@ impl subscriber::Server<::capnp::data::Owned> for SubscriberImpl {
 
         Promise::ok(())
     }
+
+    fn set_strategy(
+        &mut self,
+        params: subscriber::SetStrategyParams<::capnp::data::Owned>,
+        _results: subscriber::SetStrategyResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_strategy");
+
+        let params = pry!(params.get());
+        let strategy_str = pry!(params.get_strategy());
+        let hash_key_str = pry!(params.get_hash_key());
+        info!(
+            "strategy from publisher: {:?} with hash key {:?}",
+            strategy_str,
+            hash_key_str
+        );
+
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
+
+        match HashKey::from_str(hash_key_str) {
+            Ok(hash_key) => {
+                pool.set_hash_key(hash_key);
+            }
+            Err(e) => {
+                error!("Unable to set pool hash key: {}", e);
+            }
+        }
+
+        match Strategy::from_str(strategy_str) {
+            Ok(strategy) => {
+                pool.set_strategy(strategy);
+            }
+            Err(e) => {
+                error!("Unable to set pool strategy: {}", e);
+            }
+        }
+
+        Promise::ok(())
+    }
+
+    fn set_sticky_session(
+        &mut self,
+        params: subscriber::SetStickySessionParams<::capnp::data::Owned>,
+        _results: subscriber::SetStickySessionResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_sticky_session");
+
+        let params = pry!(params.get());
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
+
+        if params.get_enabled() {
+            let cookie = pry!(params.get_cookie());
+            let secret = pry!(params.get_secret());
+            info!("sticky session cookie from publisher: {:?}", cookie);
+
+            let sticky = StickySession::new(cookie.to_string(), secret.to_string());
+            pool.set_sticky(Some(sticky));
+        } else {
+            info!("sticky sessions disabled by publisher");
+            pool.set_sticky(None);
+        }
+
+        Promise::ok(())
+    }
+
+    fn set_upgrade(
+        &mut self,
+        params: subscriber::SetUpgradeParams<::capnp::data::Owned>,
+        _results: subscriber::SetUpgradeResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_upgrade");
+
+        let params = pry!(params.get());
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
+
+        let upgrade = Upgrade {
+            enabled: params.get_enabled(),
+            idle_timeout: params.get_idle_timeout(),
+        };
+        info!("upgrade settings from publisher: {:?}", upgrade);
+        pool.set_upgrade(upgrade);
+
+        Promise::ok(())
+    }
+
+    fn set_protocol(
+        &mut self,
+        params: subscriber::SetProtocolParams<::capnp::data::Owned>,
+        _results: subscriber::SetProtocolResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_protocol");
+
+        let params = pry!(params.get());
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
+
+        match Protocol::from_str(pry!(params.get_protocol())) {
+            Ok(protocol) => {
+                info!("protocol from publisher: {}", protocol);
+                pool.set_protocol(protocol);
+            }
+            Err(e) => {
+                error!("Unable to set pool protocol: {}", e);
+            }
+        }
+
+        Promise::ok(())
+    }
+
+    fn set_certificate(
+        &mut self,
+        params: subscriber::SetCertificateParams<::capnp::data::Owned>,
+        _results: subscriber::SetCertificateResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_certificate");
+
+        let params = pry!(params.get());
+        let name = pry!(params.get_name());
+        let certificates = match self.certificates {
+            Some(ref certificates) => certificates,
+            None => {
+                warn!("Ignoring certificate {:?} without a TLS listener", name);
+                return Promise::ok(());
+            }
+        };
+
+        let pem = Pem {
+            cert: pry!(params.get_cert()).to_vec(),
+            key: pry!(params.get_key()).to_vec(),
+        };
+        match certificates.set(name, pem) {
+            Ok(()) => info!("certificate {:?} from publisher", name),
+            Err(e) => error!("Unable to set certificate {:?}: {}", name, e),
+        }
+
+        Promise::ok(())
+    }
+
+    fn drain_server(
+        &mut self,
+        params: subscriber::DrainServerParams<::capnp::data::Owned>,
+        _results: subscriber::DrainServerResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("drain_server");
+
+        let params = pry!(params.get());
+        let url_str = pry!(params.get_url());
+        info!("url from publisher: {:?}", url_str);
+
+        let pool = match self.pool(pry!(params.get_pool())) {
+            Some(pool) => pool,
+            None => return Promise::ok(()),
+        };
+
+        let url = Uri::from_str(url_str).expect("Failed to parse server uri");
+
+        let server = Server::new(url, true);
+        if !pool.drain(&server) {
+            error!("Unable to find server {:?} to drain", server);
+        }
+
+        Promise::ok(())
+    }
+
+    fn add_pool(
+        &mut self,
+        params: subscriber::AddPoolParams<::capnp::data::Owned>,
+        _results: subscriber::AddPoolResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("add_pool");
+
+        let params = pry!(params.get());
+        let name = pry!(params.get_name());
+        let list = pry!(params.get_hosts());
+        let mut hosts = Vec::new();
+        for i in 0..list.len() {
+            match Host::from_str(pry!(list.get(i))) {
+                Ok(host) => hosts.push(host),
+                Err(e) => error!("Unable to route host to pool {:?}: {}", name, e),
+            }
+        }
+        info!("pool from publisher: {:?} with hosts {:?}", name, hosts);
+
+        self.router.add_pool(name, hosts);
+
+        Promise::ok(())
+    }
+
+    fn remove_pool(
+        &mut self,
+        params: subscriber::RemovePoolParams<::capnp::data::Owned>,
+        _results: subscriber::RemovePoolResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("remove_pool");
+
+        let name = pry!(pry!(params.get()).get_name());
+        info!("pool from publisher: {:?}", name);
+
+        if !self.router.remove_pool(name) {
+            error!("Unable to find pool {:?} to remove", name);
+        }
+
+        Promise::ok(())
+    }
+
+    fn set_routes(
+        &mut self,
+        params: subscriber::SetRoutesParams<::capnp::data::Owned>,
+        _results: subscriber::SetRoutesResults<::capnp::data::Owned>,
+    ) -> Promise<(), ::capnp::Error> {
+        trace!("set_routes");
+
+        let list = pry!(pry!(params.get()).get_routes());
+        let mut routes = Vec::new();
+        for i in 0..list.len() {
+            match read_route(list.get(i)) {
+                Ok(route) => routes.push(route),
+                Err(e) => {
+                    // keep the current routes rather than route with only some of the new ones
+                    error!("Unable to set routes: {}", e);
+                    return Promise::ok(());
+                }
+            }
+        }
+        info!("routes from publisher: {:?}", routes);
+
+        self.router.set_routes(routes);
+
+        Promise::ok(())
+    }
+}
+
+/// Read a route sent by the publisher
+fn read_route(r: route::Reader) -> Result<Route, String> {
+    let mut route = Route::new(text(r.get_pool())?);
+    if r.has_regex() {
+        route.path = PathMatch::regex(text(r.get_regex())?)?;
+    } else if r.has_prefix() {
+        route.path = PathMatch::Prefix(text(r.get_prefix())?.to_string());
+    }
+    if r.has_rewrite() {
+        route.rewrite = Some(text(r.get_rewrite())?.to_string());
+    }
+
+    let methods = r.get_methods().map_err(|e| e.to_string())?;
+    for i in 0..methods.len() {
+        let method = Method::from_str(text(methods.get(i))?).map_err(|e| e.to_string())?;
+        route.methods.push(method);
+    }
+
+    route.headers = read_predicates(r.get_headers())?;
+    route.query = read_predicates(r.get_query())?;
+
+    if r.has_split() {
+        let s = r.get_split().map_err(|e| e.to_string())?;
+        let key = text(s.get_key())?;
+        route.split = Some(Split {
+            pool: text(s.get_pool())?.to_string(),
+            percent: s.get_percent(),
+            key: if key.is_empty() {
+                None
+            } else {
+                Some(HashKey::from_str(key)?)
+            },
+        });
+    }
+
+    if r.has_mirror() {
+        let m = r.get_mirror().map_err(|e| e.to_string())?;
+        route.mirror = Some(Mirror::new(
+            text(m.get_pool())?,
+            m.get_percent(),
+            m.get_max_in_flight(),
+            m.get_max_body(),
+        ));
+    }
+
+    Ok(route)
+}
+
+fn read_predicates(
+    list: ::capnp::Result<struct_list::Reader<predicate::Owned>>,
+) -> Result<Vec<Predicate>, String> {
+    let list = list.map_err(|e| e.to_string())?;
+    let mut predicates = Vec::new();
+    for i in 0..list.len() {
+        let p = list.get(i);
+        predicates.push(Predicate::new(text(p.get_name())?, text(p.get_value())?)?);
+    }
+
+    Ok(predicates)
+}
+
+fn text<'a>(t: ::capnp::Result<&'a str>) -> Result<&'a str, String> {
+    t.map_err(|e| e.to_string())
 }
 
 pub struct S {
     pub response: Option<Response<publisher::subscribe_results::Owned<::capnp::data::Owned>>>,
 }
 
-pub fn subscribe(addr: SocketAddr, handle: Handle, pool: Pool) -> Rc<RefCell<S>> {
+/// Subscribe to the manager for changes to the pools
+///
+/// Changes to the circuit breaker state of a backend and the progress of draining backends are
+/// reported back to the manager.
+pub fn subscribe(
+    addr: SocketAddr,
+    handle: Handle,
+    router: Router,
+    certificates: Option<Certificates>,
+    id: u64,
+) -> Rc<RefCell<S>> {
     let handle1 = handle.clone();
+    // named pools share the events of the default pool
+    let events = router.default_pool().events();
+    let router1 = router.clone();
 
     let s = S { response: None };
     let s = Rc::new(RefCell::new(s));
@@ -126,7 +515,7 @@ pub fn subscribe(addr: SocketAddr, handle: Handle, pool: Pool) -> Rc<RefCell<S>>
             let publisher: publisher::Client<::capnp::data::Owned> =
                 rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
 
-            let sub = subscriber::ToClient::new(SubscriberImpl::new(pool))
+            let sub = subscriber::ToClient::new(SubscriberImpl::new(router, certificates))
                 .from_server::<::capnp_rpc::Server>();
 
             let mut request = publisher.subscribe_request();
@@ -135,6 +524,70 @@ pub fn subscribe(addr: SocketAddr, handle: Handle, pool: Pool) -> Rc<RefCell<S>>
                 error!("Subscribe RPC System error {:?}", e);
             }));
 
+            let publisher1 = publisher.clone();
+            handle1.spawn(events.for_each(move |event| {
+                let promise: Box<Future<Item = (), Error = ::capnp::Error>> = match event {
+                    Event::CircuitBreaker(pool, server, state) => {
+                        let mut request = publisher1.circuit_breaker_changed_request();
+                        request.get().set_worker(id);
+                        request.get().set_url(&format!("{}", server.url()));
+                        request.get().set_state(state.as_str());
+                        request.get().set_pool(&pool);
+                        Box::new(request.send().promise.map(|_| ()))
+                    }
+                    Event::Draining(pool, server, in_flight) => {
+                        let mut request = publisher1.server_draining_request();
+                        request.get().set_worker(id);
+                        request.get().set_url(&format!("{}", server.url()));
+                        request.get().set_in_flight(in_flight as u64);
+                        request.get().set_pool(&pool);
+                        Box::new(request.send().promise.map(|_| ()))
+                    }
+                };
+
+                promise.then(|r| {
+                    if let Err(e) = r {
+                        error!("Pool event report error {:?}", e);
+                    }
+                    Ok(())
+                })
+            }));
+
+            let publisher2 = publisher.clone();
+            let reports = timeout::timer()
+                .interval(Duration::from_secs(CONNECTIONS_REPORT_INTERVAL))
+                .map_err(|e| {
+                    error!("Connections report timer error {:?}", e);
+                })
+                .for_each(move |()| {
+                    let reports: Vec<_> = router1
+                        .pools()
+                        .into_iter()
+                        .flat_map(|(name, pool)| {
+                            pool.all().into_iter().map(move |backend| (name.clone(), backend))
+                        })
+                        .map(|(pool, backend)| {
+                            let connections = backend.connections();
+                            let mut request = publisher2.connections_report_request();
+                            request.get().set_worker(id);
+                            request.get().set_url(&format!("{}", backend.server().url()));
+                            request.get().set_open(connections.open);
+                            request.get().set_opened(connections.opened);
+                            request.get().set_requests(connections.requests);
+                            request.get().set_pool(&pool);
+                            request.send().promise.map(|_| ())
+                        })
+                        .collect();
+
+                    future::join_all(reports).then(|r| {
+                        if let Err(e) = r {
+                            error!("Connections report error {:?}", e);
+                        }
+                        Ok(())
+                    })
+                });
+            handle1.spawn(reports);
+
             request.send().promise
         })
         .map_err(|e| {
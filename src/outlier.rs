@@ -0,0 +1,201 @@
+//! Outlier detection for a single backend
+//!
+//! Every response to live traffic is recorded. A backend that fails too many requests in a row,
+//! or fails too high a percentage of requests during an interval, is ejected from the pool for a
+//! cooldown period. The cooldown doubles each time the backend is ejected again soon after it
+//! returns.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use config::OutlierDetection;
+
+#[derive(Debug, Default)]
+pub struct Outlier {
+    consecutive_failures: u64,
+
+    /// Requests in the current failure rate interval
+    interval_start: Option<Instant>,
+    interval_success: u64,
+    interval_failure: u64,
+
+    /// The number of times the backend was ejected without a healthy period in between
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl Outlier {
+    pub fn new() -> Outlier {
+        Outlier::default()
+    }
+
+    /// Record the result of a request
+    ///
+    /// Returns true if the backend should be ejected.
+    pub fn record(&mut self, success: bool, now: Instant, conf: &OutlierDetection) -> bool {
+        let interval = Duration::from_secs(conf.interval);
+        let interval_over = match self.interval_start {
+            Some(start) => now.duration_since(start) >= interval,
+            None => true,
+        };
+        if interval_over {
+            self.interval_start = Some(now);
+            self.interval_success = 0;
+            self.interval_failure = 0;
+        }
+
+        if success {
+            self.consecutive_failures = 0;
+            self.interval_success += 1;
+
+            // forget about past ejections once the backend has been healthy for a while
+            let base = Duration::from_secs(conf.base_ejection_time);
+            if let Some(until) = self.ejected_until {
+                if now >= until + base {
+                    self.ejections = 0;
+                    self.ejected_until = None;
+                }
+            }
+
+            return false;
+        }
+
+        self.consecutive_failures += 1;
+        self.interval_failure += 1;
+
+        if self.is_ejected(now) {
+            return false;
+        }
+
+        if conf.consecutive_failures > 0 && self.consecutive_failures >= conf.consecutive_failures {
+            return true;
+        }
+
+        let requests = self.interval_success + self.interval_failure;
+        conf.failure_rate > 0 && requests >= conf.failure_rate_min_requests &&
+            self.interval_failure * 100 >= conf.failure_rate * requests
+    }
+
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        match self.ejected_until {
+            Some(until) => now < until,
+            None => false,
+        }
+    }
+
+    /// Eject the backend and return how long it is ejected for
+    pub fn eject(&mut self, now: Instant, conf: &OutlierDetection) -> Duration {
+        let multiplier = 1u64 << cmp::min(self.ejections, 16);
+        let secs = cmp::min(
+            conf.base_ejection_time.saturating_mul(multiplier),
+            conf.max_ejection_time,
+        );
+        let duration = Duration::from_secs(secs);
+
+        self.ejections += 1;
+        self.ejected_until = Some(now + duration);
+        self.consecutive_failures = 0;
+        self.interval_start = None;
+
+        duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outlier;
+    use config::OutlierDetection;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_disabled_by_default() {
+        let conf = OutlierDetection::default();
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert_eq!(false, outlier.record(false, now, &conf));
+        }
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let mut conf = OutlierDetection::default();
+        conf.consecutive_failures = 5;
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        for _ in 0..conf.consecutive_failures - 1 {
+            assert_eq!(false, outlier.record(false, now, &conf));
+        }
+
+        // a success resets the consecutive failures
+        assert_eq!(false, outlier.record(true, now, &conf));
+        for _ in 0..conf.consecutive_failures - 1 {
+            assert_eq!(false, outlier.record(false, now, &conf));
+        }
+        assert_eq!(true, outlier.record(false, now, &conf));
+    }
+
+    #[test]
+    fn test_failure_rate() {
+        let mut conf = OutlierDetection::default();
+        conf.failure_rate = 50;
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        // not enough requests to check the failure rate
+        for _ in 0..conf.failure_rate_min_requests / 2 - 1 {
+            assert_eq!(false, outlier.record(true, now, &conf));
+            assert_eq!(false, outlier.record(false, now, &conf));
+        }
+        assert_eq!(false, outlier.record(true, now, &conf));
+        assert_eq!(true, outlier.record(false, now, &conf));
+
+        // a new interval starts over
+        let later = now + Duration::from_secs(conf.interval);
+        assert_eq!(false, outlier.record(false, later, &conf));
+    }
+
+    #[test]
+    fn test_ejection_backoff() {
+        let conf = OutlierDetection::default();
+        let base = Duration::from_secs(conf.base_ejection_time);
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        assert_eq!(base, outlier.eject(now, &conf));
+        assert!(outlier.is_ejected(now));
+        assert!(!outlier.is_ejected(now + base));
+
+        // ejected again right after returning
+        let now = now + base;
+        assert_eq!(base * 2, outlier.eject(now, &conf));
+        let now = now + base * 2;
+        assert_eq!(base * 4, outlier.eject(now, &conf));
+
+        // capped at the max ejection time
+        for _ in 0..10 {
+            outlier.eject(now, &conf);
+        }
+        assert_eq!(
+            Duration::from_secs(conf.max_ejection_time),
+            outlier.eject(now, &conf)
+        );
+    }
+
+    #[test]
+    fn test_ejection_backoff_resets() {
+        let conf = OutlierDetection::default();
+        let base = Duration::from_secs(conf.base_ejection_time);
+        let mut outlier = Outlier::new();
+        let now = Instant::now();
+
+        outlier.eject(now, &conf);
+
+        // healthy for the base ejection time after returning
+        let now = now + base * 2;
+        assert_eq!(false, outlier.record(true, now, &conf));
+        assert_eq!(base, outlier.eject(now, &conf));
+    }
+}
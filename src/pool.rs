@@ -1,17 +1,112 @@
-use std::io;
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::net::SocketAddr;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use futures::Future;
+use futures::{Async, Future, Poll, Stream};
+use futures::unsync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use rand::{self, Rng};
 
-use hyper::{self, server};
+use hyper::{self, server, Body, Chunk, Uri};
+use tokio_timer::Timer;
 
+use circuit::{Circuit, CircuitState};
+use config::{CircuitBreaker, ConnectionPool, ErrorPages, OutlierDetection, Retry, Timeouts,
+             Upgrade};
+use error;
+use hash::{HashKey, Ring};
+use outlier::Outlier;
 use server::Server;
-use stats::Stats;
+use stats::{Connections, Stats};
+use sticky::StickySession;
+use timeout::{RequestTimer, TimedBody};
+use upstream::Protocol;
 
-/// A round-robin pool for servers
+/// The strategy used by a pool to select a backend for each request
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Strategy {
+    /// Cycle through the active backends in proportion to their weight
+    RoundRobin,
+
+    /// Pick an active backend at random in proportion to its weight
+    Random,
+
+    /// Pick the active backend with the fewest requests in flight relative to its weight
+    LeastOutstanding,
+
+    /// Pick two active backends at random and use the one with fewer requests in flight relative
+    /// to its weight
+    PowerOfTwoChoices,
+
+    /// Hash part of the request onto a ring of active backends, see `HashKey`
+    ///
+    /// Requests without the key fall back to round-robin.
+    ConsistentHash,
+
+    /// Pick two active backends at random and use the one with the lower cost. The cost is the
+    /// peak moving average of the response latency multiplied by the requests in flight.
+    PeakEwma,
+}
+
+impl Strategy {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Strategy::RoundRobin => "round-robin",
+            Strategy::Random => "random",
+            Strategy::LeastOutstanding => "least-outstanding",
+            Strategy::PowerOfTwoChoices => "power-of-two-choices",
+            Strategy::ConsistentHash => "consistent-hash",
+            Strategy::PeakEwma => "peak-ewma",
+        }
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::RoundRobin
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "least-outstanding" | "least-connections" => Ok(Strategy::LeastOutstanding),
+            "power-of-two-choices" => Ok(Strategy::PowerOfTwoChoices),
+            "consistent-hash" => Ok(Strategy::ConsistentHash),
+            "peak-ewma" => Ok(Strategy::PeakEwma),
+            _ => Err(format!("unknown strategy: {}", s)),
+        }
+    }
+}
+
+/// Hints taken from a request that the pool uses to select a backend
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Affinity {
+    /// The hash of the request when the pool uses consistent hashing
+    pub hash: Option<u64>,
+
+    /// The id of the backend named by a valid sticky session cookie
+    pub sticky: Option<u64>,
+}
+
+/// A pool of servers
 ///
-/// A simple pool that stores socket addresses and, for now, clones them out.
+/// A simple pool that stores socket addresses and, for now, clones them out. The backend used for
+/// a request is chosen by the pool `Strategy`.
 ///
 /// Inspired by https://github.com/NicolasLM/nucleon/blob/master/src/backend.rs
 // TODO can probably get rid of the Rc<RefCell<_>> part
@@ -21,38 +116,121 @@ pub struct Pool {
 }
 
 impl Pool {
+    /// Create an empty pool that selects backends using the given strategy
+    pub fn new(strategy: Strategy) -> Pool {
+        let pool = Pool::default();
+        pool.set_strategy(strategy);
+        pool
+    }
+
+    /// Create an empty pool with the same settings as this pool
+    ///
+    /// The new pool reports its changes to the same stream of events as this pool.
+    pub fn with_settings(&self) -> Pool {
+        let inner = self.inner.borrow();
+        let pool = Pool::new(inner.strategy);
+        {
+            let mut new = pool.inner.borrow_mut();
+            new.hash_key = inner.hash_key.clone();
+            new.sticky = inner.sticky.clone();
+            new.outlier_detection = inner.outlier_detection.clone();
+            new.circuit_breaker = inner.circuit_breaker.clone();
+            new.events = inner.events.clone();
+            new.retry = inner.retry.clone();
+            new.error_pages = inner.error_pages.clone();
+            new.timeouts = inner.timeouts.clone();
+            new.connection_pool = inner.connection_pool.clone();
+            new.upgrade = inner.upgrade.clone();
+            new.protocol = inner.protocol;
+            new.slow_start = inner.slow_start;
+        }
+        pool
+    }
+
+    /// Find the hints in a request used to select a backend
+    ///
+    /// The affinity is passed to `Pool::request`.
+    pub fn affinity(&self, req: &server::Request, addr: &SocketAddr) -> Affinity {
+        let inner = self.inner.borrow();
+
+        let hash = match inner.strategy {
+            Strategy::ConsistentHash => inner.hash_key.hash(req, addr),
+            _ => None,
+        };
+
+        let sticky = match inner.sticky {
+            Some(ref sticky) => sticky.backend_id(req.headers()),
+            None => None,
+        };
+
+        Affinity {
+            hash: hash,
+            sticky: sticky,
+        }
+    }
+
     /// Send a request to the pool
     ///
     /// The pool may be exhausted of eligible addresses to connect to and will return an error.
-    pub fn request<F>(&self, f: F) -> Box<Future<Item = server::Response, Error = hyper::Error>>
+    /// Servers in `exclude`, such as those already tried for this request, are not eligible.
+    ///
+    /// The backend is counted as having a request in flight until the response body has finished
+    /// streaming to the client.
+    pub fn request<F>(
+        &self,
+        affinity: Affinity,
+        exclude: &[Server],
+        timer: &Timer,
+        f: F,
+    ) -> Box<Future<Item = server::Response<InFlightBody>, Error = hyper::Error>>
     where
         F: FnOnce(&Server) -> Box<Future<Item = server::Response, Error = hyper::Error>>,
     {
-        match self.inner.borrow_mut().get() {
+        let backend = self.inner.borrow_mut().get(affinity, exclude);
+        match backend {
             Some(backend) => {
-                Box::new(f(&backend.server()).then(move |res| match res {
-                    Ok(res) => {
+                let mut in_flight = InFlight::new(self.clone(), backend.clone());
+                in_flight.probe = self.inner.borrow_mut().dispatch(&backend);
+                let timeouts = self.inner.borrow().timeouts(&backend);
+                let timer = RequestTimer::start(timer, &timeouts);
+                let start = Instant::now();
+                let pool = self.clone();
+                Box::new(timer.headers(f(&backend.server())).then(move |res| match res {
+                    Ok((res, timer)) => {
+                        backend.observe_latency(start.elapsed());
                         if res.status().is_server_error() {
                             backend.inc_failure();
+                            pool.inner.borrow_mut().record(&backend, false);
                         } else {
                             backend.inc_success();
+                            pool.inner.borrow_mut().record(&backend, true);
                         }
-                        ::futures::finished(res)
+                        ::futures::finished(in_flight.track(res, timer))
                     }
                     Err(e) => {
                         backend.inc_failure();
+                        pool.inner.borrow_mut().record(&backend, false);
                         ::futures::failed(e)
                     }
                 }))
             }
-            None => {
-                let e = io::Error::new(io::ErrorKind::Other, "Pool is exhausted of servers");
-                // TODO should this return a Bad Gateway error ?
-                Box::new(::futures::failed(hyper::Error::Io(e)))
-            }
+            None => Box::new(::futures::failed(error::exhausted())),
         }
     }
 
+    /// Returns true if a request can be sent to a backend that is not in `exclude`
+    pub fn has_backend(&self, exclude: &[Server]) -> bool {
+        self.inner.borrow().has_backend(exclude)
+    }
+
+    /// Count a request to a server as in flight until the returned guard is dropped
+    ///
+    /// This is for a connection that outlives its response, such as one that upgraded to another
+    /// protocol. Returns `None` if the server is no longer in the pool.
+    pub fn hold(&self, server: &Server) -> Option<InFlight> {
+        self.find(server).map(|backend| InFlight::new(self.clone(), backend))
+    }
+
     /// Returns all `Backend` from the pool
     pub fn all(&self) -> Vec<Backend> {
         self.inner.borrow().all()
@@ -63,7 +241,22 @@ impl Pool {
     /// If the server is already added, then it cannot be added again. The function will return
     /// false if the server already exists.
     pub fn add(&self, server: Server) -> bool {
-        self.inner.borrow_mut().add(Backend::new(server))
+        self.add_with_weight(server, DEFAULT_WEIGHT)
+    }
+
+    /// Add a new server to the pool that will receive a share of requests relative to its weight
+    ///
+    /// See `Pool::add`.
+    pub fn add_with_weight(&self, server: Server, weight: u32) -> bool {
+        self.inner.borrow_mut().add(Backend::with_weight(server, weight))
+    }
+
+    /// Add a new backup server to the pool
+    ///
+    /// Backup servers only receive requests when none of the other servers are available. See
+    /// `Pool::add`.
+    pub fn add_backup(&self, server: Server, weight: u32) -> bool {
+        self.inner.borrow_mut().add(Backend::new_backup(server, weight))
     }
 
     /// Remove a server in the pool
@@ -75,46 +268,373 @@ impl Pool {
     pub fn find(&self, server: &Server) -> Option<Backend> {
         self.inner.borrow().find(server)
     }
+
+    /// Returns the name the router knows the pool by
+    pub fn name(&self) -> String {
+        self.inner.borrow().name.clone()
+    }
+
+    /// Change the name of the pool, which is reported with its events
+    pub fn set_name(&self, name: &str) {
+        self.inner.borrow_mut().name = name.to_string();
+    }
+
+    /// Returns the strategy used to select a backend
+    pub fn strategy(&self) -> Strategy {
+        self.inner.borrow().strategy
+    }
+
+    /// Change the strategy used to select a backend
+    pub fn set_strategy(&self, strategy: Strategy) {
+        self.inner.borrow_mut().strategy = strategy;
+    }
+
+    /// Returns the part of the request hashed when using consistent hashing
+    pub fn hash_key(&self) -> HashKey {
+        self.inner.borrow().hash_key.clone()
+    }
+
+    /// Change the part of the request hashed when using consistent hashing
+    pub fn set_hash_key(&self, hash_key: HashKey) {
+        self.inner.borrow_mut().hash_key = hash_key;
+    }
+
+    /// Returns the sticky session settings if sticky sessions are enabled
+    pub fn sticky(&self) -> Option<StickySession> {
+        self.inner.borrow().sticky.clone()
+    }
+
+    /// Enable or disable sticky sessions
+    pub fn set_sticky(&self, sticky: Option<StickySession>) {
+        self.inner.borrow_mut().sticky = sticky;
+    }
+
+    /// Change the thresholds used to eject backends that fail live traffic
+    pub fn set_outlier_detection(&self, outlier_detection: OutlierDetection) {
+        self.inner.borrow_mut().outlier_detection = outlier_detection;
+    }
+
+    /// Change the thresholds used to open the circuit breaker of a backend
+    pub fn set_circuit_breaker(&self, circuit_breaker: CircuitBreaker) {
+        self.inner.borrow_mut().circuit_breaker = circuit_breaker;
+    }
+
+    /// Returns the policy used to retry failed requests
+    pub fn retry(&self) -> Retry {
+        self.inner.borrow().retry.clone()
+    }
+
+    /// Change the time over which a new or recovered backend ramps up to its full share of
+    /// requests
+    ///
+    /// The ramp does not apply to consistent hashing, which must keep each key on the same backend.
+    pub fn set_slow_start(&self, slow_start: Duration) {
+        let mut inner = self.inner.borrow_mut();
+        inner.slow_start = slow_start;
+        for backend in &inner.backends {
+            backend.inner.borrow_mut().slow_start = slow_start;
+        }
+    }
+
+    /// Change the policy used to retry failed requests
+    pub fn set_retry(&self, retry: Retry) {
+        self.inner.borrow_mut().retry = retry;
+    }
+
+    /// Returns the responses sent to the client when a request cannot be proxied
+    pub fn error_pages(&self) -> ErrorPages {
+        self.inner.borrow().error_pages.clone()
+    }
+
+    /// Change the responses sent to the client when a request cannot be proxied
+    pub fn set_error_pages(&self, error_pages: ErrorPages) {
+        self.inner.borrow_mut().error_pages = error_pages;
+    }
+
+    /// Returns the default timeouts of requests to a backend
+    pub fn timeouts(&self) -> Timeouts {
+        self.inner.borrow().timeouts.clone()
+    }
+
+    /// Change the default timeouts of requests to a backend
+    ///
+    /// Backends that override the timeouts keep their own timeouts.
+    pub fn set_timeouts(&self, timeouts: Timeouts) {
+        self.inner.borrow_mut().timeouts = timeouts;
+    }
+
+    /// Override the timeouts of requests to a server
+    ///
+    /// Returns false if the server is not in the pool.
+    pub fn set_server_timeouts(&self, server: &Server, timeouts: Timeouts) -> bool {
+        match self.find(server) {
+            Some(backend) => {
+                backend.inner.borrow_mut().timeouts = Some(timeouts);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the settings used to reuse connections to backends
+    pub fn connection_pool(&self) -> ConnectionPool {
+        self.inner.borrow().connection_pool.clone()
+    }
+
+    /// Change the settings used to reuse connections to backends
+    ///
+    /// This must be done before the proxy starts.
+    pub fn set_connection_pool(&self, connection_pool: ConnectionPool) {
+        self.inner.borrow_mut().connection_pool = connection_pool;
+    }
+
+    /// Returns the settings used to tunnel connections that upgrade to another protocol
+    pub fn upgrade(&self) -> Upgrade {
+        self.inner.borrow().upgrade.clone()
+    }
+
+    /// Enable or disable tunneling connections that upgrade to another protocol
+    pub fn set_upgrade(&self, upgrade: Upgrade) {
+        self.inner.borrow_mut().upgrade = upgrade;
+    }
+
+    /// Returns the protocol used to send requests to the servers of the pool
+    pub fn protocol(&self) -> Protocol {
+        self.inner.borrow().protocol
+    }
+
+    /// Change the protocol used to send requests to the servers of the pool
+    ///
+    /// Requests already sent finish on the connection they were sent on.
+    pub fn set_protocol(&self, protocol: Protocol) {
+        self.inner.borrow_mut().protocol = protocol;
+    }
+
+    /// Find the backend that requests to this url are sent to
+    ///
+    /// A backend matches if its server has the same scheme, host and port as the url.
+    pub fn find_by_url(&self, url: &Uri) -> Option<Backend> {
+        self.inner
+            .borrow()
+            .backends
+            .iter()
+            .find(|b| {
+                let server = b.server().url();
+                server.scheme() == url.scheme() && server.host() == url.host() &&
+                    server.port() == url.port()
+            })
+            .cloned()
+    }
+
+    /// Returns the timeouts of requests to the server with this url
+    ///
+    /// Falls back to the default timeouts if no server in the pool matches the url.
+    pub fn timeouts_for(&self, url: &Uri) -> Timeouts {
+        match self.find_by_url(url) {
+            Some(backend) => self.inner.borrow().timeouts(&backend),
+            None => self.timeouts(),
+        }
+    }
+
+    /// Returns the connect timeout (in milliseconds) of the server with this url
+    pub fn connect_timeout(&self, url: &Uri) -> u64 {
+        self.timeouts_for(url).connect
+    }
+
+    /// Returns a stream of the changes to the pool that are reported to the manager
+    ///
+    /// Only the most recently returned stream receives the changes.
+    pub fn events(&self) -> UnboundedReceiver<Event> {
+        let (tx, rx) = mpsc::unbounded();
+        self.inner.borrow_mut().events = Some(tx);
+        rx
+    }
+
+    /// Stop sending new requests to a server and remove it once its requests finish
+    ///
+    /// Returns false if the server is not in the pool.
+    pub fn drain(&self, server: &Server) -> bool {
+        let backend = match self.find(server) {
+            Some(backend) => backend,
+            None => return false,
+        };
+
+        info!("Draining {:?}", backend);
+        backend.mark_draining();
+        self.inner.borrow_mut().draining(&backend);
+        true
+    }
+}
+
+/// A change to the pool that is reported to the manager
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// The circuit breaker of a backend in the named pool changed state
+    CircuitBreaker(String, Server, CircuitState),
+
+    /// A draining backend in the named pool has this many requests left in flight
+    Draining(String, Server, usize),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 enum ServerState {
     Active,
     Down,
-    //Disabled,
+
+    /// The backend gets no new requests and is removed once its requests finish
+    Disabled,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// The weight given to a backend when none is specified
+pub const DEFAULT_WEIGHT: u32 = 1;
+
+/// The largest weight a backend can be given
+pub const MAX_WEIGHT: u32 = 10_000;
+
+/// The number of steps in which a backend ramps up to its full weight during slow start
+const RAMP_STEPS: u64 = 100;
+
+/// The latency (in milliseconds) assumed for a backend that has not sent a response yet
+const DEFAULT_LATENCY: f64 = 30.0;
+
+#[derive(Debug, Clone)]
 pub struct Backend {
     inner: Rc<RefCell<InnerBackend>>,
 }
 
 use std::hash::{Hash, Hasher};
 
+// A backend is identified by its server. The rest of the backend is state that changes as
+// requests are sent to it.
 impl Hash for Backend {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.inner.borrow().hash(state);
+        self.inner.borrow().server.hash(state);
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+impl PartialEq for Backend {
+    fn eq(&self, other: &Backend) -> bool {
+        self.inner.borrow().server == other.inner.borrow().server
+    }
+}
+
+impl Eq for Backend {}
+
+#[derive(Debug)]
 struct InnerBackend {
     server: Server,
     state: ServerState,
     stats: Stats,
+    in_flight: usize,
+
+    /// The share of requests this backend receives relative to the other backends
+    weight: u32,
+
+    /// Running weight used by smooth weighted round-robin
+    current_weight: i64,
+
+    /// The time over which the backend ramps up to its full weight
+    slow_start: Duration,
+
+    /// When the backend was added or last recovered
+    ramp_start: Option<Instant>,
+
+    /// A backup backend is only used when no primary backend is available
+    backup: bool,
+
+    /// Timeouts that override the default timeouts of the pool
+    timeouts: Option<Timeouts>,
+
+    outlier: Outlier,
+    circuit: Circuit,
 }
 
 impl Backend {
     pub fn new(server: Server) -> Backend {
+        Backend::with_weight(server, DEFAULT_WEIGHT)
+    }
+
+    pub fn with_weight(server: Server, weight: u32) -> Backend {
         Backend {
             inner: Rc::new(RefCell::new(InnerBackend {
                 server: server,
                 state: ServerState::Active,
                 stats: Stats::new(),
+                in_flight: 0,
+                weight: weight,
+                current_weight: 0,
+                slow_start: Duration::from_secs(0),
+                ramp_start: None,
+                backup: false,
+                timeouts: None,
+                outlier: Outlier::new(),
+                circuit: Circuit::new(),
             })),
         }
     }
 
+    /// Create a backup backend that is only used when no primary backend is available
+    pub fn new_backup(server: Server, weight: u32) -> Backend {
+        let backend = Backend::with_weight(server, weight);
+        backend.inner.borrow_mut().backup = true;
+        backend
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.inner.borrow().weight
+    }
+
+    /// The weight of the backend scaled down while it is ramping up after a slow start
+    ///
+    /// The full weight is multiplied by `RAMP_STEPS`, so that a ramping backend can be given a
+    /// fraction of its weight. A backend is never given a weight of zero.
+    fn effective_weight(&self) -> u64 {
+        let inner = self.inner.borrow();
+        let weight = inner.weight as u64 * RAMP_STEPS;
+
+        let window = as_millis(inner.slow_start);
+        match inner.ramp_start {
+            Some(start) if window > 0 => {
+                let elapsed = as_millis(start.elapsed());
+                if elapsed >= window {
+                    weight
+                } else {
+                    cmp::max(1, weight * elapsed / window)
+                }
+            }
+            _ => weight,
+        }
+    }
+
+    /// Start ramping the backend up to its full weight
+    fn start_ramp(&self) {
+        self.inner.borrow_mut().ramp_start = Some(Instant::now());
+    }
+
+    pub fn is_backup(&self) -> bool {
+        self.inner.borrow().backup
+    }
+
+    /// Returns the timeouts of the backend if it overrides the default timeouts of the pool
+    pub fn timeouts(&self) -> Option<Timeouts> {
+        self.inner.borrow().timeouts.clone()
+    }
+
+    /// The number of requests in flight relative to the weight of the backend
+    ///
+    /// Returned as a ratio so that two backends can be compared without floating point math.
+    fn load(&self) -> (u64, u64) {
+        let inner = self.inner.borrow();
+        (inner.in_flight as u64, self.effective_weight())
+    }
+
+    /// Returns true if this backend has a lower load than the other backend
+    fn less_loaded_than(&self, other: &Backend) -> bool {
+        let (in_flight, weight) = self.load();
+        let (other_in_flight, other_weight) = other.load();
+        in_flight * other_weight < other_in_flight * weight
+    }
+
     pub fn inc_success(&self) {
         self.inner.borrow_mut().stats.inc_success()
     }
@@ -123,6 +643,61 @@ impl Backend {
         self.inner.borrow_mut().stats.inc_failure()
     }
 
+    /// See `Stats::observe_latency`
+    pub fn observe_latency(&self, latency: Duration) {
+        self.inner.borrow_mut().stats.observe_latency(latency)
+    }
+
+    /// See `Stats::latency`
+    pub fn latency(&self) -> Option<f64> {
+        self.inner.borrow().stats.latency()
+    }
+
+    pub fn inc_requests(&self) {
+        self.inner.borrow_mut().stats.inc_requests()
+    }
+
+    pub fn connection_opened(&self) {
+        self.inner.borrow_mut().stats.connection_opened()
+    }
+
+    pub fn connection_closed(&self) {
+        self.inner.borrow_mut().stats.connection_closed()
+    }
+
+    /// The connections to this backend in this process
+    pub fn connections(&self) -> Connections {
+        self.inner.borrow().stats.connections()
+    }
+
+    /// The peak EWMA cost of sending another request to this backend
+    fn cost(&self) -> f64 {
+        let latency = self.latency().unwrap_or(DEFAULT_LATENCY);
+        let (in_flight, weight) = self.load();
+        latency * (in_flight + 1) as f64 / weight as f64
+    }
+
+    /// The number of requests sent to this backend that have not yet completed
+    pub fn in_flight(&self) -> usize {
+        self.inner.borrow().in_flight
+    }
+
+    pub fn inc_in_flight(&self) {
+        self.inner.borrow_mut().in_flight += 1;
+    }
+
+    pub fn dec_in_flight(&self) {
+        let mut inner = self.inner.borrow_mut();
+        if inner.in_flight > 0 {
+            inner.in_flight -= 1;
+        }
+    }
+
+    /// See `Server::id`
+    pub fn id(&self) -> u64 {
+        self.inner.borrow().server.id()
+    }
+
     pub fn server(&self) -> Server {
         self.inner.borrow().server.clone()
     }
@@ -131,59 +706,503 @@ impl Backend {
         self.inner.borrow().state == ServerState::Active
     }
 
+    /// Returns true if the backend is ejected because it failed live traffic
+    pub fn is_ejected(&self) -> bool {
+        self.inner.borrow().outlier.is_ejected(Instant::now())
+    }
+
+    /// Returns true if the backend can be sent new requests
+    pub fn is_available(&self) -> bool {
+        self.is_active() && !self.is_ejected()
+    }
+
+    /// Returns the state of the circuit breaker
+    pub fn circuit_state(&self) -> CircuitState {
+        self.inner.borrow().circuit.state()
+    }
+
     pub fn is_down(&self) -> bool {
         self.inner.borrow().state == ServerState::Down
     }
 
+    /// Mark the backend active, ramping it up if it was down
     pub fn mark_active(&self) {
+        if self.is_down() {
+            self.start_ramp();
+        }
         self.inner.borrow_mut().state = ServerState::Active;
     }
 
     pub fn mark_down(&self) {
         self.inner.borrow_mut().state = ServerState::Down;
     }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.borrow().state == ServerState::Disabled
+    }
+
+    pub fn mark_draining(&self) {
+        self.inner.borrow_mut().state = ServerState::Disabled;
+    }
+}
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Counts a request as in flight on a backend for as long as this is alive
+#[derive(Debug)]
+pub struct InFlight {
+    pool: Pool,
+    backend: Backend,
+
+    /// The round of the circuit breaker probe, if the request is one
+    probe: Option<u64>,
+}
+
+impl InFlight {
+    fn new(pool: Pool, backend: Backend) -> InFlight {
+        backend.inc_in_flight();
+        InFlight {
+            pool: pool,
+            backend: backend,
+            probe: None,
+        }
+    }
+
+    /// Keep the request in flight until the body of the response is done streaming
+    fn track(self, res: server::Response, timer: RequestTimer) -> server::Response<InFlightBody> {
+        let mut r = server::Response::new().with_status(res.status());
+        r.headers_mut().extend(res.headers().iter());
+
+        // a response without a body, such as a gRPC error, is done with its headers
+        if res.body_ref().is_none() {
+            return r;
+        }
+
+        r.with_body(InFlightBody {
+            body: RefCell::new(timer.body(res.body())),
+            in_flight: RefCell::new(Some(self)),
+        })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(round) = self.probe {
+            self.backend.inner.borrow_mut().circuit.release_probe(round);
+        }
+        self.backend.dec_in_flight();
+        if self.backend.is_draining() {
+            self.pool.inner.borrow_mut().draining(&self.backend);
+        }
+    }
+}
+
+/// A response body that marks the request as no longer in flight once it finishes streaming
+///
+/// The request is also considered finished if the body errors or is dropped before the end, such
+/// as when the client disconnects.
+#[derive(Debug)]
+pub struct InFlightBody {
+    body: RefCell<TimedBody>,
+    in_flight: RefCell<Option<InFlight>>,
+}
+
+impl InFlightBody {
+    /// Take the body out of a response that can only be borrowed, leaving an empty body behind
+    ///
+    /// hyper has no public way to move the body out of a response of any type, which the HTTP/2
+    /// server needs to send the body on a stream of its own.
+    pub fn take_body(&self) -> InFlightBody {
+        let empty = TimedBody::from(Body::empty());
+        InFlightBody {
+            body: RefCell::new(mem::replace(&mut *self.body.borrow_mut(), empty)),
+            in_flight: RefCell::new(self.in_flight.borrow_mut().take()),
+        }
+    }
+}
+
+impl From<Body> for InFlightBody {
+    /// A body that is not tracked, such as one created by the proxy itself
+    fn from(body: Body) -> InFlightBody {
+        InFlightBody {
+            body: RefCell::new(TimedBody::from(body)),
+            in_flight: RefCell::new(None),
+        }
+    }
+}
+
+impl Stream for InFlightBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let poll = self.body.get_mut().poll();
+        match poll {
+            Ok(Async::Ready(None)) | Err(_) => {
+                self.in_flight.get_mut().take();
+            }
+            _ => (),
+        }
+        poll
+    }
+}
+
+/// The backends that can be selected for a request
+#[derive(Clone, Copy, Debug)]
+struct Filter<'a> {
+    /// Backends that must not be selected, such as those already tried for the request
+    exclude: &'a [Server],
+
+    /// Select among the backup backends instead of the primary backends
+    backup: bool,
 }
 
 #[derive(Debug, Default)]
 pub struct InnerPool {
+    name: String,
     backends: Vec<Backend>,
     last_used: usize,
+    strategy: Strategy,
+    hash_key: HashKey,
+    ring: Ring,
+    sticky: Option<StickySession>,
+    outlier_detection: OutlierDetection,
+    circuit_breaker: CircuitBreaker,
+    events: Option<UnboundedSender<Event>>,
+    retry: Retry,
+    error_pages: ErrorPages,
+    timeouts: Timeouts,
+    connection_pool: ConnectionPool,
+    upgrade: Upgrade,
+    protocol: Protocol,
+    slow_start: Duration,
 }
 
 impl InnerPool {
     // this is only used in test code
     #[cfg(test)]
     fn new(backends: Vec<Backend>) -> InnerPool {
-        InnerPool {
+        let mut pool = InnerPool {
+            name: String::new(),
             backends: backends.into_iter().map(|b| b).collect(),
             last_used: 0,
-        }
+            strategy: Strategy::RoundRobin,
+            hash_key: HashKey::default(),
+            ring: Ring::default(),
+            sticky: None,
+            outlier_detection: OutlierDetection::default(),
+            circuit_breaker: CircuitBreaker::default(),
+            events: None,
+            retry: Retry::default(),
+            error_pages: ErrorPages::default(),
+            timeouts: Timeouts::default(),
+            connection_pool: ConnectionPool::default(),
+            upgrade: Upgrade::default(),
+            protocol: Protocol::default(),
+            slow_start: Duration::from_secs(0),
+        };
+        pool.build_ring();
+        pool
     }
 
-    fn get(&mut self) -> Option<Backend> {
+    fn get(&mut self, affinity: Affinity, exclude: &[Server]) -> Option<Backend> {
         if self.backends.is_empty() {
             warn!("Pool is empty of backends");
             return None;
         }
 
-        let start = self.last_used;
-        loop {
-            self.last_used = (self.last_used + 1) % self.backends.len();
-            let backend = match self.backends.get(self.last_used) {
-                Some(b) => b,
-                None => return None,
+        let primary = Filter {
+            exclude: exclude,
+            backup: false,
+        };
+        let mut backend = self.select(affinity, primary);
+
+        if backend.is_none() && self.backends.iter().any(|b| b.is_backup()) {
+            warn!("Pool has no active primary backends, using backup backends");
+            let backup = Filter {
+                exclude: exclude,
+                backup: true,
+            };
+            backend = self.select(affinity, backup);
+        }
+
+        match backend {
+            Some(backend) => {
+                debug!("Pool is cloaning (hehe) out {:?}", backend);
+                Some(backend)
+            }
+            None => {
+                warn!("Pool has no active backends");
+                None
+            }
+        }
+    }
+
+    /// Returns true if `get` would find a backend that is not in `exclude`
+    fn has_backend(&self, exclude: &[Server]) -> bool {
+        self.backends.iter().any(|b| {
+            let filter = Filter {
+                exclude: exclude,
+                backup: b.is_backup(),
+            };
+            self.can_send(b, filter)
+        })
+    }
+
+    /// Select a backend among those allowed by the filter
+    fn select(&mut self, affinity: Affinity, filter: Filter) -> Option<Backend> {
+        if let Some(id) = affinity.sticky {
+            let sticky = self.backends.iter().find(|b| self.can_send(b, filter) && b.id() == id);
+            if let Some(backend) = sticky {
+                debug!("Pool is using sticky backend {:?}", backend);
+                return Some(backend.clone());
+            }
+        }
+
+        match (self.strategy, affinity.hash) {
+            (Strategy::RoundRobin, _) => self.round_robin(filter),
+            (Strategy::Random, _) => self.random(filter),
+            (Strategy::LeastOutstanding, _) => self.least_outstanding(filter),
+            (Strategy::PowerOfTwoChoices, _) => {
+                self.power_of_two_choices(filter, |a, b| a.less_loaded_than(b))
+            }
+            (Strategy::PeakEwma, _) => {
+                self.power_of_two_choices(filter, |a, b| a.cost() < b.cost())
+            }
+            (Strategy::ConsistentHash, Some(hash)) => self.consistent_hash(hash, filter),
+            (Strategy::ConsistentHash, None) => self.round_robin(filter),
+        }
+    }
+
+    /// Returns true if the backend is allowed by the filter, available and its circuit breaker
+    /// lets a request through
+    fn can_send(&self, backend: &Backend, filter: Filter) -> bool {
+        backend.is_backup() == filter.backup && backend.is_available() &&
+            !filter.exclude.contains(&backend.inner.borrow().server) &&
+            backend.inner.borrow().circuit.allows_request(
+                Instant::now(),
+                &self.circuit_breaker,
+            )
+    }
+
+    /// Returns the timeouts of requests to the backend
+    fn timeouts(&self, backend: &Backend) -> Timeouts {
+        backend.timeouts().unwrap_or_else(|| self.timeouts.clone())
+    }
+
+    /// Record that a request is being sent to the backend
+    ///
+    /// Returns the round of the circuit breaker probe if the request is one.
+    fn dispatch(&mut self, backend: &Backend) -> Option<u64> {
+        backend.inc_requests();
+        let now = Instant::now();
+        let (changed, probe) = {
+            let mut inner = backend.inner.borrow_mut();
+            let in_flight = inner.in_flight;
+            let changed = inner.circuit.dispatch(in_flight, now, &self.circuit_breaker);
+            (changed, inner.circuit.probe())
+        };
+        if let Some(state) = changed {
+            self.circuit_changed(backend, state);
+        }
+        probe
+    }
+
+    /// Log and publish a change to the circuit breaker state of a backend
+    fn circuit_changed(&mut self, backend: &Backend, state: CircuitState) {
+        match state {
+            CircuitState::Open => warn!("Opening circuit breaker of {:?}", backend),
+            _ => info!("Circuit breaker of {:?} is now {}", backend, state),
+        }
+
+        let event = Event::CircuitBreaker(self.name.clone(), backend.server(), state);
+        self.publish(event);
+    }
+
+    /// Report the progress of a draining backend and remove it once it is idle
+    fn draining(&mut self, backend: &Backend) {
+        let in_flight = backend.in_flight();
+        let event = Event::Draining(self.name.clone(), backend.server(), in_flight);
+        self.publish(event);
+
+        if in_flight == 0 {
+            info!("Removing drained {:?} from pool", backend);
+            self.remove(&backend.server());
+        }
+    }
+
+    fn publish(&mut self, event: Event) {
+        let closed = match self.events {
+            Some(ref tx) => tx.unbounded_send(event).is_err(),
+            None => false,
+        };
+        if closed {
+            self.events = None;
+        }
+    }
+
+    /// Returns the active backends, starting after the backend that was last used
+    ///
+    /// Rotating the starting point keeps ties from always going to the first backend.
+    fn active(&self, filter: Filter) -> Vec<&Backend> {
+        let len = self.backends.len();
+        (1..len + 1)
+            .map(|i| &self.backends[(self.last_used + i) % len])
+            .filter(|b| self.can_send(b, filter))
+            .collect()
+    }
+
+    /// Smooth weighted round-robin
+    ///
+    /// Each active backend has its weight added to its current weight. The backend with the
+    /// highest current weight is picked and then has the total weight subtracted from its current
+    /// weight. Backends are picked in proportion to their weight without sending a burst of
+    /// requests to the heaviest backend. This is the same algorithm nginx uses.
+    fn round_robin(&mut self, filter: Filter) -> Option<Backend> {
+        let mut total = 0;
+        let mut best: Option<&Backend> = None;
+        for backend in self.backends.iter().filter(|b| self.can_send(b, filter)) {
+            let weight = backend.effective_weight() as i64;
+            let mut inner = backend.inner.borrow_mut();
+            inner.current_weight += weight;
+            total += weight;
+
+            let is_best = match best {
+                Some(b) => inner.current_weight > b.inner.borrow().current_weight,
+                None => true,
+            };
+            if is_best {
+                best = Some(backend);
+            }
+        }
+
+        best.map(|backend| {
+            backend.inner.borrow_mut().current_weight -= total;
+            backend.clone()
+        })
+    }
+
+    fn random(&mut self, filter: Filter) -> Option<Backend> {
+        let active = self.active(filter);
+        let total: u64 = active.iter().map(|b| b.effective_weight()).sum();
+        if total == 0 {
+            return None;
+        }
+
+        let mut point = rand::thread_rng().gen_range(0, total);
+        for backend in active {
+            let weight = backend.effective_weight();
+            if point < weight {
+                return Some(backend.clone());
+            }
+            point -= weight;
+        }
+
+        None
+    }
+
+    fn least_outstanding(&mut self, filter: Filter) -> Option<Backend> {
+        let mut best: Option<&Backend> = None;
+        for backend in self.active(filter) {
+            best = match best {
+                Some(b) if !backend.less_loaded_than(b) => Some(b),
+                _ => Some(backend),
             };
+        }
+
+        let backend = best.cloned();
+        self.mark_used(&backend);
+        backend
+    }
 
-            if backend.is_down() {
-                if start == self.last_used {
-                    warn!("Pool has no active backends");
-                    return None;
+    /// Pick two random backends and use the better one
+    fn power_of_two_choices<F>(&mut self, filter: Filter, is_better: F) -> Option<Backend>
+    where
+        F: Fn(&Backend, &Backend) -> bool,
+    {
+        let active = self.active(filter);
+        let backend = match active.len() {
+            0 => None,
+            1 => Some(active[0].clone()),
+            len => {
+                let mut rng = rand::thread_rng();
+                let first = rng.gen_range(0, len);
+                // pick a different second backend by offsetting from the first one
+                let second = (first + rng.gen_range(1, len)) % len;
+                let (a, b) = (active[first], active[second]);
+                if is_better(b, a) {
+                    Some(b.clone())
+                } else {
+                    Some(a.clone())
                 }
-                continue;
             }
+        };
+        self.mark_used(&backend);
+        backend
+    }
 
-            debug!("Pool is cloaning (hehe) out {:?}", backend);
-            return Some(backend.clone());
+    fn consistent_hash(&mut self, hash: u64, filter: Filter) -> Option<Backend> {
+        let backends = &self.backends;
+        self.ring
+            .get(hash, |index| self.can_send(&backends[index], filter))
+            .map(|index| backends[index].clone())
+    }
+
+    /// Record the result of a request and eject the backend if it is an outlier
+    ///
+    /// The result is also recorded by the circuit breaker of the backend. A backend is not ejected
+    /// if that would eject more than the maximum percentage of the pool.
+    fn record(&mut self, backend: &Backend, success: bool) {
+        let now = Instant::now();
+        let changed = backend.inner.borrow_mut().circuit.record(
+            success,
+            now,
+            &self.circuit_breaker,
+        );
+        if let Some(state) = changed {
+            self.circuit_changed(backend, state);
+        }
+
+        let conf = &self.outlier_detection;
+        if !backend.inner.borrow_mut().outlier.record(success, now, conf) {
+            return;
+        }
+
+        let ejected = self.backends
+            .iter()
+            .filter(|b| b.inner.borrow().outlier.is_ejected(now))
+            .count();
+        let max_ejected = self.backends.len() * conf.max_ejection_percent as usize / 100;
+        if ejected >= max_ejected {
+            warn!(
+                "Not ejecting {:?} because {} of {} backends are already ejected",
+                backend,
+                ejected,
+                self.backends.len()
+            );
+            return;
+        }
+
+        let duration = backend.inner.borrow_mut().outlier.eject(now, conf);
+        warn!("Ejecting {:?} from pool for {:?}", backend, duration);
+    }
+
+    /// Rebuild the hash ring after the backends change
+    fn build_ring(&mut self) {
+        let nodes: Vec<(String, u32)> = self.backends
+            .iter()
+            .map(|b| (b.server().url().to_string(), b.weight()))
+            .collect();
+        self.ring = Ring::new(nodes.iter().map(|&(ref name, weight)| (&name[..], weight)));
+    }
+
+    fn mark_used(&mut self, backend: &Option<Backend>) {
+        if let Some(ref backend) = *backend {
+            if let Some(i) = self.backends.iter().position(|b| b == backend) {
+                self.last_used = i;
+            }
         }
     }
 
@@ -203,12 +1222,17 @@ impl InnerPool {
             return false;
         }
 
+        backend.inner.borrow_mut().slow_start = self.slow_start;
+        backend.start_ramp();
+
         self.backends.push(backend);
+        self.build_ring();
         true
     }
 
     fn remove(&mut self, server: &Server) {
         self.backends.retain(|b| &b.server() != server);
+        self.build_ring();
     }
 
     fn find(&self, server: &Server) -> Option<Backend> {
@@ -221,9 +1245,18 @@ impl InnerPool {
 
 #[cfg(test)]
 mod tests {
-    use super::{Backend, InnerPool};
+    use super::{Affinity, Backend, Event, InFlight, InFlightBody, InnerPool, Pool, Strategy};
+    use circuit::CircuitState;
+    use config::Timeouts;
+    use futures::{Future, Stream};
+    use futures::unsync::mpsc;
+    use hyper::Body;
+    use hyper::server::Response;
+    use std::cell::RefCell;
+    use std::time::{Duration, Instant};
     use server::Server;
     use std::str::FromStr;
+    use timeout::{timer, RequestTimer, TimedBody};
 
     #[test]
     fn test_rrb_backend() {
@@ -241,10 +1274,10 @@ mod tests {
         let mut rrb = InnerPool::new(backends);
         assert_eq!(2, rrb.backends.len());
 
-        let first = rrb.get().unwrap();
-        let second = rrb.get().unwrap();
-        let third = rrb.get().unwrap();
-        let fourth = rrb.get().unwrap();
+        let first = rrb.get(Affinity::default(), &[]).unwrap();
+        let second = rrb.get(Affinity::default(), &[]).unwrap();
+        let third = rrb.get(Affinity::default(), &[]).unwrap();
+        let fourth = rrb.get(Affinity::default(), &[]).unwrap();
         assert_eq!(first, third);
         assert_eq!(second, fourth);
         assert!(first != second);
@@ -255,19 +1288,19 @@ mod tests {
         let backends = vec![];
         let mut rrb = InnerPool::new(backends);
         assert_eq!(0, rrb.backends.len());
-        assert!(rrb.get().is_none());
+        assert!(rrb.get(Affinity::default(), &[]).is_none());
         assert!(rrb.all().is_empty());
     }
 
     #[test]
     fn test_add_to_rrb_backend() {
         let mut rrb = InnerPool::new(vec![]);
-        assert!(rrb.get().is_none());
+        assert!(rrb.get(Affinity::default(), &[]).is_none());
         let server = Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false);
         let backend = Backend::new(server.clone());
         rrb.add(backend);
         let b1 = Backend::new(server.clone());
-        assert!(rrb.get().is_some());
+        assert!(rrb.get(Affinity::default(), &[]).is_some());
         assert_eq!(vec![b1], rrb.all());
     }
 
@@ -297,4 +1330,502 @@ mod tests {
         assert_eq!(0, rrb.backends.len());
         assert!(rrb.all().is_empty());
     }
+
+    fn backends() -> Vec<Backend> {
+        vec![
+            Backend::new(Server::new(
+                FromStr::from_str("http://127.0.0.1:6000").unwrap(),
+                false,
+            )),
+            Backend::new(Server::new(
+                FromStr::from_str("http://127.0.0.1:6001").unwrap(),
+                false,
+            )),
+            Backend::new(Server::new(
+                FromStr::from_str("http://127.0.0.1:6002").unwrap(),
+                false,
+            )),
+        ]
+    }
+
+    #[test]
+    fn test_strategy_from_str() {
+        for strategy in vec![
+            Strategy::RoundRobin,
+            Strategy::Random,
+            Strategy::LeastOutstanding,
+            Strategy::PowerOfTwoChoices,
+            Strategy::ConsistentHash,
+            Strategy::PeakEwma,
+        ]
+        {
+            assert_eq!(Ok(strategy), strategy.as_str().parse::<Strategy>());
+        }
+
+        assert!("unknown".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn test_random_skips_down_backends() {
+        let backends = backends();
+        backends[0].mark_down();
+        backends[2].mark_down();
+
+        let mut pool = InnerPool::new(backends.clone());
+        pool.strategy = Strategy::Random;
+        for _ in 0..10 {
+            assert_eq!(backends[1], pool.get(Affinity::default(), &[]).unwrap());
+        }
+
+        backends[1].mark_down();
+        assert!(pool.get(Affinity::default(), &[]).is_none());
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let backends = backends();
+        backends[0].inc_in_flight();
+        backends[1].inc_in_flight();
+
+        let mut pool = InnerPool::new(backends.clone());
+        pool.strategy = Strategy::LeastOutstanding;
+        assert_eq!(backends[2], pool.get(Affinity::default(), &[]).unwrap());
+
+        backends[2].inc_in_flight();
+        backends[2].inc_in_flight();
+        backends[0].dec_in_flight();
+        assert_eq!(backends[0], pool.get(Affinity::default(), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_least_outstanding_spreads_ties() {
+        let backends = backends();
+        let mut pool = InnerPool::new(backends.clone());
+        pool.strategy = Strategy::LeastOutstanding;
+
+        let first = pool.get(Affinity::default(), &[]).unwrap();
+        let second = pool.get(Affinity::default(), &[]).unwrap();
+        let third = pool.get(Affinity::default(), &[]).unwrap();
+        assert!(first != second);
+        assert!(second != third);
+        assert!(first != third);
+    }
+
+    #[test]
+    fn test_power_of_two_choices() {
+        let backends = backends();
+        backends[0].mark_down();
+        backends[1].inc_in_flight();
+
+        let mut pool = InnerPool::new(backends.clone());
+        pool.strategy = Strategy::PowerOfTwoChoices;
+
+        // with only two active backends both are always chosen, so the less loaded one wins
+        for _ in 0..10 {
+            assert_eq!(backends[2], pool.get(Affinity::default(), &[]).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let heavy = Backend::with_weight(
+            Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false),
+            3,
+        );
+        let light = Backend::with_weight(
+            Server::new(FromStr::from_str("http://127.0.0.1:6001").unwrap(), false),
+            1,
+        );
+
+        let mut pool = InnerPool::new(vec![heavy.clone(), light.clone()]);
+        let picked: Vec<Backend> = (0..8)
+            .map(|_| pool.get(Affinity::default(), &[]).unwrap())
+            .collect();
+
+        assert_eq!(6, picked.iter().filter(|b| **b == heavy).count());
+        assert_eq!(2, picked.iter().filter(|b| **b == light).count());
+
+        // smooth weighted round-robin should not send consecutive requests to the light backend
+        for pair in picked.windows(2) {
+            assert!(!(pair[0] == light && pair[1] == light));
+        }
+    }
+
+    #[test]
+    fn test_slow_start() {
+        let window = Duration::from_secs(10);
+        let warm = Backend::new(Server::new(
+            FromStr::from_str("http://127.0.0.1:6000").unwrap(),
+            false,
+        ));
+        let cold = Backend::new(Server::new(
+            FromStr::from_str("http://127.0.0.1:6001").unwrap(),
+            false,
+        ));
+
+        let mut pool = InnerPool::new(vec![warm.clone()]);
+        pool.slow_start = window;
+        pool.add(cold.clone());
+        assert!(cold.effective_weight() < warm.effective_weight() / 10);
+
+        // half way through the window the cold backend gets half a share of requests
+        cold.inner.borrow_mut().ramp_start = Some(Instant::now() - window / 2);
+        let picked: Vec<Backend> = (0..30)
+            .map(|_| pool.get(Affinity::default(), &[]).unwrap())
+            .collect();
+        let count = picked.iter().filter(|b| **b == cold).count();
+        assert!(count >= 9 && count <= 11, "cold backend picked {} times", count);
+
+        cold.inner.borrow_mut().ramp_start = Some(Instant::now() - window);
+        assert_eq!(warm.effective_weight(), cold.effective_weight());
+
+        // a recovered backend ramps up again
+        cold.mark_down();
+        cold.mark_active();
+        assert!(cold.effective_weight() < warm.effective_weight() / 10);
+    }
+
+    #[test]
+    fn test_weighted_least_outstanding() {
+        let heavy = Backend::with_weight(
+            Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false),
+            4,
+        );
+        let light = Backend::with_weight(
+            Server::new(FromStr::from_str("http://127.0.0.1:6001").unwrap(), false),
+            1,
+        );
+        heavy.inc_in_flight();
+        heavy.inc_in_flight();
+        light.inc_in_flight();
+
+        let mut pool = InnerPool::new(vec![heavy.clone(), light.clone()]);
+        pool.strategy = Strategy::LeastOutstanding;
+        assert_eq!(heavy, pool.get(Affinity::default(), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_peak_ewma() {
+        let backends = backends();
+        backends[0].mark_down();
+        backends[1].observe_latency(Duration::from_millis(100));
+        backends[2].observe_latency(Duration::from_millis(10));
+
+        let mut pool = InnerPool::new(backends.clone());
+        pool.strategy = Strategy::PeakEwma;
+        assert_eq!(backends[2], pool.get(Affinity::default(), &[]).unwrap());
+
+        // the faster backend is avoided once it has enough requests in flight
+        for _ in 0..10 {
+            backends[2].inc_in_flight();
+        }
+        assert_eq!(backends[1], pool.get(Affinity::default(), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let backends = backends();
+        let mut pool = InnerPool::new(backends.clone());
+        pool.strategy = Strategy::ConsistentHash;
+
+        let hashed = Affinity {
+            hash: Some(42),
+            sticky: None,
+        };
+        let first = pool.get(hashed, &[]).unwrap();
+        for _ in 0..10 {
+            assert_eq!(first, pool.get(hashed, &[]).unwrap());
+        }
+
+        // a down backend gives up its keys until it is active again
+        first.mark_down();
+        let second = pool.get(hashed, &[]).unwrap();
+        assert!(first != second);
+
+        first.mark_active();
+        assert_eq!(first, pool.get(hashed, &[]).unwrap());
+
+        // requests without a key are still balanced
+        let a = pool.get(Affinity::default(), &[]).unwrap();
+        let b = pool.get(Affinity::default(), &[]).unwrap();
+        assert!(a != b);
+    }
+
+    #[test]
+    fn test_sticky_backend() {
+        let backends = backends();
+        let mut pool = InnerPool::new(backends.clone());
+
+        let sticky = Affinity {
+            hash: None,
+            sticky: Some(backends[2].id()),
+        };
+        for _ in 0..10 {
+            assert_eq!(backends[2], pool.get(sticky, &[]).unwrap());
+        }
+
+        // fall back to the strategy while the sticky backend is down
+        backends[2].mark_down();
+        let fallback = pool.get(sticky, &[]).unwrap();
+        assert!(fallback != backends[2]);
+
+        // unknown backends are ignored
+        let unknown = Affinity {
+            hash: None,
+            sticky: Some(0),
+        };
+        assert!(pool.get(unknown, &[]).is_some());
+    }
+
+    #[test]
+    fn test_outlier_ejection() {
+        let backends = backends();
+        let mut pool = InnerPool::new(backends.clone());
+        pool.outlier_detection.consecutive_failures = 5;
+        let failures = pool.outlier_detection.consecutive_failures;
+
+        for _ in 0..failures {
+            pool.record(&backends[0], false);
+        }
+        assert!(backends[0].is_ejected());
+        for _ in 0..10 {
+            assert!(pool.get(Affinity::default(), &[]).unwrap() != backends[0]);
+        }
+
+        // no more than half of the pool can be ejected
+        for _ in 0..failures {
+            pool.record(&backends[1], false);
+        }
+        assert!(!backends[1].is_ejected());
+    }
+
+    #[test]
+    fn test_exclude_tried_backends() {
+        let backends = backends();
+        let mut pool = InnerPool::new(backends.clone());
+        let tried = vec![backends[0].server(), backends[1].server()];
+
+        for _ in 0..10 {
+            assert_eq!(backends[2], pool.get(Affinity::default(), &tried).unwrap());
+        }
+
+        backends[2].mark_down();
+        assert!(pool.get(Affinity::default(), &tried).is_none());
+    }
+
+    #[test]
+    fn test_backup_backends() {
+        let backends = backends();
+        let backup = Backend::new_backup(
+            Server::new(FromStr::from_str("http://127.0.0.1:6003").unwrap(), false),
+            1,
+        );
+        let mut pool = InnerPool::new(backends.clone());
+        pool.add(backup.clone());
+
+        for _ in 0..10 {
+            assert!(pool.get(Affinity::default(), &[]).unwrap() != backup);
+        }
+
+        for backend in &backends {
+            backend.mark_down();
+        }
+        assert_eq!(backup, pool.get(Affinity::default(), &[]).unwrap());
+
+        // the primary backends are used again as soon as one is active
+        backends[1].mark_active();
+        assert_eq!(backends[1], pool.get(Affinity::default(), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens() {
+        let backends = backends();
+        let mut pool = InnerPool::new(backends.clone());
+        pool.circuit_breaker.max_concurrent = 2;
+        let (tx, rx) = mpsc::unbounded();
+        pool.events = Some(tx);
+
+        backends[0].inc_in_flight();
+        backends[0].inc_in_flight();
+        pool.dispatch(&backends[0]);
+        assert_eq!(CircuitState::Open, backends[0].circuit_state());
+        for _ in 0..10 {
+            assert!(pool.get(Affinity::default(), &[]).unwrap() != backends[0]);
+        }
+
+        drop(pool);
+        let events = rx.collect().wait().unwrap();
+        assert_eq!(
+            vec![Event::CircuitBreaker(String::new(), backends[0].server(), CircuitState::Open)],
+            events
+        );
+    }
+
+    #[test]
+    fn test_dropped_probe_released() {
+        let pool = Pool::default();
+        pool.add(backends()[0].server());
+        let mut conf = pool.inner.borrow().circuit_breaker.clone();
+        conf.failure_rate = 80;
+        conf.half_open_probes = 1;
+        conf.open_time = 0;
+        pool.set_circuit_breaker(conf.clone());
+
+        let backend = pool.all().remove(0);
+        for _ in 0..conf.min_requests {
+            pool.inner.borrow_mut().record(&backend, false);
+        }
+        assert_eq!(CircuitState::Open, backend.circuit_state());
+
+        // the probe is dropped before it gets a response, such as when the client disconnects
+        let probe = pool.request(Affinity::default(), &[], &timer(), |_| {
+            Box::new(::futures::empty())
+        });
+        assert_eq!(CircuitState::HalfOpen, backend.circuit_state());
+        assert!(pool.inner.borrow_mut().get(Affinity::default(), &[]).is_none());
+
+        drop(probe);
+        assert_eq!(Some(backend), pool.inner.borrow_mut().get(Affinity::default(), &[]));
+    }
+
+    #[test]
+    fn test_hold() {
+        let pool = Pool::default();
+        let backends = backends();
+        pool.add(backends[0].server());
+        let backend = pool.all().remove(0);
+
+        let held = pool.hold(&backend.server());
+        assert!(held.is_some());
+        assert_eq!(1, backend.in_flight());
+        drop(held);
+        assert_eq!(0, backend.in_flight());
+
+        assert!(pool.hold(&backends[1].server()).is_none());
+    }
+
+    #[test]
+    fn test_has_backend() {
+        let backends = backends();
+        let pool = InnerPool::new(backends.clone());
+        assert!(pool.has_backend(&[]));
+
+        let tried = vec![backends[0].server(), backends[1].server()];
+        assert!(pool.has_backend(&tried));
+
+        backends[2].mark_down();
+        assert!(!pool.has_backend(&tried));
+    }
+
+    #[test]
+    fn test_drain() {
+        let pool = Pool::default();
+        pool.set_name("api");
+        let backends = backends();
+        for backend in &backends {
+            pool.add(backend.server());
+        }
+        let events = pool.events();
+
+        let draining = pool.find(&backends[0].server()).unwrap();
+        let in_flight = InFlight::new(pool.clone(), draining.clone());
+        assert!(pool.drain(&backends[0].server()));
+        assert!(draining.is_draining());
+        assert_eq!(3, pool.all().len());
+
+        // a draining backend gets no new requests
+        for _ in 0..10 {
+            let backend = pool.inner.borrow_mut().get(Affinity::default(), &[]).unwrap();
+            assert!(backend != draining);
+        }
+
+        // the backend is removed once its last request finishes
+        drop(in_flight);
+        assert_eq!(2, pool.all().len());
+
+        // an idle backend is removed right away
+        assert!(pool.drain(&backends[1].server()));
+        assert_eq!(1, pool.all().len());
+        assert!(!pool.drain(&backends[1].server()));
+
+        drop(pool);
+        let events = events.collect().wait().unwrap();
+        assert_eq!(
+            vec![
+                Event::Draining("api".to_string(), backends[0].server(), 1),
+                Event::Draining("api".to_string(), backends[0].server(), 0),
+                Event::Draining("api".to_string(), backends[1].server(), 0),
+            ],
+            events
+        );
+    }
+
+    #[test]
+    fn test_server_timeouts() {
+        let pool = Pool::default();
+        let server = Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false);
+        pool.add(server.clone());
+
+        let url = FromStr::from_str("http://127.0.0.1:6000/path?q=1").unwrap();
+        assert_eq!(Timeouts::default(), pool.timeouts_for(&url));
+
+        let mut timeouts = Timeouts::default();
+        timeouts.connect = 100;
+        assert!(pool.set_server_timeouts(&server, timeouts.clone()));
+        assert_eq!(timeouts, pool.timeouts_for(&url));
+        assert_eq!(100, pool.connect_timeout(&url));
+
+        let other = FromStr::from_str("http://127.0.0.1:6001/").unwrap();
+        assert_eq!(Timeouts::default(), pool.timeouts_for(&other));
+
+        let missing = Server::new(other, false);
+        assert!(!pool.set_server_timeouts(&missing, timeouts));
+    }
+
+    #[test]
+    fn test_in_flight_until_body_finishes() {
+        let backend = backends().remove(0);
+
+        let body = InFlightBody {
+            body: RefCell::new(TimedBody::from(Body::from("hello"))),
+            in_flight: RefCell::new(Some(InFlight::new(Pool::default(), backend.clone()))),
+        };
+        assert_eq!(1, backend.in_flight());
+
+        let chunks = body.collect().wait().unwrap();
+        assert_eq!(b"hello", &chunks[0][..]);
+        assert_eq!(0, backend.in_flight());
+    }
+
+    #[test]
+    fn test_in_flight_body_take() {
+        let backend = backends().remove(0);
+
+        let body = InFlightBody {
+            body: RefCell::new(TimedBody::from(Body::from("hello"))),
+            in_flight: RefCell::new(Some(InFlight::new(Pool::default(), backend.clone()))),
+        };
+        let taken = body.take_body();
+
+        // the request stays in flight with the body that was taken
+        drop(body);
+        assert_eq!(1, backend.in_flight());
+
+        let chunks = taken.collect().wait().unwrap();
+        assert_eq!(b"hello", &chunks[0][..]);
+        assert_eq!(0, backend.in_flight());
+    }
+
+    #[test]
+    fn test_in_flight_when_response_dropped() {
+        let backend = backends().remove(0);
+
+        let timer = RequestTimer::start(&timer(), &Timeouts::default());
+        let res = InFlight::new(Pool::default(), backend.clone())
+            .track(Response::new().with_body("hello"), timer);
+        assert_eq!(1, backend.in_flight());
+
+        drop(res);
+        assert_eq!(0, backend.in_flight());
+    }
 }
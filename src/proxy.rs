@@ -1,20 +1,36 @@
 use std::io;
 use std::net::SocketAddr;
+use std::rc::Rc;
 use std::str::{self, FromStr};
+use std::time::Instant;
 
 use net2::TcpBuilder;
 use net2::unix::UnixTcpBuilderExt;
-use futures::{future, Future, Stream};
+use futures::{future, stream, Async, Future, Sink, Stream};
+use futures::future::{Either, Loop};
 use tokio_core::reactor::{Core, Handle};
-use tokio_core::net::{TcpListener, TcpStream};
-use hyper::{self, Headers, Body, Client, HttpVersion};
-use hyper::client::{self, HttpConnector, Service};
+use tokio_core::net::TcpListener;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslAcceptorExt;
+use tokio_timer::Timer;
+use hyper::{self, Body, Chunk, Headers, HttpVersion, StatusCode};
+use hyper::client::{self, Service};
 use hyper::header;
 use hyper::server::{self, Http};
-use hyper_tls::HttpsConnector;
 use hyper::Uri;
 
-use pool::Pool;
+use config::{Http2, Retry};
+use error;
+use http2::{self, Trailers};
+use pool::{Affinity, Pool, InFlightBody};
+use router::{Router, Shadow};
+use retry::{self, Replay};
+use server::Server;
+use sticky::StickySession;
+use timeout;
+use tls::Certificates;
+use upgrade::{self, Pending};
+use upstream::Upstream;
 
 // testing here before sending PR upstream
 // TODO make this typed
@@ -93,17 +109,55 @@ pub fn filter_frontend_request_headers(headers: &Headers) -> Headers {
     let _ = h.remove::<TE>();
     let _ = h.remove::<header::TransferEncoding>();
     let _ = h.remove::<ProxyAuthorization>();
-    let _ = h.remove::<Trailer>();
     let _ = h.remove::<header::Upgrade>();
 
+    // the client accepting trailers is passed on, since gRPC servers require it
+    if accepts_trailers(headers) {
+        h.set(TE("trailers".to_owned()));
+    }
+
     h
 }
 
+/// Returns true if the TE header of a request lists `trailers`
+fn accepts_trailers(headers: &Headers) -> bool {
+    let raw = match headers.get_raw("TE") {
+        Some(raw) => raw,
+        None => return false,
+    };
+
+    raw.iter().any(|line| {
+        String::from_utf8_lossy(line)
+            .split(',')
+            .any(|te| te.trim().eq_ignore_ascii_case("trailers"))
+    })
+}
+
 /// Map a frontend request to a backend request
 ///
 /// The primary purpose of this function is to add and remove headers as required by an
 /// intermediary conforming to the HTTP spec.
 fn map_request(req: server::Request) -> client::Request {
+    let mut r = map_request_head(&req);
+    r.set_body(req.body());
+    r
+}
+
+/// Change the path of a backend request, keeping the query
+fn rewrite_path(req: &mut client::Request, path: &str) {
+    let url = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+
+    match Uri::from_str(&url) {
+        Ok(uri) => req.set_uri(uri),
+        Err(e) => error!("Unable to rewrite request path to {:?}: {}", url, e),
+    }
+}
+
+/// Map a frontend request to a backend request without a body
+fn map_request_head(req: &server::Request) -> client::Request {
     let via = create_via_header(req.headers().get::<Via>(), &req.version());
 
     let mut headers = filter_frontend_request_headers(req.headers());
@@ -112,7 +166,6 @@ fn map_request(req: server::Request) -> client::Request {
     // TODO fix clone
     let mut r = client::Request::new(req.method().clone(), req.uri().clone());
     r.headers_mut().extend(headers.iter());
-    r.set_body(req.body());
     r
 }
 
@@ -121,7 +174,6 @@ pub fn filter_backend_response_headers(headers: &Headers) -> Headers {
 
     let _ = h.remove::<header::TransferEncoding>();
     let _ = h.remove::<ProxyAuthenticate>();
-    let _ = h.remove::<Trailer>();
     let _ = h.remove::<header::Upgrade>();
 
     h
@@ -137,89 +189,594 @@ fn map_response(res: client::Response) -> server::Response {
     let headers = filter_backend_response_headers(res.headers());
     r.headers_mut().extend(headers.iter());
 
-    r.set_body(res.body());
+    // a response without a body, such as a gRPC error, must not be sent with an empty one
+    if res.body_ref().is_some() {
+        r.set_body(res.body());
+    }
     r
 }
 
 struct Proxy {
-    client: Client<HttpsConnector<HttpConnector>, Body>,
-    pool: Pool,
+    client: Upstream,
+    router: Router,
+    addr: SocketAddr,
+    timer: Timer,
+    handle: Handle,
+
+    /// The backend connection of an upgrade, once the backend agrees to it
+    pending: Pending,
+
+    /// The trailers of the request and its response, when the client speaks HTTP/2
+    ///
+    /// Each request from an HTTP/1.x client gets trailers of its own instead.
+    trailers: Option<Trailers>,
 }
 
 impl Service for Proxy {
     type Request = server::Request;
-    type Response = server::Response;
+    type Response = server::Response<InFlightBody>;
     type Error = hyper::Error;
-    type Future = Box<Future<Item = server::Response, Error = Self::Error>>;
+    type Future = Box<Future<Item = Self::Response, Error = Self::Error>>;
 
     fn call(&self, req: server::Request) -> Self::Future {
 
-        let mut client_req = map_request(req);
+        let destination = self.router.route(&req, &self.addr);
+        let pool = destination.pool;
+        let path = destination.path;
+        let shadow = destination.shadow.map(|shadow| {
+            let affinity = shadow.pool.affinity(&req, &self.addr);
+            (shadow, affinity)
+        });
+        let affinity = pool.affinity(&req, &self.addr);
+        let sticky = pool.sticky();
+        let trailers = self.trailers.clone().unwrap_or_else(Trailers::http1);
+        let mut conf = pool.retry();
+        let error_pages = pool.error_pages();
+        let upgrade = pool.upgrade();
+        let upgrading = upgrade.enabled && upgrade::is_upgrade(req.headers());
+        let grpc = error::is_grpc(req.headers());
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        let addr = self.addr;
+
+        // a request copied to a shadow pool is buffered so that it can be sent twice, but it is
+        // still sent to the pool only once
+        let mut limit = conf.buffer_body;
+        let replay = match retry::replay(&conf, req.method(), retry::body_length(&req)) {
+            Replay::Never if shadow.is_some() => {
+                conf.max_attempts = 1;
+                limit = shadow.as_ref().map_or(0, |&(ref shadow, _)| shadow.max_body);
+                Replay::Buffered
+            }
+            replay => replay,
+        };
+
+        let res: Self::Future = match replay {
+            _ if upgrading => self.upgrade(&req, path, pool, affinity, upgrade.idle_timeout),
+            Replay::Never => {
+                let mut client_req = map_request(req);
+                if let Some(ref path) = path {
+                    rewrite_path(&mut client_req, path);
+                }
+                let client = &self.client;
+                pool.request(affinity, &[], &self.timer, |server| {
+                    send(client, &pool, server, client_req, affinity, &sticky, &trailers)
+                })
+            }
+            Replay::WithoutBody => {
+                let attempt = self.attempt(&req, path, pool, affinity, sticky, trailers, conf);
+                if let Some((shadow, affinity)) = shadow {
+                    mirror(&attempt, shadow, affinity, &self.handle);
+                }
+                send_with_retries(Rc::new(attempt), Vec::new())
+            }
+            Replay::Buffered => {
+                let mut attempt =
+                    self.attempt(&req, path, pool, affinity, sticky, trailers, conf);
+                let handle = self.handle.clone();
+                let error_pages = error_pages.clone();
+                let method = method.clone();
+                let uri = uri.clone();
+
+                let work = buffer_body(req.body(), limit, &self.handle)
+                    .then(move |body| -> Self::Future {
+                        let body = match body {
+                            Ok(BufferedBody::Whole(body)) => body,
+                            Ok(BufferedBody::Streamed(body)) => {
+                                warn!(
+                                    "Sending {} {} from {} once, without retries, because its body \
+                                     is larger than {} bytes",
+                                    method,
+                                    uri,
+                                    addr,
+                                    limit
+                                );
+                                return attempt.send_once(body);
+                            }
+                            Err(e) => {
+                                // the client failed to send the request, so no backend is tried
+                                error!(
+                                    "Responding with {} to {} {} from {}: {}",
+                                    StatusCode::BadRequest,
+                                    method,
+                                    uri,
+                                    addr,
+                                    e
+                                );
+                                let res = if grpc {
+                                    error::grpc_response(StatusCode::BadRequest)
+                                } else {
+                                    error::response(&error_pages, StatusCode::BadRequest)
+                                };
+                                return Box::new(future::ok(res));
+                            }
+                        };
+
+                        // a body buffered for retries may still be too large to copy
+                        let copy = shadow.and_then(|(shadow, affinity)| {
+                            if body.len() as u64 <= shadow.max_body {
+                                Some((shadow, affinity))
+                            } else {
+                                None
+                            }
+                        });
+
+                        attempt.body = Some(body);
+                        attempt.started = Instant::now();
+                        if let Some((shadow, affinity)) = copy {
+                            mirror(&attempt, shadow, affinity, &handle);
+                        }
+                        send_with_retries(Rc::new(attempt), Vec::new())
+                    });
+
+                Box::new(work)
+            }
+        };
+
+        // answer the client instead of dropping the connection when the request fails
+        let res = res.or_else(move |e| {
+            let status = error::status(&e);
+            error!(
+                "Responding with {} to {} {} from {}: {}",
+                status,
+                method,
+                uri,
+                addr,
+                e
+            );
+            if grpc {
+                return Ok(error::grpc_response(status));
+            }
+            Ok(error::response(&error_pages, status))
+        });
 
-        self.pool.request(|server| {
+        Box::new(res)
+    }
+}
 
-            let url = format!(
-                "{}{}?{}",
-                server.url(),
-                client_req.uri().path(),
-                client_req.uri().query().unwrap_or("")
-            );
-            // TODO proper error handling
-            let uri = Uri::from_str(&url).expect("Failed to parse url");
-            let map_host = server.map_host();
-            debug!("Preparing backend request to {:?}", url);
-
-            if map_host {
-                // add host header related to backend
-                let _ = client_req.headers_mut().remove::<header::Host>();
-                let host = uri.host().unwrap().to_string();
-                let port = uri.port();
-                client_req.headers_mut().set(header::Host::new(host, port));
+impl Proxy {
+    fn new(
+        client: Upstream,
+        router: Router,
+        addr: SocketAddr,
+        timer: Timer,
+        trailers: Option<Trailers>,
+        handle: &Handle,
+    ) -> Proxy {
+        Proxy {
+            client: client,
+            router: router,
+            addr: addr,
+            timer: timer,
+            handle: handle.clone(),
+            pending: Pending::new(),
+            trailers: trailers,
+        }
+    }
+
+    /// Prepare a request that may be retried
+    fn attempt(
+        &self,
+        req: &server::Request,
+        path: Option<String>,
+        pool: Pool,
+        affinity: Affinity,
+        sticky: Option<StickySession>,
+        trailers: Trailers,
+        conf: Retry,
+    ) -> Attempt {
+        let mut head = map_request_head(req);
+        if let Some(ref path) = path {
+            rewrite_path(&mut head, path);
+        }
+
+        Attempt {
+            client: self.client.clone(),
+            pool: pool,
+            timer: self.timer.clone(),
+            affinity: affinity,
+            sticky: sticky,
+            idempotent: req.method().idempotent(),
+            head: head,
+            body: None,
+            trailers: trailers,
+            conf: conf,
+            started: Instant::now(),
+        }
+    }
+
+    /// Send a request that upgrades its connection to another protocol
+    fn upgrade(
+        &self,
+        req: &server::Request,
+        path: Option<String>,
+        pool: Pool,
+        affinity: Affinity,
+        idle_timeout: u64,
+    ) -> Box<Future<Item = server::Response<InFlightBody>, Error = hyper::Error>> {
+        let mut client_req = map_request_head(req);
+        upgrade::copy_headers(req.headers(), client_req.headers_mut());
+        if let Some(ref path) = path {
+            rewrite_path(&mut client_req, path);
+        }
+
+        let client = &self.client;
+        let pending = &self.pending;
+        let handle = &self.handle;
+        pool.request(affinity, &[], &self.timer, |server| {
+            send_upgrade(client, &pool, server, client_req, pending.clone(), idle_timeout, handle)
+        })
+    }
+}
+
+/// Send a request to a backend server of a pool
+fn send(
+    client: &Upstream,
+    pool: &Pool,
+    server: &Server,
+    mut client_req: client::Request,
+    affinity: Affinity,
+    sticky: &Option<StickySession>,
+    trailers: &Trailers,
+) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+
+    // the url of a server always ends with a slash, which the path already starts with
+    let base = server.url().to_string();
+    let base = base.trim_right_matches('/');
+    let url = match client_req.uri().query() {
+        Some(query) => format!("{}{}?{}", base, client_req.uri().path(), query),
+        None => format!("{}{}", base, client_req.uri().path()),
+    };
+    // TODO proper error handling
+    let uri = Uri::from_str(&url).expect("Failed to parse url");
+    let map_host = server.map_host();
+    debug!("Preparing backend request to {:?}", url);
+
+    if map_host {
+        // add host header related to backend
+        let _ = client_req.headers_mut().remove::<header::Host>();
+        let host = uri.host().unwrap().to_string();
+        let port = uri.port();
+        client_req.headers_mut().set(header::Host::new(host, port));
+    }
+    client_req.set_uri(uri);
+
+    // only set the sticky session cookie if the client is not already using this server
+    let sticky = match *sticky {
+        Some(ref sticky) if affinity.sticky != Some(server.id()) => {
+            Some((sticky.clone(), server.clone()))
+        }
+        _ => None,
+    };
+
+    let backend = client.request(pool, server, client_req, trailers).then(move |res| match res {
+        Ok(res) => {
+            debug!("Response: {}", res.status());
+            debug!("Headers: \n{}", res.headers());
+
+            let mut server_response = map_response(res);
+
+            if let Some((sticky, server)) = sticky {
+                sticky.set_cookie(server_response.headers_mut(), &server);
             }
-            client_req.set_uri(uri);
 
-            let backend = self.client.call(client_req).then(move |res| match res {
-                Ok(res) => {
-                    debug!("Response: {}", res.status());
-                    debug!("Headers: \n{}", res.headers());
+            ::futures::finished(server_response)
+        }
+        Err(e) => {
+            error!("Error connecting to backend: {:?}", e);
+            ::futures::failed(e)
+        }
+    });
 
-                    let server_response = map_response(res);
+    Box::new(backend)
+}
 
-                    ::futures::finished(server_response)
-                }
-                Err(e) => {
-                    error!("Error connecting to backend: {:?}", e);
-                    ::futures::failed(e)
-                }
-            });
+/// Send a request that upgrades its connection to a backend server
+///
+/// The request is sent on a connection of its own, so unlike `send` the url keeps only the path.
+fn send_upgrade(
+    client: &Upstream,
+    pool: &Pool,
+    server: &Server,
+    mut client_req: client::Request,
+    pending: Pending,
+    idle_timeout: u64,
+    handle: &Handle,
+) -> Box<Future<Item = server::Response, Error = hyper::Error>> {
+
+    let url = server.url();
+    if server.map_host() || !client_req.headers().has::<header::Host>() {
+        let _ = client_req.headers_mut().remove::<header::Host>();
+        let host = url.host().unwrap_or("").to_string();
+        client_req.headers_mut().set(header::Host::new(host, url.port()));
+    }
+    debug!("Preparing upgrade request to {:?}", url);
+
+    let res = upgrade::send(
+        client,
+        pool,
+        server,
+        client_req,
+        pending,
+        idle_timeout,
+        handle,
+    );
+    Box::new(res.map(|res| {
+        let upgrade = if res.status() == StatusCode::SwitchingProtocols {
+            Some(res.headers().clone())
+        } else {
+            None
+        };
+
+        let mut server_response = map_response(res);
+        if let Some(ref headers) = upgrade {
+            upgrade::copy_headers(headers, server_response.headers_mut());
+        }
+        server_response
+    }))
+}
+
+/// A request that can be sent to the pool more than once
+struct Attempt {
+    client: Upstream,
+    pool: Pool,
+    timer: Timer,
+    affinity: Affinity,
+    sticky: Option<StickySession>,
+    idempotent: bool,
+
+    /// The backend request without a body
+    head: client::Request,
+
+    /// The buffered body of the request, if it has one
+    body: Option<Vec<u8>>,
 
-            Box::new(backend)
+    /// The trailers of the request, sent again with each attempt, and of the response
+    trailers: Trailers,
+
+    conf: Retry,
+
+    /// When the first attempt was sent, used to enforce the retry budget
+    started: Instant,
+}
+
+impl Attempt {
+    /// Create a new backend request from the buffered request
+    fn request(&self) -> client::Request {
+        let mut r = client::Request::new(self.head.method().clone(), self.head.uri().clone());
+        r.headers_mut().extend(self.head.headers().iter());
+        if let Some(ref body) = self.body {
+            r.set_body(body.clone());
+        }
+        r
+    }
+
+    /// Send the request once, without retries, with a body that is streamed instead of buffered
+    fn send_once(
+        &self,
+        body: Body,
+    ) -> Box<Future<Item = server::Response<InFlightBody>, Error = hyper::Error>> {
+        let mut req = self.request();
+        req.set_body(body);
+        self.pool.request(self.affinity, &[], &self.timer, |server| {
+            send(
+                &self.client,
+                &self.pool,
+                server,
+                req,
+                self.affinity,
+                &self.sticky,
+                &self.trailers,
+            )
         })
     }
 }
 
+/// A request body read into memory, or the body streamed once it grew too large to buffer
+enum BufferedBody {
+    /// The whole body, which is no longer than the limit
+    Whole(Vec<u8>),
+
+    /// The part of the body read before it passed the limit, followed by the rest
+    Streamed(Body),
+}
+
+/// Read a request body into memory, up to a limit
+///
+/// Once the body passes the limit it is no longer buffered. The part already read is sent ahead
+/// of the rest of the body on a task of its own, so that the request can still be sent once.
+fn buffer_body(
+    body: Body,
+    limit: u64,
+    handle: &Handle,
+) -> Box<Future<Item = BufferedBody, Error = hyper::Error>> {
+    let handle = handle.clone();
+    let work = future::loop_fn((body, Vec::new()), move |(body, mut buf)| {
+        let handle = handle.clone();
+        body.into_future().map_err(|(e, _)| e).map(move |(chunk, body)| {
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => return Loop::Break(BufferedBody::Whole(buf)),
+            };
+
+            buf.extend(&chunk[..]);
+            if buf.len() as u64 <= limit {
+                return Loop::Continue((body, buf));
+            }
+
+            let (tx, streamed) = Body::pair();
+            let rest = stream::once(Ok(Chunk::from(buf))).chain(body).then(Ok);
+            handle.spawn(tx.send_all(rest).map(|_| ()).map_err(|_| ()));
+            Loop::Break(BufferedBody::Streamed(streamed))
+        })
+    });
+
+    Box::new(work)
+}
+
+/// Send a copy of a request to a shadow pool
+///
+/// The copy is spawned on its own so the client never waits for it. The response body is read to
+/// the end, so that the connection can be reused, and then discarded along with any error.
+fn mirror(attempt: &Attempt, shadow: Shadow, affinity: Affinity, handle: &Handle) {
+    let client = &attempt.client;
+    let req = attempt.request();
+
+    // the trailers of the response to the copy are not the client's
+    let trailers = Trailers::new();
+    if let Some(request) = attempt.trailers.request() {
+        trailers.set_request(request);
+    }
+
+    let pool = &shadow.pool;
+    let res = pool.request(affinity, &[], &attempt.timer, |server| {
+        let res = send(client, pool, server, req, affinity, &None, &trailers);
+        let res = res.and_then(|res| {
+            let status = res.status();
+            res.body().for_each(|_| Ok(())).map(move |()| {
+                server::Response::new().with_status(status)
+            })
+        });
+        Box::new(res)
+    });
+
+    handle.spawn(res.then(move |res| {
+        if let Err(e) = res {
+            debug!("Mirrored request to shadow pool failed: {}", e);
+        }
+
+        // the copy is in flight until the shadow is dropped
+        drop(shadow);
+        Ok(())
+    }));
+}
+
+/// Send a request to the pool and retry it on another backend if it fails
+///
+/// Each attempt excludes the backends already tried. A request is only retried if an untried
+/// backend is left, so that otherwise the result of the last attempt is returned.
+fn send_with_retries(
+    attempt: Rc<Attempt>,
+    mut tried: Vec<Server>,
+) -> Box<Future<Item = server::Response<InFlightBody>, Error = hyper::Error>> {
+
+    let mut server = None;
+    let res = attempt.pool.request(attempt.affinity, &tried, &attempt.timer, |s| {
+        server = Some(s.clone());
+        send(
+            &attempt.client,
+            &attempt.pool,
+            s,
+            attempt.request(),
+            attempt.affinity,
+            &attempt.sticky,
+            &attempt.trailers,
+        )
+    });
+
+    match server {
+        Some(server) => tried.push(server),
+        None => return res,
+    }
+
+    Box::new(res.then(move |res| {
+        let retryable = match res {
+            Ok(ref res) => retry::retry_status(&attempt.conf, res.status(), attempt.idempotent),
+            Err(ref e) => retry::retry_error(e, attempt.idempotent),
+        };
+
+        if retryable && (tried.len() as u64) < attempt.conf.max_attempts &&
+            retry::within_budget(&attempt.conf, attempt.started) &&
+            attempt.pool.has_backend(&tried)
+        {
+            warn!(
+                "Retrying request on another backend after attempt {} to {:?} failed",
+                tried.len(),
+                tried.last()
+            );
+            // the failed response holds its backend in flight until it is dropped
+            drop(res);
+            return send_with_retries(attempt, tried);
+        }
+
+        Box::new(future::result(res))
+    }))
+}
+
 /// Run server with default Core
-pub fn run(addr: SocketAddr, pool: Pool, core: Core) -> io::Result<()> {
+///
+/// When `tls` is set, client connections are also accepted with TLS on its address.
+pub fn run(
+    addr: SocketAddr,
+    tls: Option<(SocketAddr, Certificates)>,
+    http2: Http2,
+    router: Router,
+    core: Core,
+) -> io::Result<()> {
     let handle = core.handle();
 
+    let listener = bind(&addr, &handle)?;
+    let tls = match tls {
+        Some((addr, certificates)) => Some((bind(&addr, &handle)?, certificates)),
+        None => None,
+    };
+
+    run_with_tls(core, listener, tls, http2, router, future::empty())
+}
+
+/// Bind a listener that shares its port with the listeners of the other workers
+fn bind(addr: &SocketAddr, handle: &Handle) -> io::Result<TcpListener> {
     let listener = TcpBuilder::new_v4()?;
     listener.reuse_address(true)?;
     listener.reuse_port(true)?;
-    let listener = listener.bind(&addr)?;
+    let listener = listener.bind(addr)?;
     let listener = listener.listen(128)?;
-    let listener = TcpListener::from_listener(listener, &addr, &handle)?;
-
-    run_with(core, listener, pool, future::empty())
+    TcpListener::from_listener(listener, addr, handle)
 }
 
-/// Run server with specified Core, TcpListener, Pool
+/// Run server with specified Core, TcpListener, Router
 ///
 /// This is useful for integration testing where the port is set to 0 and the test code needs to
 /// determine the local addr.
 pub fn run_with<F>(
+    core: Core,
+    listener: TcpListener,
+    router: Router,
+    shutdown_signal: F,
+) -> io::Result<()>
+where
+    F: Future<Item = (), Error = hyper::Error>,
+{
+    run_with_tls(core, listener, None, Http2::default(), router, shutdown_signal)
+}
+
+/// Run server with specified Core, TcpListener, TLS listener, Router
+pub fn run_with_tls<F>(
     mut core: Core,
     listener: TcpListener,
-    pool: Pool,
+    tls: Option<(TcpListener, Certificates)>,
+    http2: Http2,
+    router: Router,
     shutdown_signal: F,
 ) -> io::Result<()>
 where
@@ -228,11 +785,90 @@ where
     let handle = core.handle();
 
     let local_addr = listener.local_addr()?;
+    let timer = timeout::timer();
+    let conf = router.default_pool().connection_pool();
+    let client = Upstream::new(&handle, router.clone(), timer.clone(), &conf);
+
+    let tls_srv = match tls {
+        Some((listener, certificates)) => {
+            info!("Listening on https://{}", listener.local_addr()?);
+            let router = router.clone();
+            let client = client.clone();
+            let timer = timer.clone();
+            let http2 = http2.clone();
+            let handle = handle.clone();
+            let srv = listener.incoming().for_each(move |(socket, addr)| {
+                socket.set_nodelay(true).unwrap();
+
+                let router = router.clone();
+                let client = client.clone();
+                let timer = timer.clone();
+                let http2 = http2.clone();
+                let proxy_handle = handle.clone();
+                let acceptor = certificates.acceptor();
+
+                // the handshake starts on the task of the connection, so that the connection is
+                // the one woken up once the client sends more of it
+                let accept = future::lazy(move || acceptor.accept_async(socket));
+                let work = accept.then(move |res| {
+                    match res {
+                        Ok(stream) => {
+                            let alpn = stream.get_ref().ssl().selected_alpn_protocol();
+                            let h2 = if alpn == Some(http2::ALPN_H2) {
+                                Some(&http2)
+                            } else {
+                                None
+                            };
+                            proxy(stream, addr, router, client, timer, h2, &proxy_handle)
+                        }
+                        Err(e) => debug!("TLS handshake with {} failed: {}", addr, e),
+                    }
+
+                    Ok(())
+                });
+                handle.spawn(work);
+
+                Ok(())
+            });
+            Either::A(srv)
+        }
+        None => Either::B(future::empty()),
+    };
+
     let srv = listener.incoming().for_each(move |(socket, addr)| {
-        proxy(socket, addr, pool.clone(), &handle);
+        // disable Nagle's algo
+        // https://github.com/hyperium/hyper/issues/944
+        socket.set_nodelay(true).unwrap();
+
+        if !http2.enabled {
+            proxy(socket, addr, router.clone(), client.clone(), timer.clone(), None, &handle);
+            return Ok(());
+        }
+
+        // the connection is served with HTTP/2 if the client starts with its preface
+        let router = router.clone();
+        let client = client.clone();
+        let timer = timer.clone();
+        let http2 = http2.clone();
+        let proxy_handle = handle.clone();
+        let work = http2::detect(socket).then(move |res| {
+            match res {
+                Ok((socket, true)) => {
+                    proxy(socket, addr, router, client, timer, Some(&http2), &proxy_handle)
+                }
+                Ok((socket, false)) => {
+                    proxy(socket, addr, router, client, timer, None, &proxy_handle)
+                }
+                Err(e) => debug!("Connection from {} closed: {}", addr, e),
+            }
+
+            Ok(())
+        });
+        handle.spawn(work);
 
         Ok(())
     });
+    let srv = srv.join(tls_srv).map(|_| ());
 
     info!("Listening on http://{}", &local_addr);
     match core.run(shutdown_signal.select(srv.map_err(|e| e.into()))) {
@@ -241,21 +877,59 @@ where
     }
 }
 
-fn proxy(socket: TcpStream, addr: SocketAddr, pool: Pool, handle: &Handle) {
-
-    // disable Nagle's algo
-    // https://github.com/hyperium/hyper/issues/944
-    socket.set_nodelay(true).unwrap();
-    let client = Client::configure()
-        .connector(HttpsConnector::new(4, handle).unwrap())
-        .build(&handle);
-    let service = Proxy {
-        client: client,
-        pool: pool,
-    };
+/// Serve a client connection, which may use TLS
+///
+/// The connection is served with HTTP/2 when `h2` holds its settings, and HTTP/1.x otherwise.
+fn proxy<S>(
+    socket: S,
+    addr: SocketAddr,
+    router: Router,
+    client: Upstream,
+    timer: Timer,
+    h2: Option<&Http2>,
+    handle: &Handle,
+) where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    if let Some(conf) = h2 {
+        let proxy_handle = handle.clone();
+        let new_service = move |trailers| {
+            let trailers = Some(trailers);
+            Proxy::new(client.clone(), router.clone(), addr, timer.clone(), trailers, &proxy_handle)
+        };
+        let work = http2::serve(socket, new_service, conf, handle)
+            .map_err(move |e| debug!("HTTP/2 connection from {} closed: {}", addr, e));
+        handle.spawn(work);
+        return;
+    }
+
+    let service = Proxy::new(client, router, addr, timer.clone(), None, handle);
+
+    // the connection is taken back from hyper once it is done, in case it was upgraded
+    let http = Http::<hyper::Chunk>::new();
+    let mut conn = Some(http.serve_connection(socket, service));
+    let served = future::poll_fn(move || {
+        match conn.as_mut().expect("cannot poll connection twice").poll_without_shutdown()? {
+            Async::Ready(()) => Ok(Async::Ready(conn.take().unwrap().into_parts())),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    });
 
-    let http = Http::new();
-    http.bind_connection(&handle, socket, addr, service);
+    let work = served
+        .and_then(move |parts| match parts.service.pending.take() {
+            Some(upgraded) => {
+                debug!("Tunneling upgraded connection from {}", addr);
+                let tunnel = upgrade::tunnel(parts.io, parts.read_buf.to_vec(), upgraded, &timer);
+                Either::A(tunnel.map_err(hyper::Error::from))
+            }
+            None => {
+                let shutdown = ::tokio_io::io::shutdown(parts.io);
+                Either::B(shutdown.map(|_| ()).map_err(hyper::Error::from))
+            }
+        })
+        .map_err(move |e| debug!("Connection from {} closed: {}", addr, e));
+
+    handle.spawn(work);
 }
 
 #[cfg(test)]
@@ -297,6 +971,39 @@ mod tests {
         assert_eq!(Via("1.0 proxy, 1.1 weldr".to_owned()), given);
     }
 
+    #[test]
+    fn test_buffer_body() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let body = buffer_body(Body::from("hello"), 5, &handle);
+        match core.run(body).unwrap() {
+            BufferedBody::Whole(body) => assert_eq!(b"hello", &body[..]),
+            BufferedBody::Streamed(_) => panic!("expected the whole body"),
+        }
+
+        // the body past the limit is still sent in full
+        let (tx, body) = Body::pair();
+        let sent = tx.send(Ok(Chunk::from("hello")))
+            .and_then(|tx| tx.send(Ok(Chunk::from(" world"))));
+        handle.spawn(sent.map(|_| ()).map_err(|_| ()));
+        let body = match core.run(buffer_body(body, 8, &handle)).unwrap() {
+            BufferedBody::Streamed(body) => body,
+            BufferedBody::Whole(_) => panic!("expected a streamed body"),
+        };
+        let body = core.run(body.concat2()).unwrap();
+        assert_eq!(b"hello world", &body[..]);
+    }
+
+    #[test]
+    fn test_rewrite_path() {
+        let uri = Uri::from_str("/api/users?page=2").unwrap();
+        let mut req = client::Request::new(hyper::Method::Get, uri);
+        rewrite_path(&mut req, "/users");
+        assert_eq!("/users", req.uri().path());
+        assert_eq!(Some("page=2"), req.uri().query());
+    }
+
     #[test]
     /// Per RFC 2616 Section 13.5.1 - MUST remove hop-by-hop headers
     /// Per RFC 7230 Section 6.1 - MUST remove Connection and Connection option headers
@@ -334,8 +1041,13 @@ mod tests {
         assert_eq!(false, given.has::<Foo>());
         assert_eq!(false, given.has::<KeepAlive>());
         assert_eq!(false, given.has::<ProxyAuthorization>());
-        assert_eq!(false, given.has::<Trailer>());
+        assert_eq!(true, given.has::<Trailer>());
         assert_eq!(false, given.has::<header::Upgrade>());
+
+        // gRPC servers require the client to accept trailers
+        headers.set_raw("TE", "gzip, Trailers");
+        let given = filter_frontend_request_headers(&headers);
+        assert_eq!(Some(&TE("trailers".to_owned())), given.get::<TE>());
     }
 
     #[test]
@@ -361,7 +1073,7 @@ mod tests {
         assert_eq!(false, given.has::<header::TransferEncoding>());
         assert_eq!(true, given.has::<header::Host>());
         assert_eq!(false, given.has::<ProxyAuthenticate>());
-        assert_eq!(false, given.has::<Trailer>());
+        assert_eq!(true, given.has::<Trailer>());
         assert_eq!(false, given.has::<header::Upgrade>());
     }
 }
@@ -0,0 +1,158 @@
+//! Retrying failed requests on another backend
+//!
+//! A request can only be retried if it can be sent again. Idempotent requests without a body are
+//! always retried. Requests with a small body, including non-idempotent ones, are retried only if
+//! body buffering is enabled, because the body has to be held in memory to send it again.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use hyper::{self, Method, StatusCode};
+use hyper::header::{ContentLength, TransferEncoding};
+use hyper::server;
+
+use config::Retry;
+
+/// How a request is sent again when it is retried
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Replay {
+    /// The request cannot be retried
+    Never,
+
+    /// The request has no body, so it can be sent again as is
+    WithoutBody,
+
+    /// The request body must be buffered before the first attempt
+    Buffered,
+}
+
+/// Returns the length of the body of a request, or `None` if it is not known before it is read
+///
+/// A request without a body has a length of zero. A body without a `Content-Length`, such as a
+/// chunked body or the body of an HTTP/2 request, has an unknown length.
+pub fn body_length(req: &server::Request) -> Option<u64> {
+    match req.headers().get::<ContentLength>() {
+        _ if req.headers().has::<TransferEncoding>() => None,
+        Some(&ContentLength(length)) => Some(length),
+        None if req.body_ref().is_none() => Some(0),
+        None => None,
+    }
+}
+
+/// Decide whether a request can be retried from its method and the length of its body
+///
+/// A body of unknown length is never buffered.
+pub fn replay(conf: &Retry, method: &Method, length: Option<u64>) -> Replay {
+    let length = match length {
+        Some(length) if conf.max_attempts > 1 => length,
+        _ => return Replay::Never,
+    };
+
+    if length == 0 && method.idempotent() {
+        Replay::WithoutBody
+    } else if conf.buffer_body > 0 && length <= conf.buffer_body {
+        Replay::Buffered
+    } else {
+        Replay::Never
+    }
+}
+
+/// Returns true if a response with this status should be retried
+///
+/// A non-idempotent request is never retried once the backend has responded, because the backend
+/// may have acted on the request even though the response is an error.
+pub fn retry_status(conf: &Retry, status: StatusCode, idempotent: bool) -> bool {
+    idempotent && conf.statuses.contains(&u16::from(status))
+}
+
+/// Returns true if a request that failed with this error should be retried
+///
+/// A non-idempotent request is only retried if the connection to the backend could not be made,
+/// because otherwise the backend may have already acted on the request.
+pub fn retry_error(e: &hyper::Error, idempotent: bool) -> bool {
+    match *e {
+        hyper::Error::Io(ref e) => {
+            idempotent ||
+                match e.kind() {
+                    io::ErrorKind::ConnectionRefused |
+                    io::ErrorKind::AddrNotAvailable |
+                    io::ErrorKind::NotConnected => true,
+                    _ => false,
+                }
+        }
+        _ => false,
+    }
+}
+
+/// Returns true if there is time left in the retry budget of a request
+pub fn within_budget(conf: &Retry, started: Instant) -> bool {
+    started.elapsed() < Duration::from_millis(conf.budget)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{body_length, replay, retry_error, retry_status, Replay};
+    use config::Retry;
+    use hyper::{self, Body, Method, StatusCode};
+    use hyper::header::{ContentLength, Encoding, TransferEncoding};
+    use hyper::server::Request;
+    use std::io;
+
+    #[test]
+    fn test_body_length() {
+        let mut req = Request::new(Method::Get, "/".parse().unwrap());
+        assert_eq!(Some(0), body_length(&req));
+
+        // a body of an HTTP/2 request has no length unless the client sends one
+        req.set_body(Body::from("hello"));
+        assert_eq!(None, body_length(&req));
+        req.headers_mut().set(ContentLength(5));
+        assert_eq!(Some(5), body_length(&req));
+
+        req.headers_mut().set(TransferEncoding(vec![Encoding::Chunked]));
+        assert_eq!(None, body_length(&req));
+    }
+
+    #[test]
+    fn test_replay_idempotent() {
+        let conf = Retry::default();
+        assert_eq!(Replay::WithoutBody, replay(&conf, &Method::Get, Some(0)));
+        assert_eq!(Replay::WithoutBody, replay(&conf, &Method::Delete, Some(0)));
+        assert_eq!(Replay::Never, replay(&conf, &Method::Post, Some(0)));
+        assert_eq!(Replay::Never, replay(&conf, &Method::Put, Some(10)));
+        assert_eq!(Replay::Never, replay(&conf, &Method::Put, None));
+    }
+
+    #[test]
+    fn test_replay_buffered() {
+        let mut conf = Retry::default();
+        conf.buffer_body = 1024;
+        assert_eq!(Replay::Buffered, replay(&conf, &Method::Post, Some(10)));
+        assert_eq!(Replay::Buffered, replay(&conf, &Method::Put, Some(1024)));
+        assert_eq!(Replay::Never, replay(&conf, &Method::Post, Some(1025)));
+        assert_eq!(Replay::Never, replay(&conf, &Method::Post, None));
+
+        conf.max_attempts = 1;
+        assert_eq!(Replay::Never, replay(&conf, &Method::Get, Some(0)));
+    }
+
+    #[test]
+    fn test_retry_status() {
+        let conf = Retry::default();
+        assert!(retry_status(&conf, StatusCode::BadGateway, true));
+        assert!(!retry_status(&conf, StatusCode::BadGateway, false));
+        assert!(!retry_status(&conf, StatusCode::InternalServerError, true));
+        assert!(!retry_status(&conf, StatusCode::Ok, true));
+    }
+
+    #[test]
+    fn test_retry_error() {
+        let refused = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        let reset = hyper::Error::Io(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+
+        assert!(retry_error(&refused, true));
+        assert!(retry_error(&refused, false));
+        assert!(retry_error(&reset, true));
+        assert!(!retry_error(&reset, false));
+    }
+}
@@ -0,0 +1,902 @@
+//! Routing requests to one of several named pools
+//!
+//! Each pool has a name and a list of hosts. A request is sent to the pool with a host that
+//! matches the `Host` header of the request. An exact host is preferred over a wildcard host, and a
+//! longer wildcard over a shorter one. A request that matches no host is sent to the default pool.
+//!
+//! Routes are checked before the hosts, in order. A route matches on the path of a request, and
+//! optionally its method, headers and query parameters. The first route that matches sends the
+//! request to the pool of the route, and may rewrite the path sent to the backend.
+//!
+//! A route may split its requests, sending a percentage of them to another pool, such as a canary
+//! release. The split is either random for each request or decided by hashing part of the
+//! request, so that a client stays on one side of the split.
+//!
+//! A route may also mirror a sample of its requests to a shadow pool. The copy is sent alongside
+//! the request and its response is discarded, so the shadow pool never affects the client.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::{self, FromStr};
+
+use hyper::{server, Method};
+use hyper::header;
+use rand::{self, Rng};
+use regex::Regex;
+
+use hash::HashKey;
+use pool::Pool;
+use retry;
+
+/// The name of the pool that gets the requests that match no other pool
+pub const DEFAULT_POOL: &'static str = "default";
+
+/// The default number of copies of requests that a mirror may have in flight in each worker
+pub const DEFAULT_MIRROR_MAX_IN_FLIGHT: u64 = 100;
+
+/// The default largest request body (in bytes) that is copied by a mirror
+pub const DEFAULT_MIRROR_MAX_BODY: u64 = 64 * 1024;
+
+/// A host matched against the `Host` header of a request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Host {
+    /// Matches this host only
+    Exact(String),
+
+    /// Matches any subdomain of this domain, written as `*.example.com`
+    Wildcard(String),
+}
+
+impl Host {
+    /// Returns true if the host of a request matches
+    ///
+    /// The host of the request must be lowercase and without a port.
+    pub fn matches(&self, host: &str) -> bool {
+        match *self {
+            Host::Exact(ref h) => h == host,
+            Host::Wildcard(ref domain) => {
+                host.len() > domain.len() + 1 && host.ends_with(domain.as_str()) &&
+                    host[..host.len() - domain.len()].ends_with('.')
+            }
+        }
+    }
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Host, String> {
+        let s = s.trim().to_lowercase();
+        if s.starts_with("*.") && s.len() > 2 {
+            Ok(Host::Wildcard(s[2..].to_string()))
+        } else if s.is_empty() || s.contains('*') || s.contains(':') || s.contains('/') {
+            Err(format!("invalid host: {}", s))
+        } else {
+            Ok(Host::Exact(s))
+        }
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Host::Exact(ref host) => f.write_str(host),
+            Host::Wildcard(ref domain) => write!(f, "*.{}", domain),
+        }
+    }
+}
+
+/// How a route matches the path of a request
+#[derive(Clone, Debug)]
+pub enum PathMatch {
+    /// Matches this path and any path below it, so `/api` matches `/api/users` but not `/apis`
+    Prefix(String),
+
+    /// Matches any path the regex matches
+    Regex(Regex),
+}
+
+impl PathMatch {
+    /// Create a match from a regex
+    pub fn regex(re: &str) -> Result<PathMatch, String> {
+        Regex::new(re).map(PathMatch::Regex).map_err(
+            |e| format!("invalid regex: {}", e),
+        )
+    }
+
+    /// Returns the start and end of the part of the path that matches
+    fn find(&self, path: &str) -> Option<(usize, usize)> {
+        match *self {
+            PathMatch::Prefix(ref prefix) => {
+                if !path.starts_with(prefix.as_str()) {
+                    return None;
+                }
+
+                let below = prefix.ends_with('/') || path.len() == prefix.len() ||
+                    path[prefix.len()..].starts_with('/');
+                if below {
+                    Some((0, prefix.len()))
+                } else {
+                    None
+                }
+            }
+            PathMatch::Regex(ref re) => re.find(path).map(|m| (m.start(), m.end())),
+        }
+    }
+}
+
+impl fmt::Display for PathMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathMatch::Prefix(ref prefix) => f.write_str(prefix),
+            PathMatch::Regex(ref re) => f.write_str(re.as_str()),
+        }
+    }
+}
+
+/// A header or query parameter whose value must match a regex
+#[derive(Clone, Debug)]
+pub struct Predicate {
+    pub name: String,
+    pub value: Regex,
+}
+
+impl Predicate {
+    pub fn new(name: &str, value: &str) -> Result<Predicate, String> {
+        let value = Regex::new(value).map_err(|e| {
+            format!("invalid regex for {}: {}", name, e)
+        })?;
+
+        Ok(Predicate {
+            name: name.to_string(),
+            value: value,
+        })
+    }
+
+    /// Returns true if any of the values matches
+    fn matches<'a, I: IntoIterator<Item = &'a str>>(&self, values: I) -> bool {
+        values.into_iter().any(|v| self.value.is_match(v))
+    }
+}
+
+/// Sends a percentage of the requests that match a route to another pool
+#[derive(Clone, Debug)]
+pub struct Split {
+    /// The name of the pool that gets the share of the requests
+    pub pool: String,
+
+    /// The percentage of requests sent to the pool, from 0 to 100
+    pub percent: u8,
+
+    /// The part of the request hashed to decide the side of the split
+    ///
+    /// A request without the key, or any request if there is no key, is split at random.
+    pub key: Option<HashKey>,
+}
+
+impl Split {
+    /// Returns true if the request is sent to the pool of the split
+    ///
+    /// A client with the same key stays on the same side of the split. Raising the percentage only
+    /// moves clients to the pool of the split, never back.
+    pub fn selects(&self, req: &server::Request, addr: &SocketAddr) -> bool {
+        let hash = self.key.as_ref().and_then(|key| key.hash(req, addr));
+        let bucket = match hash {
+            Some(hash) => hash % 100,
+            None => rand::thread_rng().gen_range(0, 100),
+        };
+
+        bucket < self.percent as u64
+    }
+}
+
+/// Copies a percentage of the requests that match a route to a shadow pool
+///
+/// A request is only copied if its body is small enough to be buffered, and while the shadow pool
+/// has fewer than `max_in_flight` copies in flight. The copies in flight are counted per shadow
+/// pool, so that the count survives replacing the routes.
+#[derive(Clone, Debug)]
+pub struct Mirror {
+    /// The name of the shadow pool
+    pub pool: String,
+
+    /// The percentage of requests copied, from 0 to 100
+    pub percent: u8,
+
+    /// The most copies in flight at once
+    pub max_in_flight: u64,
+
+    /// The largest request body (in bytes) that is copied
+    pub max_body: u64,
+
+    in_flight: Rc<Cell<u64>>,
+}
+
+impl Mirror {
+    pub fn new(pool: &str, percent: u8, max_in_flight: u64, max_body: u64) -> Mirror {
+        Mirror {
+            pool: pool.to_string(),
+            percent: percent,
+            max_in_flight: max_in_flight,
+            max_body: max_body,
+            in_flight: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Returns the number of copies in flight
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.get()
+    }
+
+    /// Returns true if a request is copied
+    fn sample(&self, req: &server::Request) -> bool {
+        if self.in_flight.get() >= self.max_in_flight {
+            return false;
+        }
+
+        // a body of unknown length is never copied
+        let length = match retry::body_length(req) {
+            Some(length) => length,
+            None => return false,
+        };
+
+        length <= self.max_body && rand::thread_rng().gen_range(0, 100) < self.percent as u64
+    }
+}
+
+/// A copy of a request sent to a shadow pool
+///
+/// The copy is counted in flight by its mirror until this is dropped.
+#[derive(Debug)]
+pub struct Shadow {
+    pub pool: Pool,
+
+    /// The largest body, in bytes, of a request that is copied
+    pub max_body: u64,
+
+    in_flight: Rc<Cell<u64>>,
+}
+
+impl Shadow {
+    fn new(pool: Pool, mirror: &Mirror) -> Shadow {
+        mirror.in_flight.set(mirror.in_flight.get() + 1);
+        Shadow {
+            pool: pool,
+            max_body: mirror.max_body,
+            in_flight: mirror.in_flight.clone(),
+        }
+    }
+}
+
+impl Drop for Shadow {
+    fn drop(&mut self) {
+        self.in_flight.set(self.in_flight.get() - 1);
+    }
+}
+
+/// A rule that sends the requests that match it to a pool
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// The name of the pool the requests are sent to
+    pub pool: String,
+
+    pub path: PathMatch,
+
+    /// The methods the route matches, or any method if empty
+    pub methods: Vec<Method>,
+
+    /// The headers a request must have, with a value that matches
+    pub headers: Vec<Predicate>,
+
+    /// The query parameters a request must have, with a value that matches
+    ///
+    /// The value is matched as it appears in the query, without decoding it.
+    pub query: Vec<Predicate>,
+
+    /// Replaces the part of the path that matched, so an empty rewrite strips it
+    ///
+    /// A regex rewrite may refer to the groups of the regex, such as `$1`.
+    pub rewrite: Option<String>,
+
+    pub split: Option<Split>,
+
+    pub mirror: Option<Mirror>,
+}
+
+impl Route {
+    /// Create a route that sends every request to a pool
+    pub fn new(pool: &str) -> Route {
+        Route {
+            pool: pool.to_string(),
+            path: PathMatch::Prefix("/".to_string()),
+            methods: Vec::new(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            rewrite: None,
+            split: None,
+            mirror: None,
+        }
+    }
+
+    /// Returns the path to send to the backend if the route matches a request
+    pub fn matches_request(&self, req: &server::Request) -> Option<String> {
+        if !self.methods.is_empty() && !self.methods.contains(req.method()) {
+            return None;
+        }
+
+        let headers = self.headers.iter().all(|p| match req.headers().get_raw(&p.name) {
+            Some(raw) => p.matches(raw.iter().filter_map(|v| str::from_utf8(v).ok())),
+            None => false,
+        });
+        if !headers {
+            return None;
+        }
+
+        let query = req.query().unwrap_or("");
+        let params = self.query.iter().all(|p| {
+            p.matches(query.split('&').filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), v) if k == p.name => Some(v.unwrap_or("")),
+                    _ => None,
+                }
+            }))
+        });
+        if !params {
+            return None;
+        }
+
+        self.matches(req.path())
+    }
+
+    /// Returns the path to send to the backend if the route matches the path of a request
+    pub fn matches(&self, path: &str) -> Option<String> {
+        let (start, end) = match self.path.find(path) {
+            Some(found) => found,
+            None => return None,
+        };
+        let rewrite = match self.rewrite {
+            Some(ref rewrite) => rewrite,
+            None => return Some(path.to_string()),
+        };
+
+        let replaced = match self.path {
+            PathMatch::Regex(ref re) => {
+                let mut replaced = String::new();
+                if let Some(caps) = re.captures(path) {
+                    caps.expand(rewrite, &mut replaced);
+                }
+                replaced
+            }
+            PathMatch::Prefix(_) => rewrite.clone(),
+        };
+
+        let mut rest = &path[end..];
+        if replaced.ends_with('/') && rest.starts_with('/') {
+            rest = &rest[1..];
+        }
+
+        let rewritten = format!("{}{}{}", &path[..start], replaced, rest);
+        if rewritten.starts_with('/') {
+            Some(rewritten)
+        } else {
+            Some(format!("/{}", rewritten))
+        }
+    }
+}
+
+/// The pool a request is sent to
+#[derive(Debug)]
+pub struct Destination {
+    pub pool: Pool,
+
+    /// The path to send to the backend if a route rewrote it
+    pub path: Option<String>,
+
+    /// The shadow pool a copy of the request is sent to
+    pub shadow: Option<Shadow>,
+}
+
+/// The pools of a process and the hosts routed to each of them
+#[derive(Clone, Debug)]
+pub struct Router {
+    inner: Rc<RefCell<InnerRouter>>,
+}
+
+#[derive(Debug)]
+struct InnerRouter {
+    /// The pools keyed by name, which always include the default pool
+    pools: BTreeMap<String, Pool>,
+
+    /// The hosts of each pool keyed by pool name
+    hosts: BTreeMap<String, Vec<Host>>,
+
+    /// The routes checked before the hosts, in order
+    routes: Vec<Route>,
+
+    /// The copies in flight to each shadow pool, keyed by pool name
+    mirrors: BTreeMap<String, Rc<Cell<u64>>>,
+}
+
+impl InnerRouter {
+    /// Make the mirror of a route count its copies with those already in flight to its pool
+    fn share_in_flight(&mut self, route: &mut Route) {
+        if let Some(ref mut mirror) = route.mirror {
+            let in_flight = self.mirrors
+                .entry(mirror.pool.clone())
+                .or_insert_with(|| mirror.in_flight.clone());
+            mirror.in_flight = in_flight.clone();
+        }
+    }
+}
+
+impl Router {
+    /// Create a router that sends every request to the default pool
+    pub fn new(default: Pool) -> Router {
+        default.set_name(DEFAULT_POOL);
+        let mut pools = BTreeMap::new();
+        pools.insert(DEFAULT_POOL.to_string(), default);
+
+        Router {
+            inner: Rc::new(RefCell::new(InnerRouter {
+                pools: pools,
+                hosts: BTreeMap::new(),
+                routes: Vec::new(),
+                mirrors: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Returns the pool that gets the requests that match no other pool
+    pub fn default_pool(&self) -> Pool {
+        self.inner.borrow().pools[DEFAULT_POOL].clone()
+    }
+
+    /// Returns the pool with this name
+    pub fn pool(&self, name: &str) -> Option<Pool> {
+        self.inner.borrow().pools.get(name).cloned()
+    }
+
+    /// Returns all pools keyed by name
+    pub fn pools(&self) -> BTreeMap<String, Pool> {
+        self.inner.borrow().pools.clone()
+    }
+
+    /// Returns the hosts routed to a pool
+    pub fn hosts(&self, name: &str) -> Vec<Host> {
+        self.inner
+            .borrow()
+            .hosts
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Add a pool, or change the hosts of a pool that already exists
+    ///
+    /// A new pool has the same settings as the default pool. Returns the pool.
+    pub fn add_pool(&self, name: &str, hosts: Vec<Host>) -> Pool {
+        let mut inner = self.inner.borrow_mut();
+        let pool = match inner.pools.get(name) {
+            Some(pool) => pool.clone(),
+            None => inner.pools[DEFAULT_POOL].with_settings(),
+        };
+        pool.set_name(name);
+
+        inner.pools.insert(name.to_string(), pool.clone());
+        inner.hosts.insert(name.to_string(), hosts);
+        pool
+    }
+
+    /// Remove a pool and the hosts routed to it
+    ///
+    /// The default pool cannot be removed. Returns false if there is no pool to remove.
+    pub fn remove_pool(&self, name: &str) -> bool {
+        if name == DEFAULT_POOL {
+            return false;
+        }
+
+        let mut inner = self.inner.borrow_mut();
+        inner.hosts.remove(name);
+        inner.pools.remove(name).is_some()
+    }
+
+    /// Returns the routes in the order they are checked
+    pub fn routes(&self) -> Vec<Route> {
+        self.inner.borrow().routes.clone()
+    }
+
+    /// Add a route that is checked after the routes already added
+    pub fn add_route(&self, mut route: Route) {
+        let mut inner = self.inner.borrow_mut();
+        inner.share_in_flight(&mut route);
+        inner.routes.push(route);
+    }
+
+    /// Replace all routes
+    pub fn set_routes(&self, mut routes: Vec<Route>) {
+        let mut inner = self.inner.borrow_mut();
+        for route in routes.iter_mut() {
+            inner.share_in_flight(route);
+        }
+        inner.routes = routes;
+    }
+
+    /// Change or remove the split of the route at this index
+    ///
+    /// Returns false if there is no route at the index.
+    pub fn set_split(&self, index: usize, split: Option<Split>) -> bool {
+        match self.inner.borrow_mut().routes.get_mut(index) {
+            Some(route) => {
+                route.split = split;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Select the pool for a request from the routes, falling back to its `Host` header
+    pub fn route(&self, req: &server::Request, addr: &SocketAddr) -> Destination {
+        {
+            let inner = self.inner.borrow();
+            for route in &inner.routes {
+                // a route to a pool that was removed is skipped
+                let pool = match inner.pools.get(&route.pool) {
+                    Some(pool) => pool,
+                    None => continue,
+                };
+
+                if let Some(rewritten) = route.matches_request(req) {
+                    // a split to a pool that was removed sends every request to the route pool
+                    let pool = match route.split {
+                        Some(ref split) if split.selects(req, addr) => {
+                            inner.pools.get(&split.pool).unwrap_or(pool)
+                        }
+                        _ => pool,
+                    };
+
+                    let shadow = route.mirror.as_ref().and_then(|mirror| {
+                        match inner.pools.get(&mirror.pool) {
+                            Some(shadow) if mirror.sample(req) => {
+                                Some(Shadow::new(shadow.clone(), mirror))
+                            }
+                            _ => None,
+                        }
+                    });
+
+                    return Destination {
+                        pool: pool.clone(),
+                        path: route.rewrite.as_ref().map(|_| rewritten),
+                        shadow: shadow,
+                    };
+                }
+            }
+        }
+
+        let host = match req.headers().get::<header::Host>() {
+            Some(host) => Some(host.hostname().to_string()),
+            None => req.uri().host().map(|h| h.to_string()),
+        };
+
+        Destination {
+            pool: self.route_host(host.as_ref().map(|h| h.as_str())),
+            path: None,
+            shadow: None,
+        }
+    }
+
+    /// Select the pool for a host
+    fn route_host(&self, host: Option<&str>) -> Pool {
+        let inner = self.inner.borrow();
+        let host = match host {
+            Some(host) => host.to_lowercase(),
+            None => return inner.pools[DEFAULT_POOL].clone(),
+        };
+
+        let mut best: Option<(&String, &Host)> = None;
+        for (name, hosts) in &inner.hosts {
+            for h in hosts.iter().filter(|h| h.matches(&host)) {
+                let better = match (best.map(|b| b.1), h) {
+                    (None, _) => true,
+                    (Some(&Host::Wildcard(_)), &Host::Exact(_)) => true,
+                    (Some(&Host::Wildcard(ref a)), &Host::Wildcard(ref b)) => b.len() > a.len(),
+                    _ => false,
+                };
+                if better {
+                    best = Some((name, h));
+                }
+            }
+        }
+
+        let name = best.map(|b| b.0.as_str()).unwrap_or(DEFAULT_POOL);
+        inner.pools[name].clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Host, Mirror, PathMatch, Predicate, Route, Router, Split, DEFAULT_POOL};
+    use hash::HashKey;
+    use std::net::SocketAddr;
+    use hyper::{server, Body, Method};
+    use hyper::header;
+    use pool::Pool;
+    use server::Server;
+    use std::str::FromStr;
+
+    fn hosts(hosts: &[&str]) -> Vec<Host> {
+        hosts.iter().map(|h| Host::from_str(h).unwrap()).collect()
+    }
+
+    /// Add a pool with a server named after the pool, so that the pool can be told apart
+    fn add_pool(router: &Router, name: &str, h: &[&str]) {
+        let pool = router.add_pool(name, hosts(h));
+        pool.add(server(name));
+    }
+
+    fn server(name: &str) -> Server {
+        Server::new(FromStr::from_str(&format!("http://{}.test", name)).unwrap(), false)
+    }
+
+    fn pool_of(router: &Router, host: &str) -> String {
+        let pool = router.route_host(Some(host));
+        match pool.all().first() {
+            Some(backend) => backend.server().url().host().unwrap().replace(".test", ""),
+            None => DEFAULT_POOL.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_host_from_str() {
+        assert_eq!(Host::Exact("example.com".to_string()), Host::from_str("Example.com").unwrap());
+        assert_eq!(
+            Host::Wildcard("example.com".to_string()),
+            Host::from_str("*.example.com").unwrap()
+        );
+        assert!(Host::from_str("").is_err());
+        assert!(Host::from_str("*").is_err());
+        assert!(Host::from_str("example.com:8080").is_err());
+        assert_eq!("*.example.com", Host::from_str("*.example.com").unwrap().to_string());
+    }
+
+    #[test]
+    fn test_host_matches() {
+        let wildcard = Host::from_str("*.example.com").unwrap();
+        assert!(wildcard.matches("www.example.com"));
+        assert!(wildcard.matches("a.b.example.com"));
+        assert!(!wildcard.matches("example.com"));
+        assert!(!wildcard.matches("badexample.com"));
+
+        let exact = Host::from_str("example.com").unwrap();
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+    }
+
+    #[test]
+    fn test_route() {
+        let router = Router::new(Pool::default());
+        add_pool(&router, "api", &["api.example.com"]);
+        add_pool(&router, "web", &["*.example.com", "example.com"]);
+        add_pool(&router, "admin", &["*.admin.example.com"]);
+
+        assert_eq!("api", pool_of(&router, "api.example.com"));
+        assert_eq!("api", pool_of(&router, "API.example.com"));
+        assert_eq!("web", pool_of(&router, "www.example.com"));
+        assert_eq!("web", pool_of(&router, "example.com"));
+        assert_eq!("admin", pool_of(&router, "eu.admin.example.com"));
+        assert_eq!(DEFAULT_POOL, pool_of(&router, "example.org"));
+
+        assert!(router.remove_pool("api"));
+        assert_eq!("web", pool_of(&router, "api.example.com"));
+        assert!(!router.remove_pool(DEFAULT_POOL));
+    }
+
+    fn route(pool: &str, path: PathMatch, rewrite: Option<&str>) -> Route {
+        let mut route = Route::new(pool);
+        route.path = path;
+        route.rewrite = rewrite.map(|r| r.to_string());
+        route
+    }
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    fn request(method: Method, host: Option<&str>, path: &str) -> server::Request {
+        let mut req = server::Request::new(method, FromStr::from_str(path).unwrap());
+        if let Some(host) = host {
+            req.headers_mut().set(header::Host::new(host.to_string(), None));
+        }
+        req
+    }
+
+    fn prefix(prefix: &str) -> PathMatch {
+        PathMatch::Prefix(prefix.to_string())
+    }
+
+    #[test]
+    fn test_prefix_route() {
+        let keep = route("api", prefix("/api"), None);
+        assert_eq!(Some("/api".to_string()), keep.matches("/api"));
+        assert_eq!(Some("/api/users".to_string()), keep.matches("/api/users"));
+        assert_eq!(None, keep.matches("/apis"));
+        assert_eq!(None, keep.matches("/static/api"));
+        assert_eq!(None, keep.matches("/"));
+        assert_eq!(None, keep.matches("/a"));
+
+        let strip = route("api", prefix("/api"), Some(""));
+        assert_eq!(Some("/".to_string()), strip.matches("/api"));
+        assert_eq!(Some("/users".to_string()), strip.matches("/api/users"));
+
+        let rewrite = route("api", prefix("/api/"), Some("/v2/"));
+        assert_eq!(Some("/v2/users".to_string()), rewrite.matches("/api/users"));
+    }
+
+    #[test]
+    fn test_regex_route() {
+        let r = route("assets", PathMatch::regex(r"^/(css|js)/").unwrap(), Some("/static/$1/"));
+        assert_eq!(Some("/static/css/site.css".to_string()), r.matches("/css/site.css"));
+        assert_eq!(None, r.matches("/img/logo.png"));
+        assert!(PathMatch::regex("(").is_err());
+    }
+
+    #[test]
+    fn test_route_before_host() {
+        let router = Router::new(Pool::default());
+        add_pool(&router, "web", &["example.com"]);
+        add_pool(&router, "api", &[]);
+        router.add_route(route("api", prefix("/api"), Some("")));
+        router.add_route(route("gone", prefix("/gone"), None));
+
+        let req = request(Method::Get, Some("example.com"), "/api/users");
+        let dest = router.route(&req, &addr());
+        assert_eq!(Some("/users".to_string()), dest.path);
+        assert!(dest.pool.find(&server("api")).is_some());
+
+        let req = request(Method::Get, Some("example.com"), "/index.html");
+        let dest = router.route(&req, &addr());
+        assert_eq!(None, dest.path);
+        assert!(dest.pool.find(&server("web")).is_some());
+
+        // the pool of the route does not exist
+        let dest = router.route(&request(Method::Get, None, "/gone"), &addr());
+        assert!(dest.pool.all().is_empty());
+    }
+
+    #[test]
+    fn test_route_predicates() {
+        let mut v2 = Route::new("v2");
+        v2.headers.push(Predicate::new("X-Api-Version", "^2").unwrap());
+        let mut beta = Route::new("beta");
+        beta.query.push(Predicate::new("beta", "^(1|true)$").unwrap());
+        let mut writes = Route::new("writes");
+        writes.methods = vec![Method::Post, Method::Put];
+
+        let mut req = request(Method::Get, None, "/users");
+        assert!(v2.matches_request(&req).is_none());
+        req.headers_mut().set_raw("X-Api-Version", "2.1");
+        assert!(v2.matches_request(&req).is_some());
+        req.headers_mut().set_raw("X-Api-Version", "1.9");
+        assert!(v2.matches_request(&req).is_none());
+
+        assert!(beta.matches_request(&request(Method::Get, None, "/?a=b&beta=1")).is_some());
+        assert!(beta.matches_request(&request(Method::Get, None, "/?beta=0")).is_none());
+        assert!(beta.matches_request(&request(Method::Get, None, "/?notbeta=1")).is_none());
+
+        assert!(writes.matches_request(&request(Method::Post, None, "/")).is_some());
+        assert!(writes.matches_request(&request(Method::Get, None, "/")).is_none());
+        assert!(Predicate::new("X-Api-Version", "(").is_err());
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let router = Router::new(Pool::default());
+        add_pool(&router, "v2", &[]);
+        add_pool(&router, "fallback", &[]);
+        let mut v2 = Route::new("v2");
+        v2.headers.push(Predicate::new("X-Api-Version", "^2").unwrap());
+        router.set_routes(vec![v2, Route::new("fallback")]);
+
+        let mut req = request(Method::Get, None, "/users");
+        req.headers_mut().set_raw("X-Api-Version", "2");
+        assert!(router.route(&req, &addr()).pool.find(&server("v2")).is_some());
+
+        let req = request(Method::Get, None, "/users");
+        assert!(router.route(&req, &addr()).pool.find(&server("fallback")).is_some());
+    }
+
+    #[test]
+    fn test_split() {
+        let router = Router::new(Pool::default());
+        add_pool(&router, "stable", &[]);
+        add_pool(&router, "canary", &[]);
+        let mut route = Route::new("stable");
+        route.split = Some(Split {
+            pool: "canary".to_string(),
+            percent: 0,
+            key: Some(HashKey::Cookie("user".to_string())),
+        });
+        router.set_routes(vec![route.clone()]);
+
+        let side = |router: &Router, user: u32| {
+            let mut req = request(Method::Get, None, "/");
+            req.headers_mut().set_raw("Cookie", format!("user={}", user));
+            let pool = router.route(&req, &addr()).pool;
+            pool.find(&server("canary")).is_some()
+        };
+
+        assert!((0..100).all(|user| !side(&router, user)));
+
+        route.split.as_mut().unwrap().percent = 30;
+        router.set_routes(vec![route.clone()]);
+        let canary: Vec<u32> = (0..1000).filter(|&user| side(&router, user)).collect();
+        assert!(canary.len() > 200 && canary.len() < 400);
+
+        // a user stays on the canary as the percentage grows
+        route.split.as_mut().unwrap().percent = 50;
+        router.set_routes(vec![route.clone()]);
+        assert!(canary.iter().all(|&user| side(&router, user)));
+
+        route.split.as_mut().unwrap().percent = 100;
+        router.set_routes(vec![route]);
+        assert!((0..100).all(|user| side(&router, user)));
+    }
+
+    #[test]
+    fn test_mirror() {
+        let router = Router::new(Pool::default());
+        add_pool(&router, "shadow", &[]);
+        let mirror = Mirror::new("shadow", 100, 2, 10);
+        let mut route = Route::new(DEFAULT_POOL);
+        route.mirror = Some(mirror.clone());
+        router.set_routes(vec![route]);
+
+        let first = router.route(&request(Method::Get, None, "/"), &addr());
+        assert!(first.shadow.as_ref().unwrap().pool.find(&server("shadow")).is_some());
+        assert!(first.pool.find(&server("shadow")).is_none());
+        let second = router.route(&request(Method::Get, None, "/"), &addr());
+        assert!(second.shadow.is_some());
+        assert_eq!(2, mirror.in_flight());
+
+        // the mirror is at its limit of copies in flight
+        let third = router.route(&request(Method::Get, None, "/"), &addr());
+        assert!(third.shadow.is_none());
+
+        drop(first);
+        assert_eq!(1, mirror.in_flight());
+
+        // the body is too large to copy
+        let mut req = request(Method::Post, None, "/");
+        req.headers_mut().set(header::ContentLength(11));
+        assert!(router.route(&req, &addr()).shadow.is_none());
+
+        // the length of the body is not known up front
+        let mut req = request(Method::Post, None, "/");
+        req.set_body(Body::from("hello"));
+        assert!(router.route(&req, &addr()).shadow.is_none());
+
+        // the copy still in flight counts against the mirror of the new routes
+        let mut route = Route::new(DEFAULT_POOL);
+        route.mirror = Some(Mirror::new("shadow", 100, 2, 10));
+        router.set_routes(vec![route]);
+        let fourth = router.route(&request(Method::Get, None, "/"), &addr());
+        assert!(fourth.shadow.is_some());
+        assert!(router.route(&request(Method::Get, None, "/"), &addr()).shadow.is_none());
+
+        drop(second);
+        drop(fourth);
+        assert_eq!(0, router.routes()[0].mirror.as_ref().unwrap().in_flight());
+    }
+
+    #[test]
+    fn test_pool_names() {
+        let router = Router::new(Pool::default());
+        add_pool(&router, "api", &["api.example.com"]);
+
+        assert_eq!(DEFAULT_POOL, router.default_pool().name());
+        assert_eq!("api", router.pool("api").unwrap().name());
+    }
+}
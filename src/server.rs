@@ -1,5 +1,7 @@
 use hyper::Uri;
 
+use hash;
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Server {
     url: Uri,
@@ -23,4 +25,9 @@ impl Server {
     pub fn map_host(&self) -> bool {
         self.map_host
     }
+
+    /// An identifier for the server that is the same in every worker
+    pub fn id(&self) -> u64 {
+        hash::hash(&self.url.to_string())
+    }
 }
@@ -1,7 +1,38 @@
-#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+use std::time::{Duration, Instant};
+
+/// The time (in seconds) it takes for an old latency sample to lose most of its influence
+const LATENCY_DECAY: f64 = 10.0;
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Stats {
     failure: usize,
     success: usize,
+
+    /// Peak exponentially weighted moving average of the response latency in milliseconds
+    latency: Option<f64>,
+    latency_updated: Option<Instant>,
+
+    connections: Connections,
+}
+
+/// Counts of the connections to a backend, used to measure how often connections are reused
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Connections {
+    /// The connections that are open
+    pub open: u64,
+
+    /// The connections opened since the backend was added
+    pub opened: u64,
+
+    /// The requests sent since the backend was added
+    pub requests: u64,
+}
+
+impl Connections {
+    /// The number of requests sent on a connection that was already open
+    pub fn reused(&self) -> u64 {
+        self.requests.saturating_sub(self.opened)
+    }
 }
 
 impl Stats {
@@ -9,6 +40,9 @@ impl Stats {
         Stats {
             failure: 0,
             success: 0,
+            latency: None,
+            latency_updated: None,
+            connections: Connections::default(),
         }
     }
 
@@ -27,4 +61,124 @@ impl Stats {
     pub fn failure(&self) -> usize {
         self.failure
     }
+
+    pub fn inc_requests(&mut self) {
+        self.connections.requests += 1;
+    }
+
+    pub fn connection_opened(&mut self) {
+        self.connections.open += 1;
+        self.connections.opened += 1;
+    }
+
+    pub fn connection_closed(&mut self) {
+        self.connections.open = self.connections.open.saturating_sub(1);
+    }
+
+    pub fn connections(&self) -> Connections {
+        self.connections
+    }
+
+    /// Add a response latency to the moving average
+    ///
+    /// A latency higher than the average replaces the average right away so that a backend that
+    /// slows down is noticed quickly. Lower latencies are averaged in over time.
+    pub fn observe_latency(&mut self, latency: Duration) {
+        self.observe_latency_at(latency, Instant::now())
+    }
+
+    /// The moving average of the response latency in milliseconds
+    ///
+    /// The average decays towards zero when no responses are observed, so that an idle backend
+    /// is eventually given another chance. Returns `None` until a latency is observed.
+    pub fn latency(&self) -> Option<f64> {
+        self.latency_at(Instant::now())
+    }
+
+    fn observe_latency_at(&mut self, latency: Duration, now: Instant) {
+        let latency = as_millis(latency);
+        let average = match self.latency {
+            Some(average) if latency <= average => {
+                let w = self.decay(now);
+                average * w + latency * (1.0 - w)
+            }
+            _ => latency,
+        };
+
+        self.latency = Some(average);
+        self.latency_updated = Some(now);
+    }
+
+    fn latency_at(&self, now: Instant) -> Option<f64> {
+        self.latency.map(|latency| latency * self.decay(now))
+    }
+
+    /// The weight given to the current average based on how long ago it was updated
+    fn decay(&self, now: Instant) -> f64 {
+        match self.latency_updated {
+            Some(updated) if now > updated => {
+                let elapsed = as_millis(now.duration_since(updated)) / 1000.0;
+                (-elapsed / LATENCY_DECAY).exp()
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+fn as_millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Stats;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_connections_reused() {
+        let mut stats = Stats::new();
+        stats.connection_opened();
+        stats.inc_requests();
+        stats.inc_requests();
+        stats.connection_opened();
+        stats.inc_requests();
+        stats.connection_closed();
+
+        let connections = stats.connections();
+        assert_eq!(1, connections.open);
+        assert_eq!(2, connections.opened);
+        assert_eq!(3, connections.requests);
+        assert_eq!(1, connections.reused());
+    }
+
+    #[test]
+    fn test_latency_peak() {
+        let mut stats = Stats::new();
+        let now = Instant::now();
+        assert_eq!(None, stats.latency_at(now));
+
+        stats.observe_latency_at(Duration::from_millis(10), now);
+        assert_eq!(Some(10.0), stats.latency_at(now));
+
+        // a slower response is used as the average right away
+        stats.observe_latency_at(Duration::from_millis(100), now);
+        assert_eq!(Some(100.0), stats.latency_at(now));
+    }
+
+    #[test]
+    fn test_latency_decay() {
+        let mut stats = Stats::new();
+        let now = Instant::now();
+        stats.observe_latency_at(Duration::from_millis(100), now);
+
+        // a faster response is averaged in based on how old the current average is
+        let later = now + Duration::from_secs(10);
+        stats.observe_latency_at(Duration::from_millis(10), later);
+        let latency = stats.latency_at(later).unwrap();
+        assert!(latency > 10.0 && latency < 100.0, "latency was {}", latency);
+
+        // with no responses the average decays towards zero
+        let much_later = later + Duration::from_secs(60);
+        assert!(stats.latency_at(much_later).unwrap() < 1.0);
+    }
 }
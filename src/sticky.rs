@@ -0,0 +1,167 @@
+//! Sticky sessions using a cookie set by the proxy
+//!
+//! The first response to a client gets a cookie naming the backend that served it. Later requests
+//! that send the cookie back are routed to the same backend for as long as it is active. The
+//! cookie is signed so that a client cannot pick a backend by forging the cookie.
+
+use std::hash::{Hasher, SipHasher};
+
+use hyper::Headers;
+use hyper::header::SetCookie;
+use rand::{self, Rng};
+
+use hash;
+use server::Server;
+
+/// The name of the cookie used when none is specified
+pub const DEFAULT_COOKIE: &'static str = "weldr_backend";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StickySession {
+    /// The name of the cookie
+    cookie: String,
+
+    /// The key used to sign the cookie. Every worker must use the same secret.
+    secret: String,
+}
+
+impl StickySession {
+    pub fn new(cookie: String, secret: String) -> StickySession {
+        StickySession {
+            cookie: cookie,
+            secret: secret,
+        }
+    }
+
+    /// Create a sticky session with a randomly generated secret
+    pub fn with_random_secret(cookie: String) -> StickySession {
+        let secret = rand::thread_rng().gen_ascii_chars().take(32).collect();
+        StickySession::new(cookie, secret)
+    }
+
+    pub fn cookie(&self) -> &str {
+        &self.cookie
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Returns the id of the backend named by the cookie in the request headers
+    ///
+    /// Returns `None` if the cookie is missing or the signature is not valid.
+    pub fn backend_id(&self, headers: &Headers) -> Option<u64> {
+        let value = match hash::cookie(headers, &self.cookie) {
+            Some(value) => value,
+            None => return None,
+        };
+
+        let mut parts = value.splitn(2, '.');
+        let id = parts.next().and_then(|id| u64::from_str_radix(id, 16).ok());
+        let signature = parts.next().and_then(|sig| u64::from_str_radix(sig, 16).ok());
+
+        match (id, signature) {
+            (Some(id), Some(signature)) if self.sign(id) == signature => Some(id),
+            _ => {
+                debug!("Ignoring invalid sticky session cookie {:?}", value);
+                None
+            }
+        }
+    }
+
+    /// Add a cookie to the response headers naming the server that served the request
+    pub fn set_cookie(&self, headers: &mut Headers, server: &Server) {
+        let id = server.id();
+        let cookie = format!(
+            "{}={:016x}.{:016x}; Path=/; HttpOnly",
+            self.cookie,
+            id,
+            self.sign(id)
+        );
+
+        if let Some(set_cookie) = headers.get_mut::<SetCookie>() {
+            set_cookie.0.push(cookie);
+            return;
+        }
+
+        headers.set(SetCookie(vec![cookie]));
+    }
+
+    /// Sign the backend id using SipHash keyed with the secret
+    ///
+    /// SipHash is a keyed function designed to authenticate short messages, which is all that is
+    /// needed to keep a client from forging the id of a backend.
+    #[allow(deprecated)]
+    fn sign(&self, id: u64) -> u64 {
+        let mut hasher = SipHasher::new_with_keys(
+            hash::hash(&(0, &self.secret)),
+            hash::hash(&(1, &self.secret)),
+        );
+        hasher.write_u64(id);
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StickySession;
+    use hyper::Headers;
+    use hyper::header::SetCookie;
+    use server::Server;
+    use std::str::FromStr;
+
+    fn server() -> Server {
+        Server::new(FromStr::from_str("http://127.0.0.1:6000").unwrap(), false)
+    }
+
+    /// Send the cookies set on a response back on a request
+    fn request_headers(response: &Headers) -> Headers {
+        let mut headers = Headers::new();
+        let set_cookie = response.get::<SetCookie>().unwrap();
+        let cookies: Vec<&str> = set_cookie
+            .0
+            .iter()
+            .map(|c| c.split(';').next().unwrap())
+            .collect();
+        headers.set_raw("Cookie", cookies.join("; "));
+        headers
+    }
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let sticky = StickySession::new("sticky".to_string(), "secret".to_string());
+        let server = server();
+
+        let mut response = Headers::new();
+        response.set(SetCookie(vec!["other=1".to_string()]));
+        sticky.set_cookie(&mut response, &server);
+        assert_eq!(2, response.get::<SetCookie>().unwrap().0.len());
+
+        let request = request_headers(&response);
+        assert_eq!(Some(server.id()), sticky.backend_id(&request));
+    }
+
+    #[test]
+    fn test_cookie_signed_with_other_secret() {
+        let sticky = StickySession::new("sticky".to_string(), "secret".to_string());
+        let other = StickySession::new("sticky".to_string(), "other".to_string());
+
+        let mut response = Headers::new();
+        other.set_cookie(&mut response, &server());
+
+        let request = request_headers(&response);
+        assert_eq!(None, sticky.backend_id(&request));
+    }
+
+    #[test]
+    fn test_forged_cookie() {
+        let sticky = StickySession::with_random_secret("sticky".to_string());
+
+        let mut request = Headers::new();
+        request.set_raw("Cookie", format!("sticky={:016x}.0000000000000000", server().id()));
+        assert_eq!(None, sticky.backend_id(&request));
+
+        request.set_raw("Cookie", format!("sticky={:016x}", server().id()));
+        assert_eq!(None, sticky.backend_id(&request));
+    }
+}
@@ -0,0 +1,375 @@
+//! Timeouts for requests sent to backend servers
+//!
+//! A request has four timeouts, each of which is disabled when set to zero:
+//!
+//!    * connect - the time allowed to open the connection to the backend
+//!    * first byte - the time from sending the request until the response headers are received
+//!    * idle - the longest time the backend may go without sending part of the response body
+//!    * request - the time allowed for the whole request, including the response body
+//!
+//! A request that times out before the response headers are received fails with a timeout error,
+//! which is sent to the client as a `504 Gateway Timeout`. Once the headers are sent to the client
+//! the response can no longer be changed, so a timeout while streaming the body aborts it.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+use hyper::{self, Body, Chunk, Uri};
+use hyper::client::Service;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_timer::{self, Sleep, Timer};
+
+use config::Timeouts;
+use router::Router;
+
+/// The longest timeout that can be set
+///
+/// The timer rejects a timeout longer than this, so longer timeouts are shortened to it.
+const MAX_TIMEOUT: u64 = 24 * 60 * 60;
+
+/// Create the timer used for the timeouts of requests to backends
+pub fn timer() -> Timer {
+    tokio_timer::wheel()
+        .max_timeout(Duration::from_secs(MAX_TIMEOUT))
+        .build()
+}
+
+/// Convert a timeout in milliseconds, where zero disables the timeout
+pub fn duration(ms: u64) -> Option<Duration> {
+    if ms == 0 {
+        None
+    } else {
+        Some(::std::cmp::min(Duration::from_millis(ms), Duration::from_secs(MAX_TIMEOUT)))
+    }
+}
+
+/// Returns true once the sleep is done
+///
+/// A sleep that fails is disabled, so that a problem with the timer does not fail the request.
+pub fn expired(sleep: &mut Option<Sleep>) -> bool {
+    let res = match *sleep {
+        Some(ref mut s) => s.poll(),
+        None => return false,
+    };
+
+    match res {
+        Ok(Async::Ready(())) => true,
+        Ok(Async::NotReady) => false,
+        Err(e) => {
+            warn!("Disabling timeout after timer error: {}", e);
+            sleep.take();
+            false
+        }
+    }
+}
+
+/// A timeout that is pushed back whenever there is activity
+///
+/// Activity only moves the deadline. The sleep is re-armed for the rest of the time when it fires
+/// before the deadline, so that a busy stream does not create a new sleep each time.
+#[derive(Debug)]
+pub struct IdleTimer {
+    timer: Timer,
+    idle: Duration,
+    deadline: Instant,
+    sleep: Option<Sleep>,
+}
+
+impl IdleTimer {
+    pub fn new(timer: &Timer, idle: Duration) -> IdleTimer {
+        IdleTimer {
+            timer: timer.clone(),
+            idle: idle,
+            deadline: Instant::now() + idle,
+            sleep: Some(timer.sleep(idle)),
+        }
+    }
+
+    /// Push the deadline back to a full idle timeout from now
+    pub fn reset(&mut self) {
+        self.deadline = Instant::now() + self.idle;
+    }
+
+    /// Returns true once the deadline passes without activity
+    pub fn expired(&mut self) -> bool {
+        while expired(&mut self.sleep) {
+            let now = Instant::now();
+            if now >= self.deadline {
+                return true;
+            }
+            self.sleep = Some(self.timer.sleep(self.deadline - now));
+        }
+        false
+    }
+}
+
+/// A connector that gives up on connecting to a backend after the connect timeout
+///
+/// The timeout of the backend the connection is for is looked up in the named pool, so that a
+/// server in more than one pool gets the timeout of the pool the request was sent to.
+#[derive(Clone)]
+pub struct TimeoutConnector<C> {
+    connector: C,
+    timer: Timer,
+    router: Router,
+    pool: String,
+}
+
+impl<C> TimeoutConnector<C> {
+    pub fn new(connector: C, timer: Timer, router: Router, pool: &str) -> TimeoutConnector<C> {
+        TimeoutConnector {
+            connector: connector,
+            timer: timer,
+            router: router,
+            pool: pool.to_string(),
+        }
+    }
+}
+
+impl<C> Service for TimeoutConnector<C>
+where
+    C: Service<Request = Uri, Error = io::Error>,
+    C::Response: AsyncRead + AsyncWrite + 'static,
+    C::Future: 'static,
+{
+    type Request = Uri;
+    type Response = C::Response;
+    type Error = io::Error;
+    type Future = Box<Future<Item = C::Response, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let pool = self.router.pool(&self.pool).unwrap_or_else(
+            || self.router.default_pool(),
+        );
+        let timeout = duration(pool.connect_timeout(&uri));
+        let connecting = self.connector.call(uri);
+
+        match timeout {
+            // the timer fails with an error of kind `TimedOut`
+            Some(timeout) => Box::new(self.timer.timeout(connecting, timeout)),
+            None => Box::new(connecting),
+        }
+    }
+}
+
+/// Tracks the timeouts of a single request to a backend
+#[derive(Debug)]
+pub struct RequestTimer {
+    first_byte: Option<Sleep>,
+    idle: Option<IdleTimer>,
+    deadline: Option<Sleep>,
+}
+
+impl RequestTimer {
+    /// Start the timeouts of a request that is about to be sent
+    pub fn start(timer: &Timer, timeouts: &Timeouts) -> RequestTimer {
+        RequestTimer {
+            first_byte: duration(timeouts.first_byte).map(|d| timer.sleep(d)),
+            idle: duration(timeouts.idle).map(|d| IdleTimer::new(timer, d)),
+            deadline: duration(timeouts.request).map(|d| timer.sleep(d)),
+        }
+    }
+
+    /// Wait for the response headers until the first byte timeout or the request deadline
+    pub fn headers<F>(self, future: F) -> Headers<F> {
+        Headers {
+            future: future,
+            timer: Some(self),
+        }
+    }
+
+    /// Time the response body until the idle timeout or the request deadline
+    pub fn body(mut self, body: Body) -> TimedBody {
+        self.first_byte = None;
+        // the idle timeout starts with the body
+        if let Some(ref mut idle) = self.idle {
+            idle.reset();
+        }
+        TimedBody {
+            body: body,
+            timer: Some(self),
+        }
+    }
+}
+
+/// A future for the response headers of a request that fails if they take too long
+#[derive(Debug)]
+pub struct Headers<F> {
+    future: F,
+    timer: Option<RequestTimer>,
+}
+
+impl<F> Future for Headers<F>
+where
+    F: Future<Error = hyper::Error>,
+{
+    type Item = (F::Item, RequestTimer);
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, hyper::Error> {
+        if let Async::Ready(res) = self.future.poll()? {
+            let timer = self.timer.take().expect("cannot poll Headers twice");
+            return Ok(Async::Ready((res, timer)));
+        }
+
+        let timer = self.timer.as_mut().expect("cannot poll Headers twice");
+        if expired(&mut timer.first_byte) || expired(&mut timer.deadline) {
+            return Err(hyper::Error::Timeout);
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// A response body that fails if the backend stops sending it or the request deadline passes
+#[derive(Debug)]
+pub struct TimedBody {
+    body: Body,
+    timer: Option<RequestTimer>,
+}
+
+impl From<Body> for TimedBody {
+    /// A body without timeouts
+    fn from(body: Body) -> TimedBody {
+        TimedBody {
+            body: body,
+            timer: None,
+        }
+    }
+}
+
+impl Stream for TimedBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        match self.body.poll()? {
+            Async::Ready(Some(chunk)) => {
+                if let Some(idle) = self.timer.as_mut().and_then(|t| t.idle.as_mut()) {
+                    idle.reset();
+                }
+                return Ok(Async::Ready(Some(chunk)));
+            }
+            Async::Ready(None) => {
+                self.timer.take();
+                return Ok(Async::Ready(None));
+            }
+            Async::NotReady => (),
+        }
+
+        let timed_out = match self.timer {
+            Some(ref mut timer) => {
+                timer.idle.as_mut().map_or(false, |idle| idle.expired()) ||
+                    expired(&mut timer.deadline)
+            }
+            None => false,
+        };
+
+        if timed_out {
+            warn!("Aborting response body from backend after timeout");
+            self.timer.take();
+            return Err(hyper::Error::Timeout);
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{duration, timer, IdleTimer, RequestTimer, TimedBody};
+    use config::Timeouts;
+    use futures::{empty, Async, Future, Poll, Stream};
+    use futures::future::{ok, poll_fn};
+    use hyper::{self, Body};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn timeouts(first_byte: u64, idle: u64, request: u64) -> Timeouts {
+        Timeouts {
+            connect: 0,
+            first_byte: first_byte,
+            idle: idle,
+            request: request,
+        }
+    }
+
+    #[test]
+    fn test_duration() {
+        assert_eq!(None, duration(0));
+        assert_eq!(Some(Duration::from_millis(1500)), duration(1500));
+        assert_eq!(Some(Duration::from_secs(24 * 60 * 60)), duration(u64::max_value()));
+    }
+
+    #[test]
+    fn test_headers_in_time() {
+        let timer = timer();
+        let req = RequestTimer::start(&timer, &timeouts(1000, 0, 0));
+        let (res, _) = req.headers(ok::<_, hyper::Error>("response")).wait().unwrap();
+        assert_eq!("response", res);
+    }
+
+    #[test]
+    fn test_first_byte_timeout() {
+        let timer = timer();
+        let req = RequestTimer::start(&timer, &timeouts(200, 0, 0));
+        match req.headers(empty::<(), hyper::Error>()).wait() {
+            Err(hyper::Error::Timeout) => (),
+            res => panic!("expected timeout, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_request_deadline() {
+        let timer = timer();
+        let req = RequestTimer::start(&timer, &timeouts(0, 0, 200));
+        match req.headers(empty::<(), hyper::Error>()).wait() {
+            Err(hyper::Error::Timeout) => (),
+            res => panic!("expected timeout, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let timer = timer();
+        let (tx, body) = Body::pair();
+        let body = RequestTimer::start(&timer, &timeouts(0, 200, 0)).body(body);
+
+        // the sender is kept open without sending anything
+        let chunks = body.collect().wait();
+        drop(tx);
+        match chunks {
+            Err(hyper::Error::Timeout) => (),
+            res => panic!("expected timeout, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_body_without_timeouts() {
+        let body = TimedBody::from(Body::from("hello"));
+        let chunks = body.collect().wait().unwrap();
+        assert_eq!(b"hello", &chunks[0][..]);
+    }
+
+    #[test]
+    fn test_idle_timer_reset() {
+        let timer = timer();
+        let mut idle = IdleTimer::new(&timer, Duration::from_millis(300));
+        let start = Instant::now();
+
+        // activity half way through pushes the deadline back without a new sleep
+        thread::sleep(Duration::from_millis(150));
+        idle.reset();
+
+        let expired = poll_fn(|| -> Poll<(), ()> {
+            if idle.expired() {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        });
+        expired.wait().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
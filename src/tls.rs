@@ -0,0 +1,443 @@
+//! TLS termination of client connections
+//!
+//! A TLS listener offers one or more certificates, each loaded from a PEM file holding the
+//! certificate followed by its chain and a PEM file holding its private key. The certificate is
+//! selected by the server name the client sends (SNI) and the names the certificate is valid for,
+//! which are its DNS subject alternative names or, when it has none, its common name. An exact name
+//! is preferred over a wildcard, which matches a single label. The first certificate is offered to
+//! clients that send no server name or a name no certificate matches.
+//!
+//! Clients that support HTTP/2 may select it during the handshake (ALPN) unless it is disabled.
+//! HTTP/1.1 is used otherwise.
+//!
+//! Each certificate has a name, so that it can be replaced while the listener runs. Replacing a
+//! certificate builds a new acceptor, which is used for the handshakes that follow. Connections
+//! that already finished their handshake are not affected.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{self, AlpnError, NameType, SniError, SslAcceptor, SslAcceptorBuilder,
+                   SslContext, SslMethod, SslVersion};
+use openssl::x509::X509;
+
+use config::{Certificate, Http2, Tls};
+use http2;
+
+/// A certificate chain and private key in PEM
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pem {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl Pem {
+    /// Read the files of a certificate
+    pub fn load(certificate: &Certificate) -> io::Result<Pem> {
+        Ok(Pem {
+            cert: read(&certificate.cert)?,
+            key: read(&certificate.key)?,
+        })
+    }
+}
+
+/// The certificates offered by a TLS listener, which can be replaced while it runs
+#[derive(Clone)]
+pub struct Certificates {
+    inner: Rc<RefCell<Inner>>,
+}
+
+struct Inner {
+    conf: Tls,
+    http2: Http2,
+
+    /// The certificates in the order they were added, where the first is the default
+    pems: Vec<(String, Pem)>,
+
+    /// The server names of each certificate, in the same order
+    names: Vec<Vec<String>>,
+
+    acceptor: SslAcceptor,
+}
+
+impl fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Certificates {{ certificates: {} }}",
+            self.inner.borrow().pems.len()
+        )
+    }
+}
+
+impl Certificates {
+    /// Load the certificates of the configuration
+    pub fn load(conf: &Tls, http2: &Http2) -> io::Result<Certificates> {
+        let mut pems = Vec::with_capacity(conf.certificates.len());
+        for certificate in &conf.certificates {
+            pems.push((certificate.name.clone(), Pem::load(certificate)?));
+        }
+
+        Certificates::new(conf, http2, pems)
+    }
+
+    fn new(conf: &Tls, http2: &Http2, pems: Vec<(String, Pem)>) -> io::Result<Certificates> {
+        let (acceptor, names) = acceptor(conf, http2, &pems)?;
+        Ok(Certificates {
+            inner: Rc::new(RefCell::new(Inner {
+                conf: conf.clone(),
+                http2: http2.clone(),
+                pems: pems,
+                names: names,
+                acceptor: acceptor,
+            })),
+        })
+    }
+
+    /// Returns the acceptor for the next handshake
+    pub fn acceptor(&self) -> SslAcceptor {
+        self.inner.borrow().acceptor.clone()
+    }
+
+    /// Returns the name of each certificate and the server names it is valid for
+    pub fn names(&self) -> Vec<(String, Vec<String>)> {
+        let inner = self.inner.borrow();
+        inner
+            .pems
+            .iter()
+            .map(|&(ref name, _)| name.clone())
+            .zip(inner.names.iter().cloned())
+            .collect()
+    }
+
+    /// Returns each certificate with its name, in the order they were added
+    pub fn pems(&self) -> Vec<(String, Pem)> {
+        self.inner.borrow().pems.clone()
+    }
+
+    /// Add a certificate, or replace the certificate with the same name
+    ///
+    /// Nothing changes if the certificate is invalid.
+    pub fn set(&self, name: &str, pem: Pem) -> io::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+
+        let mut pems = inner.pems.clone();
+        match pems.iter().position(|&(ref n, _)| n == name) {
+            Some(i) => pems[i].1 = pem,
+            None => pems.push((name.to_string(), pem)),
+        }
+
+        let (acceptor, names) = acceptor(&inner.conf, &inner.http2, &pems)?;
+        inner.pems = pems;
+        inner.names = names;
+        inner.acceptor = acceptor;
+        Ok(())
+    }
+}
+
+/// A certificate and the server names it is valid for
+struct Identity {
+    names: Vec<String>,
+
+    /// The settings of the handshake with the certificate, or `None` for the first certificate,
+    /// which the handshake already uses
+    context: Option<SslContext>,
+}
+
+/// Create the acceptor for the TLS handshake of client connections
+///
+/// Returns the server names of each certificate.
+fn acceptor(
+    conf: &Tls,
+    http2: &Http2,
+    pems: &[(String, Pem)],
+) -> io::Result<(SslAcceptor, Vec<Vec<String>>)> {
+    let (first, rest) = match pems.split_first() {
+        Some(pems) => pems,
+        None => return Err(error("no certificate configured")),
+    };
+
+    let (default_names, mut default) = builder(conf, http2, first)?;
+    let mut names = vec![default_names.clone()];
+    let mut identities = Vec::with_capacity(pems.len());
+    identities.push(Identity {
+        names: default_names,
+        context: None,
+    });
+    for pem in rest {
+        let (identity_names, identity) = builder(conf, http2, pem)?;
+        names.push(identity_names.clone());
+        identities.push(Identity {
+            names: identity_names,
+            context: Some(identity.build().into_context()),
+        });
+    }
+
+    if !rest.is_empty() {
+        let identities = Arc::new(identities);
+        default.set_servername_callback(move |ssl, _alert| {
+            let identity = match ssl.servername(NameType::HOST_NAME) {
+                Some(server_name) => select(&identities, server_name),
+                None => None,
+            };
+
+            if let Some(&Identity { context: Some(ref context), .. }) = identity {
+                ssl.set_ssl_context(context).map_err(|e| {
+                    warn!("Failed to select certificate: {}", e);
+                    SniError::ALERT_FATAL
+                })?;
+            }
+
+            Ok(())
+        });
+    }
+
+    Ok((default.build(), names))
+}
+
+/// Create the settings of the handshake for a certificate
+///
+/// Returns the server names the certificate is valid for.
+fn builder(
+    conf: &Tls,
+    http2: &Http2,
+    pem: &(String, Pem),
+) -> io::Result<(Vec<String>, SslAcceptorBuilder)> {
+    let (ref name, ref pem) = *pem;
+    let mut chain = X509::stack_from_pem(&pem.cert)
+        .map_err(|e| error(format!("certificate {}: {}", name, e)))?
+        .into_iter();
+    let cert = match chain.next() {
+        Some(cert) => cert,
+        None => return Err(error(format!("certificate {}: no certificate found", name))),
+    };
+    let key = PKey::private_key_from_pem(&pem.key)
+        .map_err(|e| error(format!("certificate {}: invalid key: {}", name, e)))?;
+
+    let names = names(&cert);
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(error)?;
+    builder.set_min_proto_version(Some(version(&conf.min_version)?)).map_err(error)?;
+    if !conf.ciphers.is_empty() {
+        builder.set_cipher_list(&conf.ciphers).map_err(|e| {
+            error(format!("invalid ciphers {}: {}", conf.ciphers, e))
+        })?;
+    }
+    // the protocol is selected again once a certificate is selected by server name, so each
+    // certificate offers it
+    if http2.enabled {
+        builder.set_alpn_select_callback(|_ssl, client| {
+            ssl::select_next_proto(http2::ALPN_PROTOCOLS, client).ok_or(AlpnError::NOACK)
+        });
+    }
+    builder.set_certificate(&cert).map_err(error)?;
+    for cert in chain {
+        builder.add_extra_chain_cert(cert).map_err(error)?;
+    }
+    builder.set_private_key(&key).map_err(error)?;
+    builder.check_private_key().map_err(|e| {
+        error(format!("certificate {}: key does not match: {}", name, e))
+    })?;
+
+    Ok((names, builder))
+}
+
+/// Parse a protocol version, such as `TLSv1.2`
+fn version(version: &str) -> io::Result<SslVersion> {
+    match version {
+        "TLSv1" | "TLSv1.0" => Ok(SslVersion::TLS1),
+        "TLSv1.1" => Ok(SslVersion::TLS1_1),
+        "TLSv1.2" => Ok(SslVersion::TLS1_2),
+        "TLSv1.3" => Ok(SslVersion::TLS1_3),
+        _ => Err(error(format!("unknown TLS version {}", version))),
+    }
+}
+
+/// The server names a certificate is valid for
+fn names(cert: &X509) -> Vec<String> {
+    let names: Vec<String> = match cert.subject_alt_names() {
+        Some(names) => {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname())
+                .map(|name| name.to_lowercase())
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    if !names.is_empty() {
+        return names;
+    }
+
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_lowercase())
+        .collect()
+}
+
+/// Select the certificate for a server name, preferring an exact name over a wildcard
+fn select<'a>(identities: &'a [Identity], server_name: &str) -> Option<&'a Identity> {
+    let server_name = server_name.to_lowercase();
+
+    identities
+        .iter()
+        .find(|identity| identity.names.iter().any(|name| *name == server_name))
+        .or_else(|| {
+            identities.iter().find(|identity| {
+                identity.names.iter().any(|name| matches_wildcard(name, &server_name))
+            })
+        })
+}
+
+/// Returns true if a wildcard name, such as `*.example.com`, matches the server name
+///
+/// The wildcard only matches a single label, so `*.example.com` does not match `example.com` or
+/// `a.b.example.com`.
+fn matches_wildcard(name: &str, server_name: &str) -> bool {
+    if !name.starts_with("*.") {
+        return false;
+    }
+
+    match server_name.find('.') {
+        Some(i) => i > 0 && server_name[i..] == name[1..],
+        None => false,
+    }
+}
+
+fn read(path: &str) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    Ok(buf)
+}
+
+fn error<E>(e: E) -> io::Error
+where
+    E: Into<Box<::std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches_wildcard, select, version, Certificates, Identity, Pem};
+    use config::{Http2, Tls};
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::ssl::{SslContext, SslMethod, SslVersion};
+    use openssl::x509::{X509, X509NameBuilder};
+
+    fn identity(names: &[&str]) -> Identity {
+        Identity {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            context: Some(SslContext::builder(SslMethod::tls()).unwrap().build()),
+        }
+    }
+
+    /// Create a self-signed certificate for a common name
+    fn pem(common_name: &str) -> Pem {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name).unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        Pem {
+            cert: cert.build().to_pem().unwrap(),
+            key: key.private_key_to_pem_pkcs8().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_matches_wildcard() {
+        assert!(matches_wildcard("*.example.com", "www.example.com"));
+        assert!(!matches_wildcard("*.example.com", "example.com"));
+        assert!(!matches_wildcard("*.example.com", "a.b.example.com"));
+        assert!(!matches_wildcard("*.example.com", ".example.com"));
+        assert!(!matches_wildcard("www.example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn test_select() {
+        let identities = vec![
+            identity(&["*.example.com"]),
+            identity(&["api.example.com", "example.com"]),
+        ];
+
+        let selected = select(&identities, "API.example.com").unwrap();
+        assert_eq!("api.example.com", selected.names[0]);
+
+        let selected = select(&identities, "www.example.com").unwrap();
+        assert_eq!("*.example.com", selected.names[0]);
+
+        let selected = select(&identities, "example.com").unwrap();
+        assert_eq!("api.example.com", selected.names[0]);
+
+        assert!(select(&identities, "example.org").is_none());
+    }
+
+    #[test]
+    fn test_select_default_exact() {
+        let mut identities = vec![identity(&["www.example.com"]), identity(&["*.example.com"])];
+        identities[0].context = None;
+
+        let selected = select(&identities, "www.example.com").unwrap();
+        assert!(selected.context.is_none());
+
+        let selected = select(&identities, "api.example.com").unwrap();
+        assert_eq!("*.example.com", selected.names[0]);
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(SslVersion::TLS1_2, version("TLSv1.2").unwrap());
+        assert!(version("SSLv3").is_err());
+    }
+
+    #[test]
+    fn test_without_certificates() {
+        assert!(Certificates::new(&Tls::default(), &Http2::default(), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_set_certificate() {
+        let pems = vec![("default".to_string(), pem("example.com"))];
+        let certificates = Certificates::new(&Tls::default(), &Http2::default(), pems).unwrap();
+
+        certificates.set("api", pem("api.example.com")).unwrap();
+        certificates.set("default", pem("www.example.com")).unwrap();
+        assert_eq!(
+            vec![
+                ("default".to_string(), vec!["www.example.com".to_string()]),
+                ("api".to_string(), vec!["api.example.com".to_string()]),
+            ],
+            certificates.names()
+        );
+
+        // a key that does not match leaves the certificate as it was
+        let mut invalid = pem("other.example.com");
+        invalid.key = pem("other.example.com").key;
+        assert!(certificates.set("api", invalid).is_err());
+        assert_eq!("api.example.com", certificates.names()[1].1[0]);
+    }
+}
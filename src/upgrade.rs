@@ -0,0 +1,393 @@
+//! Tunneling connections that upgrade to another protocol, such as WebSocket
+//!
+//! A request with `Connection: Upgrade` and an `Upgrade` header asks to switch its connection to
+//! another protocol. When the pool allows upgrades, the request is sent to a backend on a new
+//! connection of its own. If the backend agrees with a `101 Switching Protocols` response, the
+//! response is sent to the client and the two connections are spliced together. Bytes are then
+//! copied both ways until both sides close the connection, either side fails, or neither side sends
+//! anything for the idle timeout.
+//!
+//! Any other response from the backend is proxied as usual, and the connection to the backend is
+//! closed once the response is done.
+
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::rc::Rc;
+
+use futures::{Async, Future, Poll};
+use hyper::{self, Body, Headers, StatusCode};
+use hyper::client::{self, conn};
+use hyper::header::{Connection, ConnectionOption, Upgrade};
+use tokio_core::reactor::Handle;
+use tokio_io::AsyncWrite;
+use tokio_timer::Timer;
+
+use pool::{InFlight, Pool};
+use server::Server;
+use timeout::{self, IdleTimer};
+use upstream::{BackendStream, Upstream};
+
+/// The size of the buffer used to copy each direction of a tunnel
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Returns true if the request asks to upgrade its connection to another protocol
+pub fn is_upgrade(headers: &Headers) -> bool {
+    let connection = match headers.get::<Connection>() {
+        Some(connection) => connection,
+        None => return false,
+    };
+
+    headers.has::<Upgrade>() &&
+        connection.iter().any(|option| match *option {
+            ConnectionOption::ConnectionHeader(ref name) => *name == "upgrade",
+            _ => false,
+        })
+}
+
+/// Copy the headers of an upgrade, which are removed when a message is proxied as plain HTTP
+pub fn copy_headers(from: &Headers, to: &mut Headers) {
+    if let Some(upgrade) = from.get_raw("Upgrade") {
+        to.set_raw("Upgrade", upgrade.clone());
+        to.set_raw("Connection", "upgrade");
+    }
+}
+
+/// The connection to a backend that agreed to upgrade
+pub struct Upgraded {
+    io: BackendStream,
+
+    /// Bytes the backend sent after its response, which belong to the new protocol
+    read_buf: Vec<u8>,
+
+    idle_timeout: u64,
+
+    /// Keeps the upgrade in flight on its backend until the tunnel is done
+    in_flight: Option<InFlight>,
+}
+
+/// Holds the connection of an upgrade until the client connection is done with HTTP
+///
+/// It is shared by the service that sends the upgrade request to the backend and the task that
+/// serves the client connection.
+#[derive(Clone, Default)]
+pub struct Pending {
+    inner: Rc<RefCell<Option<Upgraded>>>,
+}
+
+impl Pending {
+    pub fn new() -> Pending {
+        Pending::default()
+    }
+
+    /// Take the connection of the upgrade, if the backend agreed to one
+    pub fn take(&self) -> Option<Upgraded> {
+        self.inner.borrow_mut().take()
+    }
+
+    fn set(&self, upgraded: Upgraded) {
+        *self.inner.borrow_mut() = Some(upgraded);
+    }
+}
+
+/// Send a request to upgrade the connection to a backend server
+///
+/// The request is sent on a new connection. If the backend agrees to the upgrade, the connection
+/// is left in `pending` for the client connection to take.
+pub fn send(
+    client: &Upstream,
+    pool: &Pool,
+    server: &Server,
+    req: client::Request,
+    pending: Pending,
+    idle_timeout: u64,
+    handle: &Handle,
+) -> Box<Future<Item = client::Response, Error = hyper::Error>> {
+
+    let handle = handle.clone();
+    let pool1 = pool.clone();
+    let server1 = server.clone();
+    let work = client
+        .connect(pool, server)
+        .map_err(hyper::Error::from)
+        .and_then(|io| conn::handshake(io))
+        .and_then(move |(mut sender, conn)| {
+            let res = sender.send_request(req);
+            Handshake {
+                _sender: sender,
+                conn: Some(conn),
+                done: false,
+                res: res,
+                response: None,
+            }
+        })
+        .map(move |(res, conn)| {
+            if res.status() == StatusCode::SwitchingProtocols {
+                let parts = conn.into_parts();
+                pending.set(Upgraded {
+                    io: parts.io,
+                    read_buf: parts.read_buf.to_vec(),
+                    idle_timeout: idle_timeout,
+                    in_flight: pool1.hold(&server1),
+                });
+            } else {
+                // the body of the response is read from the connection, which closes once done
+                handle.spawn(conn.then(|_| Ok(())));
+            }
+
+            res
+        });
+
+    Box::new(work)
+}
+
+/// Drives the connection to a backend until the response to the upgrade request arrives
+///
+/// If the backend switched protocols, the connection is also done with HTTP by then, so that it
+/// can be taken apart.
+struct Handshake {
+    /// Kept until the response arrives so that the connection does not give up on the request
+    _sender: conn::SendRequest<Body>,
+    conn: Option<conn::Connection<BackendStream, Body>>,
+    done: bool,
+    res: conn::ResponseFuture,
+    response: Option<client::Response>,
+}
+
+impl Future for Handshake {
+    type Item = (client::Response, conn::Connection<BackendStream, Body>);
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, hyper::Error> {
+        if !self.done {
+            let conn = self.conn.as_mut().expect("cannot poll Handshake twice");
+            self.done = conn.poll_without_shutdown()?.is_ready();
+        }
+
+        if self.response.is_none() {
+            match self.res.poll()? {
+                Async::Ready(res) => self.response = Some(res),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+
+        let switching = match self.response {
+            Some(ref res) => res.status() == StatusCode::SwitchingProtocols,
+            None => false,
+        };
+        if switching && !self.done {
+            return Ok(Async::NotReady);
+        }
+
+        let res = self.response.take().expect("cannot poll Handshake twice");
+        let conn = self.conn.take().expect("cannot poll Handshake twice");
+        Ok(Async::Ready((res, conn)))
+    }
+}
+
+/// Splice a client connection to the backend connection of an upgrade
+///
+/// `read_buf` holds the bytes the client sent after the upgrade request that were already read.
+pub fn tunnel<C>(client: C, read_buf: Vec<u8>, upgraded: Upgraded, timer: &Timer) -> Tunnel<C> {
+    Tunnel {
+        client: client,
+        backend: upgraded.io,
+        to_backend: Pipe::new(read_buf),
+        to_client: Pipe::new(upgraded.read_buf),
+        idle: timeout::duration(upgraded.idle_timeout.saturating_mul(1000))
+            .map(|d| IdleTimer::new(timer, d)),
+        _in_flight: upgraded.in_flight,
+    }
+}
+
+/// A future that copies bytes both ways between a client and a backend
+///
+/// It finishes once both sides close the connection, and fails if either side fails or the tunnel
+/// is idle for too long.
+pub struct Tunnel<C> {
+    client: C,
+    backend: BackendStream,
+    to_backend: Pipe,
+    to_client: Pipe,
+    idle: Option<IdleTimer>,
+
+    /// The upgrade counts as a request in flight to the backend until the tunnel is dropped
+    _in_flight: Option<InFlight>,
+}
+
+impl<C: Read + AsyncWrite> Future for Tunnel<C> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let mut active = false;
+        let to_backend = self.to_backend.poll(
+            &mut self.client,
+            &mut self.backend,
+            &mut active,
+        )?;
+        let to_client = self.to_client.poll(
+            &mut self.backend,
+            &mut self.client,
+            &mut active,
+        )?;
+
+        if to_backend.is_ready() && to_client.is_ready() {
+            return Ok(Async::Ready(()));
+        }
+
+        if let Some(ref mut idle) = self.idle {
+            if active {
+                idle.reset();
+            }
+            if idle.expired() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "tunnel idle timeout"));
+            }
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// Copies one direction of a tunnel
+#[derive(Debug)]
+struct Pipe {
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl Pipe {
+    /// Create a pipe that first writes the bytes already read
+    fn new(pending: Vec<u8>) -> Pipe {
+        let cap = pending.len();
+        let mut buf = pending;
+        if buf.len() < BUFFER_SIZE {
+            buf.resize(BUFFER_SIZE, 0);
+        }
+
+        Pipe {
+            buf: buf,
+            pos: 0,
+            cap: cap,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Copy as much as possible without blocking
+    ///
+    /// Finishes once the reader is closed and everything read was written. `active` is set if any
+    /// bytes were copied.
+    fn poll<R, W>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        active: &mut bool,
+    ) -> Poll<(), io::Error>
+    where
+        R: Read,
+        W: AsyncWrite,
+    {
+        while !self.done {
+            if self.pos == self.cap && !self.eof {
+                match nonblocking(reader.read(&mut self.buf))? {
+                    Async::Ready(0) => self.eof = true,
+                    Async::Ready(n) => {
+                        self.pos = 0;
+                        self.cap = n;
+                        *active = true;
+                    }
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+            }
+
+            while self.pos < self.cap {
+                match nonblocking(writer.write(&self.buf[self.pos..self.cap]))? {
+                    Async::Ready(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "tunnel closed while writing",
+                        ))
+                    }
+                    Async::Ready(n) => {
+                        self.pos += n;
+                        *active = true;
+                    }
+                    Async::NotReady => return Ok(Async::NotReady),
+                }
+            }
+
+            if self.eof {
+                if let Async::NotReady = nonblocking(writer.flush())? {
+                    return Ok(Async::NotReady);
+                }
+                if let Async::NotReady = writer.shutdown()? {
+                    return Ok(Async::NotReady);
+                }
+                self.done = true;
+            }
+        }
+
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Convert the result of a non-blocking read or write into a poll
+fn nonblocking<T>(res: io::Result<T>) -> Poll<T, io::Error> {
+    match res {
+        Ok(t) => Ok(Async::Ready(t)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_headers, is_upgrade, Pipe};
+    use futures::Async;
+    use hyper::Headers;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_is_upgrade() {
+        let mut headers = Headers::new();
+        headers.set_raw("Upgrade", "websocket");
+        assert!(!is_upgrade(&headers));
+
+        headers.set_raw("Connection", "keep-alive, Upgrade");
+        assert!(is_upgrade(&headers));
+
+        headers.remove_raw("Upgrade");
+        assert!(!is_upgrade(&headers));
+    }
+
+    #[test]
+    fn test_copy_headers() {
+        let mut from = Headers::new();
+        from.set_raw("Upgrade", "websocket");
+        from.set_raw("Connection", "Upgrade");
+
+        let mut to = Headers::new();
+        copy_headers(&from, &mut to);
+        assert!(is_upgrade(&to));
+
+        let mut to = Headers::new();
+        copy_headers(&Headers::new(), &mut to);
+        assert_eq!(0, to.len());
+    }
+
+    #[test]
+    fn test_pipe() {
+        let mut pipe = Pipe::new(b"hello ".to_vec());
+        let mut reader = Cursor::new(b"world".to_vec());
+        let mut writer = Cursor::new(Vec::new());
+        let mut active = false;
+
+        let res = pipe.poll(&mut reader, &mut writer, &mut active).unwrap();
+        assert_eq!(Async::Ready(()), res);
+        assert!(active);
+        assert_eq!(b"hello world", &writer.into_inner()[..]);
+    }
+}
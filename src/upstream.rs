@@ -0,0 +1,515 @@
+//! The client used to send requests to backend servers
+//!
+//! Each worker has a client for each backend of each pool that is shared by every client
+//! connection, so that a connection to a backend can be reused by requests from any client.
+//! Connections are kept open for reuse until they have been idle for too long. The number of idle
+//! connections to a backend is limited by closing a connection once its response is done if the
+//! backend already has enough idle connections.
+//!
+//! A request that takes over its connection, such as an upgrade to WebSocket, is sent on a new
+//! connection of its own that is never reused.
+//!
+//! A pool may send its requests over HTTP/2 instead. A single connection to each backend then
+//! carries all of its requests at once, and is replaced once it is closed.
+//!
+//! The connections to a backend that is draining or has been removed from its pool are dropped
+//! periodically, which closes those that are idle.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures::{future, Future, Poll, Stream};
+use futures::future::Shared;
+use hyper::{self, Body, Client, Uri};
+use hyper::client::{self, HttpConnector, Service};
+use hyper::header::Connection as ConnectionHeader;
+use hyper_tls::HttpsConnector;
+use openssl::ssl::{HandshakeError, SslConnector, SslMethod};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslConnectorExt;
+use tokio_timer::Timer;
+
+use config::ConnectionPool;
+use http2::{self, Trailers};
+use pool::{Backend, Pool};
+use router::Router;
+use server::Server;
+use timeout::TimeoutConnector;
+
+/// The protocol used to send requests to the servers of a pool
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Protocol {
+    /// HTTP/1.1, with each connection carrying one request at a time
+    Http1,
+
+    /// HTTP/2, with every request to a server multiplexed over a single connection. Plaintext
+    /// servers are sent HTTP/2 right away (prior knowledge) and TLS servers must select it during
+    /// the handshake (ALPN).
+    Http2,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Protocol::Http1 => "http1",
+            Protocol::Http2 => "http2",
+        }
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        Protocol::Http1
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Protocol, String> {
+        match s {
+            "http1" | "http/1.1" => Ok(Protocol::Http1),
+            "http2" | "h2" | "h2c" => Ok(Protocol::Http2),
+            _ => Err(format!("unknown protocol: {}", s)),
+        }
+    }
+}
+
+type Connector = TrackingConnector<TimeoutConnector<HttpsConnector<HttpConnector>>>;
+
+/// Opens the TCP connections that HTTP/2 is spoken on, with TLS added on top when needed
+type H2Connector = TrackingConnector<TimeoutConnector<HttpConnector>>;
+
+/// A connection to a backend, which may use TLS
+pub type BackendStream = Connection<<HttpsConnector<HttpConnector> as Service>::Response>;
+
+/// The HTTP/2 connection to a backend, shared by every request to it from the moment it starts
+/// opening
+type H2Connection = Shared<Box<Future<Item = http2::Client, Error = io::Error>>>;
+
+/// The time (in seconds) between two checks for connections to backends that are no longer needed
+const SWEEP_INTERVAL: u64 = 10;
+
+/// The clients shared by all client connections of a worker
+#[derive(Clone)]
+pub struct Upstream {
+    /// The client of each backend of a pool, created once the backend gets its first request
+    clients: Rc<RefCell<HashMap<(String, Server), Client<Connector, Body>>>>,
+
+    https: HttpsConnector<HttpConnector>,
+    http: HttpConnector,
+    tls: SslConnector,
+
+    /// The HTTP/2 connection to each backend of a pool, once it starts opening
+    h2: Rc<RefCell<HashMap<(String, Server), H2Connection>>>,
+
+    router: Router,
+    timer: Timer,
+    handle: Handle,
+    conf: ConnectionPool,
+}
+
+impl Upstream {
+    pub fn new(handle: &Handle, router: Router, timer: Timer, conf: &ConnectionPool) -> Upstream {
+        let mut http = HttpConnector::new(4, handle);
+        http.enforce_http(false);
+
+        let upstream = Upstream {
+            clients: Rc::new(RefCell::new(HashMap::new())),
+            https: HttpsConnector::new(4, handle).unwrap(),
+            http: http,
+            tls: h2_tls_connector(),
+            h2: Rc::new(RefCell::new(HashMap::new())),
+            router: router,
+            timer: timer.clone(),
+            handle: handle.clone(),
+            conf: conf.clone(),
+        };
+
+        let upstream1 = upstream.clone();
+        let sweep = timer
+            .interval(Duration::from_secs(SWEEP_INTERVAL))
+            .for_each(move |()| {
+                upstream1.sweep();
+                Ok(())
+            })
+            .map_err(|e| {
+                error!("Backend connection sweep timer error {:?}", e);
+            });
+        handle.spawn(sweep);
+
+        upstream
+    }
+
+    /// Send a request to a backend server of a pool
+    ///
+    /// Over HTTP/1.1, the connection is closed once the response is done if keeping it open would
+    /// leave the backend with more idle connections than allowed. Over HTTP/2, the trailers of the
+    /// request are sent after its body and the trailers of the response are stored.
+    pub fn request(
+        &self,
+        pool: &Pool,
+        server: &Server,
+        mut req: client::Request,
+        trailers: &Trailers,
+    ) -> Box<Future<Item = client::Response, Error = hyper::Error>> {
+        if pool.protocol() == Protocol::Http2 {
+            return self.request_h2(pool, server, req, trailers);
+        }
+
+        let backend = pool.find(server);
+        if !keep_alive(backend.as_ref(), self.conf.max_idle) {
+            req.headers_mut().set(ConnectionHeader::close());
+        }
+
+        Box::new(self.client(pool, server).request(req))
+    }
+
+    /// Send a request to a backend server on its HTTP/2 connection, which is opened if needed
+    fn request_h2(
+        &self,
+        pool: &Pool,
+        server: &Server,
+        req: client::Request,
+        trailers: &Trailers,
+    ) -> Box<Future<Item = client::Response, Error = hyper::Error>> {
+        let trailers = trailers.clone();
+        let handle = self.handle.clone();
+        let work = self.h2_connection(pool, server).then(move |res| match res {
+            Ok(client) => client.request(req, &trailers, &handle),
+            Err(e) => {
+                let e = io::Error::new(e.kind(), e.to_string());
+                Box::new(future::err(hyper::Error::from(e)))
+            }
+        });
+
+        Box::new(work)
+    }
+
+    /// Returns the HTTP/2 connection to a backend server of a pool
+    ///
+    /// A new connection is opened if there is none, or if the last one failed to open or has since
+    /// closed. Requests that arrive while it opens wait for the same connection.
+    fn h2_connection(&self, pool: &Pool, server: &Server) -> H2Connection {
+        let key = (pool.name(), server.clone());
+        if let Some(connection) = self.h2.borrow().get(&key) {
+            if usable(connection) {
+                return connection.clone();
+            }
+        }
+
+        let connections = self.h2.clone();
+        let failed = key.clone();
+        let connector = self.h2_connector(&key.0);
+        let connecting = connect_h2(&connector, &self.tls, server.url(), &self.handle);
+        let connecting: Box<Future<Item = http2::Client, Error = io::Error>> =
+            Box::new(connecting.map_err(move |e| {
+                connections.borrow_mut().remove(&failed);
+                e
+            }));
+
+        let connection = connecting.shared();
+        self.h2.borrow_mut().insert(key, connection.clone());
+        connection
+    }
+
+    /// Open a new connection to a backend server of a pool that is not shared with other requests
+    pub fn connect(
+        &self,
+        pool: &Pool,
+        server: &Server,
+    ) -> Box<Future<Item = BackendStream, Error = io::Error>> {
+        self.connector(&pool.name()).call(server.url())
+    }
+
+    /// Returns the client of a backend server of a pool
+    ///
+    /// Each backend of each pool has a client of its own, so that its connections are counted
+    /// against, and timed out by, the backend of that pool even when a server is in more than one
+    /// pool. Dropping the client closes its idle connections.
+    fn client(&self, pool: &Pool, server: &Server) -> Client<Connector, Body> {
+        let key = (pool.name(), server.clone());
+        let mut clients = self.clients.borrow_mut();
+        if let Some(client) = clients.get(&key) {
+            return client.clone();
+        }
+
+        let client = Client::configure()
+            .connector(self.connector(&key.0))
+            .keep_alive(self.conf.max_idle > 0)
+            .keep_alive_timeout(Some(Duration::from_secs(self.conf.idle_timeout)))
+            .build(&self.handle);
+        clients.insert(key, client.clone());
+        client
+    }
+
+    /// Drop the clients and HTTP/2 connections of backends that no longer get new requests
+    ///
+    /// A backend that is draining or has been removed from its pool has its idle connections
+    /// closed, while its requests in flight finish on connections that close once they are done.
+    /// HTTP/2 connections that have closed are dropped as well.
+    fn sweep(&self) {
+        let router = &self.router;
+        self.clients.borrow_mut().retain(|&(ref pool, ref server), _| {
+            in_use(router, pool, server)
+        });
+        self.h2.borrow_mut().retain(|&(ref pool, ref server), connection| {
+            in_use(router, pool, server) && usable(connection)
+        });
+    }
+
+    /// Returns the connector used to open connections to the backends of a pool
+    fn connector(&self, pool: &str) -> Connector {
+        let connector = TimeoutConnector::new(
+            self.https.clone(),
+            self.timer.clone(),
+            self.router.clone(),
+            pool,
+        );
+        TrackingConnector::new(connector, self.router.clone(), pool)
+    }
+
+    /// Returns the connector used to open HTTP/2 connections to the backends of a pool
+    fn h2_connector(&self, pool: &str) -> H2Connector {
+        let connector = TimeoutConnector::new(
+            self.http.clone(),
+            self.timer.clone(),
+            self.router.clone(),
+            pool,
+        );
+        TrackingConnector::new(connector, self.router.clone(), pool)
+    }
+}
+
+/// Returns true if requests can be sent on an HTTP/2 connection, or wait for it to open
+fn usable(connection: &H2Connection) -> bool {
+    match connection.peek() {
+        None => true,
+        Some(Ok(client)) => !client.is_closed(),
+        Some(Err(_)) => false,
+    }
+}
+
+/// Returns true if the server is still in the pool and may get new requests
+fn in_use(router: &Router, pool: &str, server: &Server) -> bool {
+    match router.pool(pool).and_then(|pool| pool.find(server)) {
+        Some(backend) => !backend.is_draining(),
+        None => false,
+    }
+}
+
+/// Create the TLS connector for HTTP/2 backends, which asks for HTTP/2 during the handshake
+pub fn h2_tls_connector() -> SslConnector {
+    let mut builder = SslConnector::builder(SslMethod::tls()).expect("Failed to create connector");
+    builder.set_alpn_protos(b"\x02h2").expect("Unable to set ALPN protocols");
+    builder.build()
+}
+
+/// Open an HTTP/2 connection to a backend server
+///
+/// A backend with an `https` url must select HTTP/2 during the TLS handshake, while a plaintext
+/// backend is expected to speak HTTP/2 right away.
+pub fn connect_h2<C>(
+    connector: &C,
+    tls: &SslConnector,
+    url: Uri,
+    handle: &Handle,
+) -> Box<Future<Item = http2::Client, Error = io::Error>>
+where
+    C: Service<Request = Uri, Error = io::Error>,
+    C::Response: AsyncRead + AsyncWrite + 'static,
+    C::Future: 'static,
+{
+    let handle = handle.clone();
+    let connecting = connector.call(url.clone());
+    if url.scheme() != Some("https") {
+        return Box::new(connecting.and_then(move |stream| http2::handshake(stream, &handle)));
+    }
+
+    let tls = tls.clone();
+    let domain = url.host().unwrap_or("").to_string();
+    let work = connecting
+        .and_then(move |stream| {
+            tls.connect_async(&domain, stream).map_err(|e| match e {
+                HandshakeError::SetupFailure(e) => io::Error::new(io::ErrorKind::Other, e),
+                HandshakeError::Failure(s) | HandshakeError::WouldBlock(s) => {
+                    io::Error::new(io::ErrorKind::Other, s.into_error())
+                }
+            })
+        })
+        .and_then(move |stream| {
+            if stream.get_ref().ssl().selected_alpn_protocol() != Some(http2::ALPN_H2) {
+                let e = io::Error::new(io::ErrorKind::Other, "backend did not select HTTP/2");
+                return future::Either::A(future::err(e));
+            }
+            future::Either::B(http2::handshake(stream, &handle))
+        });
+
+    Box::new(work)
+}
+
+/// Returns true if the connection used by a request to the backend can be kept open for reuse
+///
+/// The request being sent is counted as in flight. Every other request in flight is assumed to
+/// hold a connection, so the rest of the open connections are idle.
+fn keep_alive(backend: Option<&Backend>, max_idle: u64) -> bool {
+    let backend = match backend {
+        Some(backend) => backend,
+        None => return max_idle > 0,
+    };
+
+    let busy = backend.in_flight().saturating_sub(1) as u64;
+    let idle = backend.connections().open.saturating_sub(busy);
+    max_idle > 0 && idle <= max_idle
+}
+
+/// A connector that counts the connections open to each backend of the named pool
+#[derive(Clone)]
+pub struct TrackingConnector<C> {
+    connector: C,
+    router: Router,
+    pool: String,
+}
+
+impl<C> TrackingConnector<C> {
+    pub fn new(connector: C, router: Router, pool: &str) -> TrackingConnector<C> {
+        TrackingConnector {
+            connector: connector,
+            router: router,
+            pool: pool.to_string(),
+        }
+    }
+}
+
+impl<C> Service for TrackingConnector<C>
+where
+    C: Service<Request = Uri, Error = io::Error>,
+    C::Response: AsyncRead + AsyncWrite + 'static,
+    C::Future: 'static,
+{
+    type Request = Uri;
+    type Response = Connection<C::Response>;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Connection<C::Response>, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let backend = self.router.pool(&self.pool).and_then(
+            |pool| pool.find_by_url(&uri),
+        );
+        Box::new(self.connector.call(uri).map(move |stream| {
+            Connection::new(stream, backend)
+        }))
+    }
+}
+
+/// A connection to a backend that is counted as open until it is dropped
+#[derive(Debug)]
+pub struct Connection<S> {
+    stream: S,
+    backend: Option<Backend>,
+}
+
+impl<S> Connection<S> {
+    fn new(stream: S, backend: Option<Backend>) -> Connection<S> {
+        if let Some(ref backend) = backend {
+            backend.connection_opened();
+        }
+
+        Connection {
+            stream: stream,
+            backend: backend,
+        }
+    }
+}
+
+impl<S> Drop for Connection<S> {
+    fn drop(&mut self) {
+        if let Some(ref backend) = self.backend {
+            backend.connection_closed();
+        }
+    }
+}
+
+impl<S: Read> Read for Connection<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Connection<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Connection<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Connection<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keep_alive, Connection};
+    use pool::Backend;
+    use server::Server;
+    use std::io;
+    use std::str::FromStr;
+
+    fn backend() -> Backend {
+        Backend::new(Server::new(
+            FromStr::from_str("http://127.0.0.1:6000").unwrap(),
+            false,
+        ))
+    }
+
+    #[test]
+    fn test_connection_counted_until_dropped() {
+        let backend = backend();
+        let conn = Connection::new(io::empty(), Some(backend.clone()));
+        assert_eq!(1, backend.connections().open);
+
+        drop(conn);
+        assert_eq!(0, backend.connections().open);
+        assert_eq!(1, backend.connections().opened);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let backend = backend();
+        let _conns: Vec<_> = (0..3)
+            .map(|_| Connection::new(io::empty(), Some(backend.clone())))
+            .collect();
+
+        // the request being sent is in flight on one of the three connections
+        backend.inc_in_flight();
+        assert!(keep_alive(Some(&backend), 3));
+        assert!(!keep_alive(Some(&backend), 2));
+
+        // two other requests hold two of the connections, so only one is idle
+        backend.inc_in_flight();
+        backend.inc_in_flight();
+        assert!(keep_alive(Some(&backend), 1));
+
+        assert!(!keep_alive(Some(&backend), 0));
+        assert!(keep_alive(None, 1));
+    }
+}
@@ -7,15 +7,20 @@ extern crate clap;
 extern crate tokio_core;
 
 use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
 
 use clap::{Arg, App, SubCommand};
 
 use tokio_core::reactor::Core;
 
 use weldr::pool::Pool;
-use weldr::config::Config;
+use weldr::router::Router;
+use weldr::tls::Certificates;
+use weldr::config::{Certificate, Config};
 use weldr::mgmt::{worker, manager};
 use weldr::mgmt::health::BackendHealth;
+use weldr::upstream::Protocol;
 
 fn main() {
     env_logger::init().expect("Failed to start logger");
@@ -37,6 +42,97 @@ fn main() {
                 .takes_value(true)
                 .help("listening ip and port for cluster. default: 0.0.0.0:8080"),
         )
+        .arg(
+            Arg::with_name("tls-ip")
+                .long("tls-ip")
+                .value_name("tls-ip")
+                .takes_value(true)
+                .help("listening ip and port for TLS connections. default: 0.0.0.0:8443"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_names(&["cert", "key"])
+                .multiple(true)
+                .number_of_values(2)
+                .help(
+                    "PEM certificate chain and private key offered to TLS connections. The \
+                     certificate is selected by SNI and the first one is the default. TLS is \
+                     disabled without one.",
+                ),
+        )
+        .arg(
+            Arg::with_name("tls-min-version")
+                .long("tls-min-version")
+                .value_name("tls-min-version")
+                .takes_value(true)
+                .help("oldest TLS version accepted. default: TLSv1.2"),
+        )
+        .arg(
+            Arg::with_name("tls-ciphers")
+                .long("tls-ciphers")
+                .value_name("tls-ciphers")
+                .takes_value(true)
+                .help("OpenSSL cipher list for TLS 1.2 and older. default: Mozilla intermediate"),
+        )
+        .arg(
+            Arg::with_name("no-http2")
+                .long("no-http2")
+                .help("serve clients with HTTP/1.x only"),
+        )
+        .arg(
+            Arg::with_name("backend-protocol")
+                .long("backend-protocol")
+                .value_name("backend-protocol")
+                .takes_value(true)
+                .help("protocol used to send requests to backends, http1 or http2. default: http1"),
+        )
+        .arg(
+            Arg::with_name("outlier-consecutive-failures")
+                .long("outlier-consecutive-failures")
+                .value_name("outlier-consecutive-failures")
+                .takes_value(true)
+                .help("consecutive failed requests that eject a server. default: 0 (disabled)"),
+        )
+        .arg(
+            Arg::with_name("outlier-failure-rate")
+                .long("outlier-failure-rate")
+                .value_name("outlier-failure-rate")
+                .takes_value(true)
+                .help(
+                    "percentage of failed requests within 10 seconds that ejects a server. \
+                     default: 0 (disabled)",
+                ),
+        )
+        .arg(
+            Arg::with_name("circuit-failure-rate")
+                .long("circuit-failure-rate")
+                .value_name("circuit-failure-rate")
+                .takes_value(true)
+                .help(
+                    "percentage of failed requests within 10 seconds that opens the circuit of a \
+                     server. default: 0 (disabled)",
+                ),
+        )
+        .arg(
+            Arg::with_name("circuit-max-concurrent")
+                .long("circuit-max-concurrent")
+                .value_name("circuit-max-concurrent")
+                .takes_value(true)
+                .help(
+                    "concurrent requests to a server that open its circuit. default: 0 (disabled)",
+                ),
+        )
+        .arg(
+            Arg::with_name("slow-start")
+                .long("slow-start")
+                .value_name("slow-start")
+                .takes_value(true)
+                .help(
+                    "seconds over which a new or recovered server ramps up to its full share of \
+                     requests. default: 0 (disabled)",
+                ),
+        )
         .subcommand(
             SubCommand::with_name("worker").about("start a worker").arg(
                 Arg::with_name("id")
@@ -61,25 +157,108 @@ fn main() {
     let ip = matches.value_of("worker").unwrap_or("0.0.0.0:8080");
     let ip = ip.parse::<SocketAddr>().unwrap();
 
-    let pool = Pool::default();
+    let mut conf = Config::default();
+    if let Some(values) = matches.values_of("tls-cert") {
+        let values: Vec<&str> = values.collect();
+        // a certificate is named after its file, such as `example.com` for `example.com.pem`
+        conf.tls.certificates = values
+            .chunks(2)
+            .map(|pair| {
+                let name = Path::new(pair[0]).file_stem().and_then(|name| name.to_str());
+                Certificate {
+                    name: name.unwrap_or(pair[0]).to_string(),
+                    cert: pair[0].to_string(),
+                    key: pair[1].to_string(),
+                }
+            })
+            .collect();
+    }
+    if let Some(min_version) = matches.value_of("tls-min-version") {
+        conf.tls.min_version = min_version.to_string();
+    }
+    if let Some(ciphers) = matches.value_of("tls-ciphers") {
+        conf.tls.ciphers = ciphers.to_string();
+    }
+    if matches.is_present("no-http2") {
+        conf.http2.enabled = false;
+    }
+    if let Some(protocol) = matches.value_of("backend-protocol") {
+        conf.protocol = protocol.parse::<Protocol>().expect("Failed to parse backend protocol");
+    }
+    if let Some(failures) = matches.value_of("outlier-consecutive-failures") {
+        conf.outlier_detection.consecutive_failures =
+            failures.parse::<u64>().expect("Failed to parse outlier consecutive failures");
+    }
+    if let Some(rate) = matches.value_of("outlier-failure-rate") {
+        conf.outlier_detection.failure_rate =
+            rate.parse::<u64>().expect("Failed to parse outlier failure rate");
+    }
+    if let Some(rate) = matches.value_of("circuit-failure-rate") {
+        conf.circuit_breaker.failure_rate =
+            rate.parse::<u64>().expect("Failed to parse circuit failure rate");
+    }
+    if let Some(max) = matches.value_of("circuit-max-concurrent") {
+        conf.circuit_breaker.max_concurrent =
+            max.parse::<u64>().expect("Failed to parse circuit max concurrent");
+    }
+    if let Some(window) = matches.value_of("slow-start") {
+        conf.slow_start.window = window.parse::<u64>().expect("Failed to parse slow start");
+    }
+
+    // fail before starting any worker if the certificates cannot be loaded
+    let certificates = if conf.tls.certificates.is_empty() {
+        None
+    } else {
+        Some(Certificates::load(&conf.tls, &conf.http2).expect("Failed to load TLS certificates"))
+    };
+    let tls_ip = matches.value_of("tls-ip").unwrap_or("0.0.0.0:8443");
+    let tls_ip = tls_ip.parse::<SocketAddr>().unwrap();
+
+    let pool = Pool::new(conf.strategy);
+    pool.set_hash_key(conf.hash_key.clone());
+    pool.set_outlier_detection(conf.outlier_detection.clone());
+    pool.set_circuit_breaker(conf.circuit_breaker.clone());
+    pool.set_retry(conf.retry.clone());
+    pool.set_slow_start(Duration::from_secs(conf.slow_start.window));
+    pool.set_error_pages(conf.error_pages.clone());
+    pool.set_timeouts(conf.timeouts.clone());
+    pool.set_connection_pool(conf.connection_pool.clone());
+    pool.set_upgrade(conf.upgrade.clone());
+    pool.set_protocol(conf.protocol);
+    let router = Router::new(pool);
 
     if let Some(matches) = matches.subcommand_matches("worker") {
         let id = matches.value_of("id").unwrap();
         debug!("Spawned worker {}", id);
-        let _result = worker::subscribe(internal_addr, handle, pool.clone());
+        let id = id.parse::<u64>().expect("Failed to parse worker id");
+        let _result = worker::subscribe(
+            internal_addr,
+            handle,
+            router.clone(),
+            certificates.clone(),
+            id,
+        );
 
-        weldr::proxy::run(ip, pool, core).expect("Failed to start server");
+        let tls = certificates.map(|certificates| (tls_ip, certificates));
+        weldr::proxy::run(ip, tls, conf.http2.clone(), router, core)
+            .expect("Failed to start server");
     } else {
-        let conf = Config::default();
         let mut manager = manager::Manager::new();
-        manager.listen(internal_addr, handle.clone());
+        manager.listen(internal_addr, router.clone(), certificates.clone(), handle.clone());
         manager.start_workers(5).expect("Failed to start manager");
 
         let health = BackendHealth::new();
 
         let admin_ip = matches.value_of("worker").unwrap_or("0.0.0.0:8687");
         let admin_ip = admin_ip.parse::<SocketAddr>().unwrap();
-        weldr::mgmt::run(admin_ip, pool, core, manager.clone(), &conf, health.clone())
-            .expect("Failed to start server");
+        weldr::mgmt::run(
+            admin_ip,
+            router,
+            core,
+            manager.clone(),
+            &conf,
+            health.clone(),
+            certificates,
+        ).expect("Failed to start server");
     }
 }
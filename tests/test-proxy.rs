@@ -24,6 +24,7 @@ use hyper::header::{ContentLength, TransferEncoding};
 
 use weldr::server::Server;
 use weldr::pool::Pool;
+use weldr::router::Router;
 
 #[derive(Clone, Copy)]
 struct Origin;
@@ -132,7 +133,7 @@ where
         )
     });
 
-    weldr::proxy::run_with(core, listener, pool.clone(), shutdown_signal)
+    weldr::proxy::run_with(core, listener, Router::new(pool.clone()), shutdown_signal)
         .expect("Failed to start server");
 }
 